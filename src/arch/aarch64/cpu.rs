@@ -1,6 +1,9 @@
 global_asm!(include_str!("header.S"));
-use crate::{link_var, memory};
-use cortex_a::{asm, regs::*};
+global_asm!(include_str!("fp.S"));
+use crate::{link_var, sched::SpawnError};
+use cortex_a::{asm, barrier, regs::*};
+
+use super::{FpState, Regs};
 
 link_var!(__start);
 #[inline(always)]
@@ -18,6 +21,132 @@ pub fn spin_for_cycles(n: usize) {
         nop();
     }
 }
+/// Runs `f` with IRQs masked on this core.
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let daif = DAIF.get();
+    DAIF.modify(DAIF::I::Masked);
+    let result = f();
+    DAIF.set(daif);
+    result
+}
+/// CPACR_EL1.FPEN, which traps SIMD&FP instructions when clear.
+const CPACR_FPEN: u64 = 0b11 << 20;
+
+/// Checks if the SIMD&FP registers may have been written since
+/// [set_fp_enabled]. There is no dirty bit, so this is whether they're enabled.
+pub fn fp_dirty() -> bool {
+    let cpacr: u64;
+    unsafe { asm!("mrs {0}, cpacr_el1", out(reg) cpacr) };
+    cpacr & CPACR_FPEN == CPACR_FPEN
+}
+
+/// Lets the running code use the SIMD&FP registers, or makes any use of them
+/// trap.
+pub fn set_fp_enabled(enabled: bool) {
+    let fpen = if enabled { CPACR_FPEN } else { 0 };
+    unsafe {
+        asm!(
+            "mrs {0}, cpacr_el1",
+            "bic {0}, {0}, #(0b11 << 20)",
+            "orr {0}, {0}, {1}",
+            "msr cpacr_el1, {0}",
+            out(reg) _,
+            in(reg) fpen,
+        );
+    }
+    barrier::isb(barrier::SY);
+}
+
+/// Saves the SIMD&FP registers into `state`.
+///
+/// # Safety
+/// SIMD&FP must be enabled.
+pub unsafe fn save_fp(state: &mut FpState) {
+    extern "C" {
+        fn __save_fp(state: *mut FpState);
+    }
+    __save_fp(state)
+}
+
+/// Loads the SIMD&FP registers from `state`.
+///
+/// # Safety
+/// SIMD&FP must be enabled.
+pub unsafe fn restore_fp(state: &FpState) {
+    extern "C" {
+        fn __restore_fp(state: *const FpState);
+    }
+    __restore_fp(state)
+}
+
+/// Gets the registers a user process starts with, running on the given stack.
+pub const fn user_regs(stack_top: usize) -> Regs {
+    let mut regs = super::default_regs();
+    // sp_el0
+    regs[31] = stack_top;
+    regs
+}
+
+/// Makes instructions written to `len` bytes at `addr` visible to
+/// instruction fetches on all cores.
+pub fn sync_icache(addr: usize, len: usize) {
+    let ctr: usize;
+    unsafe { asm!("mrs {0}, ctr_el0", out(reg) ctr) };
+    // CTR_EL0.DminLine, log2 of the smallest data cache line in words
+    let line = 4 << (ctr >> 16 & 0xf);
+    for line_addr in (addr & !(line - 1)..addr + len).step_by(line) {
+        unsafe { asm!("dc cvau, {0}", in(reg) line_addr) };
+    }
+    unsafe { asm!("dsb ish", "ic ialluis", "dsb ish", "isb") };
+}
+
+/// SPSR_EL1.M for EL0 with SP_EL0.
+const SPSR_EL0T: usize = 0b0000;
+/// SPSR_EL1.M for EL1 with SP_EL1.
+const SPSR_EL1H: usize = 0b0101;
+
+/// Whether exceptions return to EL0, see [set_return_mode].
+static mut RETURN_TO_EL0: bool = false;
+
+/// Stack exceptions from EL0 start on, loaded into SP_EL1 by
+/// `__exception_return` before it returns to EL0.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+static mut __el0_kernel_stack: usize = 0;
+
+/// Makes exception handlers that switch tasks return to EL0 if `user` is set,
+/// or EL1 otherwise. Exceptions from EL0 run on `kernel_stack`.
+pub fn set_return_mode(user: bool, kernel_stack: usize) {
+    unsafe {
+        RETURN_TO_EL0 = user;
+        __el0_kernel_stack = kernel_stack;
+    }
+}
+
+/// Gets the SPSR to return to the task picked by the scheduler with, with
+/// all exceptions unmasked.
+pub fn return_spsr() -> usize {
+    if unsafe { RETURN_TO_EL0 } {
+        SPSR_EL0T
+    } else {
+        SPSR_EL1H
+    }
+}
+
+/// Nothing to do, exceptions from EL1 run on the stack of the code they
+/// interrupt, and tasks have a guard page below theirs.
+///
+/// # Errors
+/// Never fails.
+pub const fn init_trap_stack() -> Result<(), SpawnError> {
+    Ok(())
+}
+
+/// Checks if an exception came from EL0, given its SPSR.
+pub const fn trapped_from_user(spsr: usize) -> bool {
+    spsr & 0b1111 == SPSR_EL0T
+}
+
 #[inline(always)]
 pub fn core_num() -> u8 {
     // technically there can be 255 cores per clusters and 255 clusters,
@@ -31,10 +160,7 @@ pub fn cluster_num() -> u8 {
 #[inline(always)]
 #[no_mangle]
 /// # Safety
-/// Safe only to call from asm entry. Same safety restrictions as
-/// [setup_environment].
-///
-/// [setup_environment]: memory::setup_environment
+/// Safe only to call from asm entry, with the MMU off.
 pub unsafe fn __early_entry(_dtb_addr: *mut u8) -> ! {
     if cluster_num() != 0 {
         wait_forever()
@@ -45,11 +171,21 @@ pub unsafe fn __early_entry(_dtb_addr: *mut u8) -> ! {
     match CurrentEL.get() & 0b11_00 {
         0b11_00 => el3_to_el2(),
         0b10_00 => el2_to_el1(),
-        0b01_00 => memory::setup_environment(_dtb_addr),
+        0b01_00 => el1_entry(_dtb_addr),
         _ => wait_forever(),
     }
 }
 
+/// Sets up paging and continues in the higher half with kinit.
+///
+/// # Safety
+/// Only safe to call once, at EL1 with the MMU off.
+unsafe extern "C" fn el1_entry(dtb_addr: *mut u8) -> ! {
+    // SIMD&FP traps until a task uses it, see sched::fp_trap
+    set_fp_enabled(false);
+    super::mmu::init(crate::kinit as usize, dtb_addr as usize)
+}
+
 #[inline(always)]
 /// # Safety
 /// Only safe to call from [__early_entry].
@@ -60,6 +196,17 @@ unsafe fn el2_to_el1() -> ! {
     // Counter-timer Virtual Offset = 0
     CNTVOFF_EL2.set(0);
 
+    // Let EL1 use the GICv3 CPU interface system registers, if there are any
+    let pfr0: u64;
+    asm!("mrs {0}, ID_AA64PFR0_EL1", out(reg) pfr0);
+    if pfr0 >> 24 & 0xF != 0 {
+        // ICC_SRE_EL2.Enable | ICC_SRE_EL2.SRE
+        asm!("mrs {0}, S3_4_C12_C9_5", "orr {0}, {0}, #0b1001", "msr S3_4_C12_C9_5, {0}", out(reg) _);
+    }
+
+    // Don't trap SIMD&FP to EL2, EL1 decides. The rest of CPTR_EL2 is RES1.
+    asm!("msr cptr_el2, {0}", in(reg) 0x33ffu64);
+
     // Enable aarch64 (not aarch32, since that's an option!)
     HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64);
 
@@ -73,7 +220,7 @@ unsafe fn el2_to_el1() -> ! {
     );
 
     // Set the link register to the correct location (this will execute after exception return)
-    ELR_EL2.set(memory::setup_environment as *const () as u64);
+    ELR_EL2.set(el1_entry as *const () as u64);
 
     // Set up the Stack Pointer
     SP_EL1.set(&__start as *const _ as u64);
@@ -0,0 +1,100 @@
+use register::{mmio::*, register_structs};
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub IntcRegisters {
+        (0x00 => BASIC_PENDING: ReadOnly<u32>),
+        (0x04 => PENDING: [ReadOnly<u32>; 2]),
+        (0x0c => FIQ_CONTROL: ReadWrite<u32>),
+        (0x10 => ENABLE: [WriteOnly<u32>; 2]),
+        (0x18 => ENABLE_BASIC: WriteOnly<u32>),
+        (0x1c => DISABLE: [WriteOnly<u32>; 2]),
+        (0x24 => DISABLE_BASIC: WriteOnly<u32>),
+        (0x28 => @END),
+    }
+}
+
+/// `compatible` strings of this controller.
+pub const COMPATIBLE: &[&str] = &["brcm,bcm2836-armctrl-ic", "brcm,bcm2835-armctrl-ic"];
+
+/// Number of GPU interrupts, which come first.
+pub const GPU_IRQS: u32 = 64;
+/// Number of interrupts, the GPU ones followed by the ARM ones of the
+/// basic pending register.
+pub const IRQ_COUNT: u32 = GPU_IRQS + 8;
+/// Bits of the basic pending register that are ARM interrupts. The rest
+/// summarize the GPU pending registers.
+const BASIC_ARM_IRQS: u32 = 0xff;
+
+/// BCM2835 ARM interrupt controller, which collects the GPU's peripheral
+/// interrupts. On the BCM2836 and later it is chained to the local
+/// controller of the core the GPU interrupts are routed to.
+pub struct BCM2835Intc {
+    base_address: usize,
+}
+
+impl BCM2835Intc {
+    /// # Safety
+    /// Only safe if the base address is valid and mapped as device memory.
+    pub const unsafe fn new(base_address: usize) -> Self {
+        Self { base_address }
+    }
+
+    fn regs(&self) -> &IntcRegisters {
+        unsafe { &*(self.base_address as *const IntcRegisters) }
+    }
+
+    /// Converts a device tree interrupt specifier, which is a bank (0 for
+    /// ARM, 1 and 2 for GPU) and a number in it, to an interrupt number.
+    pub const fn irq_from_bank(bank: u32, number: u32) -> Option<u32> {
+        match bank {
+            0 if number < 8 => Some(GPU_IRQS + number),
+            1 | 2 if number < 32 => Some((bank - 1) * 32 + number),
+            _ => None,
+        }
+    }
+
+    /// Disables every interrupt and FIQ routing.
+    pub fn reset(&mut self) {
+        let regs = self.regs();
+        regs.FIQ_CONTROL.set(0);
+        regs.DISABLE[0].set(u32::MAX);
+        regs.DISABLE[1].set(u32::MAX);
+        regs.DISABLE_BASIC.set(u32::MAX);
+    }
+
+    /// Lets an interrupt through.
+    pub fn enable(&mut self, irq: u32) {
+        let regs = self.regs();
+        match irq {
+            0..=63 => regs.ENABLE[irq as usize / 32].set(1 << (irq % 32)),
+            64..=71 => regs.ENABLE_BASIC.set(1 << (irq - GPU_IRQS)),
+            _ => {}
+        }
+    }
+
+    /// Stops an interrupt.
+    pub fn disable(&mut self, irq: u32) {
+        let regs = self.regs();
+        match irq {
+            0..=63 => regs.DISABLE[irq as usize / 32].set(1 << (irq % 32)),
+            64..=71 => regs.DISABLE_BASIC.set(1 << (irq - GPU_IRQS)),
+            _ => {}
+        }
+    }
+
+    /// Gets the lowest numbered enabled interrupt that is pending, if any.
+    pub fn pending(&self) -> Option<u32> {
+        let regs = self.regs();
+        let basic = regs.BASIC_PENDING.get() & BASIC_ARM_IRQS;
+        if basic != 0 {
+            return Some(GPU_IRQS + basic.trailing_zeros());
+        }
+        // Only enabled interrupts show up as pending. The basic register has
+        // shortcuts for some GPU interrupts, but these hold all of them.
+        (0..2).find_map(|bank| match regs.PENDING[bank].get() {
+            0 => None,
+            pending => Some(bank as u32 * 32 + pending.trailing_zeros()),
+        })
+    }
+}
@@ -0,0 +1,157 @@
+use super::bcm2835_intc::{self, BCM2835Intc};
+use crate::{
+    driver_interfaces::IrqChip,
+    fdt::{FdtNode, InterruptSpecifier},
+};
+use register::{mmio::*, register_structs};
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub LocalRegisters {
+        (0x00 => CONTROL: ReadWrite<u32>),
+        (0x04 => _reserved0),
+        (0x0c => GPU_ROUTING: ReadWrite<u32>),
+        (0x10 => _reserved1),
+        (0x40 => TIMER_CONTROL: [ReadWrite<u32>; 4]),
+        (0x50 => MAILBOX_CONTROL: [ReadWrite<u32>; 4]),
+        (0x60 => IRQ_SOURCE: [ReadOnly<u32>; 4]),
+        (0x70 => FIQ_SOURCE: [ReadOnly<u32>; 4]),
+        (0x80 => @END),
+    }
+}
+
+/// `compatible` strings of this controller.
+pub const COMPATIBLE: &[&str] = &["brcm,bcm2836-l1-intc"];
+
+/// Physical timer interrupt of the secure world.
+pub const CNTPS_IRQ: u32 = 0;
+/// Non-secure physical timer interrupt, which is the one we use.
+pub const CNTPNS_IRQ: u32 = 1;
+/// Hypervisor timer interrupt.
+pub const CNTHP_IRQ: u32 = 2;
+/// Virtual timer interrupt.
+pub const CNTV_IRQ: u32 = 3;
+/// First of the four mailbox interrupts.
+pub const MAILBOX0_IRQ: u32 = 4;
+/// Set in the source register when the GPU controller has an interrupt.
+const GPU_SOURCE: u32 = 8;
+/// Interrupts of the chained [BCM2835Intc] are numbered from here.
+pub const GPU_IRQ_BASE: u32 = 32;
+
+/// BCM2836 per-core local interrupt controller, which has the generic timer
+/// and mailbox interrupts, and those of the [BCM2835Intc] chained to it.
+pub struct BCM2836Local {
+    base_address: usize,
+    /// The core interrupts are delivered to.
+    core: usize,
+    gpu: Option<BCM2835Intc>,
+}
+
+impl BCM2836Local {
+    /// # Safety
+    /// Only safe if the base address is valid and mapped as device memory.
+    pub const unsafe fn new(base_address: usize, core: usize, gpu: Option<BCM2835Intc>) -> Self {
+        Self {
+            base_address,
+            core,
+            gpu,
+        }
+    }
+
+    fn regs(&self) -> &LocalRegisters {
+        unsafe { &*(self.base_address as *const LocalRegisters) }
+    }
+
+    /// Converts a device tree interrupt specifier of the local controller,
+    /// which is the bit of the source register, to an interrupt number.
+    pub const fn irq_from_source(source: u32) -> Option<u32> {
+        if source < GPU_IRQ_BASE && source != GPU_SOURCE {
+            Some(source)
+        } else {
+            None
+        }
+    }
+
+    /// Masks every interrupt, and routes GPU interrupts to our core.
+    pub fn init(&mut self) {
+        let regs = self.regs();
+        regs.TIMER_CONTROL[self.core].set(0);
+        regs.MAILBOX_CONTROL[self.core].set(0);
+        // IRQ to the core in bits 0-1, FIQ to the core in bits 2-3
+        regs.GPU_ROUTING.set(self.core as u32);
+        if let Some(ref mut gpu) = self.gpu {
+            gpu.reset();
+        }
+    }
+
+    /// Sets or clears the bit that lets a local interrupt through.
+    fn set_local(&mut self, irq: u32, enabled: bool) {
+        let regs = self.regs();
+        let (reg, bit) = match irq {
+            CNTPS_IRQ..=CNTV_IRQ => (&regs.TIMER_CONTROL[self.core], 1 << irq),
+            4..=7 => (&regs.MAILBOX_CONTROL[self.core], 1 << (irq - MAILBOX0_IRQ)),
+            // The PMU and local timer aren't used
+            _ => return,
+        };
+        if enabled {
+            reg.set(reg.get() | bit);
+        } else {
+            reg.set(reg.get() & !bit);
+        }
+    }
+}
+
+impl IrqChip for BCM2836Local {
+    fn name(&self) -> &'static str {
+        "BCM2836 local interrupt controller"
+    }
+
+    fn irq_count(&self) -> usize {
+        (GPU_IRQ_BASE + bcm2835_intc::IRQ_COUNT) as usize
+    }
+
+    fn unmask(&mut self, irq: u32) {
+        match (irq.checked_sub(GPU_IRQ_BASE), self.gpu.as_mut()) {
+            (Some(gpu_irq), Some(gpu)) => gpu.enable(gpu_irq),
+            (Some(_), None) => {}
+            (None, _) => self.set_local(irq, true),
+        }
+    }
+
+    fn mask(&mut self, irq: u32) {
+        match (irq.checked_sub(GPU_IRQ_BASE), self.gpu.as_mut()) {
+            (Some(gpu_irq), Some(gpu)) => gpu.disable(gpu_irq),
+            (Some(_), None) => {}
+            (None, _) => self.set_local(irq, false),
+        }
+    }
+
+    fn claim(&mut self) -> Option<u32> {
+        let source = self.regs().IRQ_SOURCE[self.core].get();
+        if source & 1 << GPU_SOURCE != 0 {
+            if let Some(irq) = self.gpu.as_ref().and_then(BCM2835Intc::pending) {
+                return Some(GPU_IRQ_BASE + irq);
+            }
+        }
+        match source & !(1 << GPU_SOURCE) {
+            0 => None,
+            local => Some(local.trailing_zeros()),
+        }
+    }
+
+    fn complete(&mut self, _irq: u32) {
+        // Interrupts are level triggered and go away once the device is handled
+    }
+
+    fn translate(&self, parent: &FdtNode, specifier: &InterruptSpecifier) -> Option<u32> {
+        if bcm2835_intc::COMPATIBLE
+            .iter()
+            .any(|c| parent.is_compatible(c))
+        {
+            let irq = BCM2835Intc::irq_from_bank(specifier.cell(0)?, specifier.cell(1)?)?;
+            Some(GPU_IRQ_BASE + irq)
+        } else {
+            Self::irq_from_source(specifier.cell(0)?)
+        }
+    }
+}
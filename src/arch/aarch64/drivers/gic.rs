@@ -0,0 +1,116 @@
+//! Distributor of the ARM Generic Interrupt Controller, shared by [GICv2]
+//! and [GICv3].
+//!
+//! [GICv2]: super::GICv2
+//! [GICv3]: super::GICv3
+
+use crate::fdt::InterruptSpecifier;
+use register::{mmio::*, register_structs};
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GicdRegisters {
+        (0x0000 => CTLR: ReadWrite<u32>),
+        (0x0004 => TYPER: ReadOnly<u32>),
+        (0x0008 => _reserved0),
+        (0x0080 => IGROUPR: [ReadWrite<u32>; 32]),
+        (0x0100 => ISENABLER: [ReadWrite<u32>; 32]),
+        (0x0180 => ICENABLER: [ReadWrite<u32>; 32]),
+        (0x0200 => _reserved1),
+        (0x0280 => ICPENDR: [ReadWrite<u32>; 32]),
+        (0x0300 => _reserved2),
+        (0x0400 => IPRIORITYR: [ReadWrite<u8>; 1024]),
+        (0x0800 => ITARGETSR: [ReadWrite<u8>; 1024]),
+        (0x0c00 => ICFGR: [ReadWrite<u32>; 64]),
+        (0x0d00 => _reserved3),
+        (0x0f00 => SGIR: WriteOnly<u32>),
+        (0x0f04 => _reserved4),
+        // GICv3 only, and only for SPIs
+        (0x6000 => IROUTER: [ReadWrite<u64>; 1020]),
+        (0x7fe0 => @END),
+    }
+}
+
+/// Number of Software Generated Interrupts, which come first.
+pub const SGI_COUNT: u32 = 16;
+/// First Shared Peripheral Interrupt. Private Peripheral Interrupts, like
+/// the generic timer, are between the SGIs and this.
+pub const SPI_BASE: u32 = 32;
+/// IDs from this on mean there was no interrupt to acknowledge.
+pub const SPURIOUS: u32 = 1020;
+/// Priority of every interrupt. Lower is more urgent.
+pub const DEFAULT_PRIORITY: u8 = 0xa0;
+
+/// Converts a GIC interrupt specifier from the device tree, which is the
+/// type (0 for SPI, 1 for PPI), the number and the flags, to an interrupt ID.
+pub fn intid(specifier: &InterruptSpecifier) -> Option<u32> {
+    let number = specifier.cell(1)?;
+    match specifier.cell(0)? {
+        0 => Some(SPI_BASE + number),
+        1 => Some(SGI_COUNT + number),
+        _ => None,
+    }
+}
+
+/// The distributor, which routes the shared interrupts to CPUs.
+pub struct Distributor {
+    base_address: usize,
+}
+
+impl Distributor {
+    /// # Safety
+    /// Only safe if the base address is valid and mapped as device memory.
+    pub const unsafe fn new(base_address: usize) -> Self {
+        Self { base_address }
+    }
+
+    pub(super) fn regs(&self) -> &GicdRegisters {
+        unsafe { &*(self.base_address as *const GicdRegisters) }
+    }
+
+    /// Gets the number of interrupt IDs, including the SGIs and PPIs.
+    pub fn irq_count(&self) -> usize {
+        (((self.regs().TYPER.get() as usize & 0x1f) + 1) * 32).min(SPURIOUS as usize)
+    }
+
+    /// Disables forwarding, then masks every SPI, clears it if pending and
+    /// gives it the default priority.
+    pub(super) fn reset(&self) {
+        let regs = self.regs();
+        regs.CTLR.set(0);
+        let count = self.irq_count();
+        for word in (SPI_BASE as usize / 32)..(count + 31) / 32 {
+            regs.ICENABLER[word].set(u32::MAX);
+            regs.ICPENDR[word].set(u32::MAX);
+        }
+        for irq in SPI_BASE as usize..count {
+            regs.IPRIORITYR[irq].set(DEFAULT_PRIORITY);
+        }
+    }
+
+    /// Lets an interrupt through to the CPU interfaces.
+    pub fn enable(&self, irq: u32) {
+        self.regs().ISENABLER[irq as usize / 32].set(1 << (irq % 32));
+    }
+
+    /// Stops an interrupt from reaching the CPU interfaces.
+    pub fn disable(&self, irq: u32) {
+        self.regs().ICENABLER[irq as usize / 32].set(1 << (irq % 32));
+    }
+
+    /// Sets the priority of an interrupt. Lower is more urgent.
+    pub fn set_priority(&self, irq: u32, priority: u8) {
+        self.regs().IPRIORITYR[irq as usize].set(priority);
+    }
+
+    /// Makes an SPI edge triggered, or level sensitive if `edge` is false.
+    pub fn set_edge_triggered(&self, irq: u32, edge: bool) {
+        let reg = &self.regs().ICFGR[irq as usize / 16];
+        let bit = 1 << ((irq % 16) * 2 + 1);
+        if edge {
+            reg.set(reg.get() | bit);
+        } else {
+            reg.set(reg.get() & !bit);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use super::gic::{intid, Distributor, DEFAULT_PRIORITY, SGI_COUNT, SPI_BASE, SPURIOUS};
+use crate::{
+    driver_interfaces::IrqChip,
+    fdt::{FdtNode, InterruptSpecifier},
+};
+use register::{mmio::*, register_structs};
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GiccRegisters {
+        (0x00 => CTLR: ReadWrite<u32>),
+        (0x04 => PMR: ReadWrite<u32>),
+        (0x08 => _reserved0),
+        (0x0c => IAR: ReadOnly<u32>),
+        (0x10 => EOIR: WriteOnly<u32>),
+        (0x14 => @END),
+    }
+}
+
+/// Generic Interrupt Controller v2, with a memory mapped CPU interface.
+pub struct GICv2 {
+    distributor: Distributor,
+    cpu_interface: usize,
+    /// Value of IAR for the interrupt being handled. For SGIs it also holds
+    /// the CPU that sent it, which EOIR needs back.
+    acknowledged: u32,
+}
+
+impl GICv2 {
+    /// # Safety
+    /// Only safe if both base addresses are valid and mapped as device memory.
+    pub const unsafe fn new(distributor: usize, cpu_interface: usize) -> Self {
+        Self {
+            distributor: Distributor::new(distributor),
+            cpu_interface,
+            acknowledged: SPURIOUS,
+        }
+    }
+
+    fn cpu_regs(&self) -> &GiccRegisters {
+        unsafe { &*(self.cpu_interface as *const GiccRegisters) }
+    }
+
+    /// Masks every interrupt, routes the SPIs to this CPU and enables the
+    /// distributor and this CPU's interface.
+    pub fn init(&mut self) {
+        self.distributor.reset();
+        let regs = self.distributor.regs();
+        // The SGI and PPI registers are banked per CPU
+        regs.ICENABLER[0].set(u32::MAX);
+        for irq in 0..SPI_BASE as usize {
+            regs.IPRIORITYR[irq].set(DEFAULT_PRIORITY);
+        }
+        // ITARGETSR of the private interrupts reads as this CPU's bit
+        let this_cpu = regs.ITARGETSR[0].get();
+        for irq in SPI_BASE as usize..self.distributor.irq_count() {
+            regs.ITARGETSR[irq].set(this_cpu);
+        }
+        regs.CTLR.set(1);
+        let cpu = self.cpu_regs();
+        // Let every priority through
+        cpu.PMR.set(0xff);
+        cpu.CTLR.set(1);
+    }
+
+    /// Gets the distributor, to set priorities and triggers.
+    pub const fn distributor(&self) -> &Distributor {
+        &self.distributor
+    }
+
+    /// Sends a software generated interrupt to this CPU.
+    pub fn send_sgi_to_self(&mut self, sgi: u32) {
+        assert!(sgi < SGI_COUNT, "No SGI {}", sgi);
+        // TargetListFilter = 0b10, this CPU only
+        self.distributor.regs().SGIR.set(0b10 << 24 | sgi);
+    }
+}
+
+impl IrqChip for GICv2 {
+    fn name(&self) -> &'static str {
+        "GICv2"
+    }
+
+    fn irq_count(&self) -> usize {
+        self.distributor.irq_count()
+    }
+
+    fn unmask(&mut self, irq: u32) {
+        self.distributor.enable(irq);
+    }
+
+    fn mask(&mut self, irq: u32) {
+        self.distributor.disable(irq);
+    }
+
+    fn claim(&mut self) -> Option<u32> {
+        let iar = self.cpu_regs().IAR.get();
+        let irq = iar & 0x3ff;
+        if irq >= SPURIOUS {
+            return None;
+        }
+        self.acknowledged = iar;
+        Some(irq)
+    }
+
+    fn complete(&mut self, irq: u32) {
+        let eoi = if self.acknowledged & 0x3ff == irq {
+            self.acknowledged
+        } else {
+            irq
+        };
+        self.cpu_regs().EOIR.set(eoi);
+    }
+
+    fn translate(&self, _parent: &FdtNode, specifier: &InterruptSpecifier) -> Option<u32> {
+        intid(specifier)
+    }
+}
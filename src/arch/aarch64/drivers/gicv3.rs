@@ -0,0 +1,192 @@
+use super::gic::{intid, Distributor, DEFAULT_PRIORITY, SGI_COUNT, SPI_BASE, SPURIOUS};
+use crate::{
+    driver_interfaces::IrqChip,
+    fdt::{FdtNode, InterruptSpecifier},
+};
+use cortex_a::{barrier, regs::*};
+use register::{mmio::*, register_structs};
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GicrRegisters {
+        // RD_base frame
+        (0x00000 => CTLR: ReadWrite<u32>),
+        (0x00004 => IIDR: ReadOnly<u32>),
+        (0x00008 => TYPER: ReadOnly<u64>),
+        (0x00010 => _reserved0),
+        (0x00014 => WAKER: ReadWrite<u32>),
+        (0x00018 => _reserved1),
+        // SGI_base frame, which holds the SGI and PPI registers
+        (0x10080 => IGROUPR0: ReadWrite<u32>),
+        (0x10084 => _reserved2),
+        (0x10100 => ISENABLER0: ReadWrite<u32>),
+        (0x10104 => _reserved3),
+        (0x10180 => ICENABLER0: ReadWrite<u32>),
+        (0x10184 => _reserved4),
+        (0x10400 => IPRIORITYR: [ReadWrite<u8>; 32]),
+        (0x10420 => _reserved5),
+        (0x10c00 => ICFGR: [ReadWrite<u32>; 2]),
+        (0x10c08 => @END),
+    }
+}
+
+/// GICR_TYPER: this is the last redistributor in the region.
+const TYPER_LAST: u64 = 1 << 4;
+/// GICR_TYPER: the redistributor has the two extra frames for virtual LPIs.
+const TYPER_VLPIS: u64 = 1 << 1;
+/// GICR_WAKER: the CPU interface is asleep.
+const WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+/// GICR_WAKER: the redistributor is still asleep.
+const WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// Generic Interrupt Controller v3. Each CPU has a redistributor for its
+/// SGIs and PPIs, and talks to the GIC through system registers.
+pub struct GICv3 {
+    distributor: Distributor,
+    /// This CPU's redistributor.
+    redistributor: usize,
+}
+
+/// Gets this CPU's affinity in the format of GICR_TYPER and IROUTER.
+fn affinity() -> u64 {
+    let mpidr = MPIDR_EL1.get();
+    (mpidr & 0xff_ffff) | (mpidr >> 32 & 0xff) << 24
+}
+
+impl GICv3 {
+    /// Finds this CPU's redistributor in the region starting at `redistributors`.
+    ///
+    /// # Safety
+    /// Only safe if both base addresses are valid and mapped as device memory,
+    /// and the CPU interface system registers are enabled for EL1.
+    pub unsafe fn new(distributor: usize, redistributors: usize) -> Option<Self> {
+        let mut frame = redistributors;
+        loop {
+            let regs = &*(frame as *const GicrRegisters);
+            let typer = regs.TYPER.get();
+            if typer >> 32 == affinity() {
+                return Some(Self {
+                    distributor: Distributor::new(distributor),
+                    redistributor: frame,
+                });
+            }
+            if typer & TYPER_LAST != 0 {
+                return None;
+            }
+            frame += if typer & TYPER_VLPIS != 0 {
+                0x4_0000
+            } else {
+                0x2_0000
+            };
+        }
+    }
+
+    fn redist_regs(&self) -> &GicrRegisters {
+        unsafe { &*(self.redistributor as *const GicrRegisters) }
+    }
+
+    /// Masks every interrupt, routes the SPIs to this CPU, wakes up its
+    /// redistributor and enables group 1 interrupts.
+    pub fn init(&mut self) {
+        self.distributor.reset();
+        let regs = self.distributor.regs();
+        // Group 1 is what ICC_IAR1_EL1 acknowledges
+        for word in (SPI_BASE as usize / 32)..(self.distributor.irq_count() + 31) / 32 {
+            regs.IGROUPR[word].set(u32::MAX);
+        }
+        for irq in SPI_BASE as usize..self.distributor.irq_count() {
+            regs.IROUTER[irq].set(affinity());
+        }
+        // ARE | EnableGrp1 | EnableGrp0, or EnableGrp1A with two security states
+        regs.CTLR.set(1 << 4 | 1 << 1 | 1);
+
+        let redist = self.redist_regs();
+        redist
+            .WAKER
+            .set(redist.WAKER.get() & !WAKER_PROCESSOR_SLEEP);
+        while redist.WAKER.get() & WAKER_CHILDREN_ASLEEP != 0 {}
+        redist.ICENABLER0.set(u32::MAX);
+        redist.IGROUPR0.set(u32::MAX);
+        for irq in 0..SPI_BASE as usize {
+            redist.IPRIORITYR[irq].set(DEFAULT_PRIORITY);
+        }
+
+        unsafe {
+            // ICC_SRE_EL1.SRE, use the system registers
+            asm!("mrs {0}, S3_0_C12_C12_5", "orr {0}, {0}, #1", "msr S3_0_C12_C12_5, {0}", out(reg) _);
+            barrier::isb(barrier::SY);
+            // ICC_PMR_EL1, let every priority through
+            asm!("msr S3_0_C4_C6_0, {0}", in(reg) 0xffusize);
+            // ICC_IGRPEN1_EL1, enable group 1
+            asm!("msr S3_0_C12_C12_7, {0}", in(reg) 1usize);
+            barrier::isb(barrier::SY);
+        }
+    }
+
+    /// Gets the distributor, to set priorities and triggers of SPIs.
+    pub const fn distributor(&self) -> &Distributor {
+        &self.distributor
+    }
+
+    /// Sends a software generated interrupt to this CPU.
+    pub fn send_sgi_to_self(&mut self, sgi: u32) {
+        assert!(sgi < SGI_COUNT, "No SGI {}", sgi);
+        let mpidr = MPIDR_EL1.get();
+        let target_list = 1 << (mpidr & 0xf);
+        let aff1 = mpidr >> 8 & 0xff;
+        let aff2 = mpidr >> 16 & 0xff;
+        let aff3 = mpidr >> 32 & 0xff;
+        let value = aff3 << 48 | aff2 << 32 | (sgi as u64) << 24 | aff1 << 16 | target_list;
+        unsafe {
+            // ICC_SGI1R_EL1
+            asm!("msr S3_0_C12_C11_5, {0}", in(reg) value);
+            barrier::isb(barrier::SY);
+        }
+    }
+}
+
+impl IrqChip for GICv3 {
+    fn name(&self) -> &'static str {
+        "GICv3"
+    }
+
+    fn irq_count(&self) -> usize {
+        self.distributor.irq_count()
+    }
+
+    fn unmask(&mut self, irq: u32) {
+        if irq < SPI_BASE {
+            self.redist_regs().ISENABLER0.set(1 << irq);
+        } else {
+            self.distributor.enable(irq);
+        }
+    }
+
+    fn mask(&mut self, irq: u32) {
+        if irq < SPI_BASE {
+            self.redist_regs().ICENABLER0.set(1 << irq);
+        } else {
+            self.distributor.disable(irq);
+        }
+    }
+
+    fn claim(&mut self) -> Option<u32> {
+        let iar: usize;
+        // ICC_IAR1_EL1
+        unsafe { asm!("mrs {0}, S3_0_C12_C12_0", out(reg) iar) };
+        let irq = iar as u32 & 0xff_ffff;
+        if (SPURIOUS..SPURIOUS + 4).contains(&irq) {
+            return None;
+        }
+        Some(irq)
+    }
+
+    fn complete(&mut self, irq: u32) {
+        // ICC_EOIR1_EL1
+        unsafe { asm!("msr S3_0_C12_C12_1, {0}", in(reg) irq as usize) };
+    }
+
+    fn translate(&self, _parent: &FdtNode, specifier: &InterruptSpecifier) -> Option<u32> {
+        intid(specifier)
+    }
+}
@@ -0,0 +1,11 @@
+pub mod bcm2835_intc;
+pub mod bcm2836_local;
+mod gic;
+mod gicv2;
+mod gicv3;
+
+pub use bcm2835_intc::BCM2835Intc;
+pub use bcm2836_local::{BCM2836Local, CNTPNS_IRQ, GPU_IRQ_BASE};
+pub use gic::{intid, Distributor};
+pub use gicv2::GICv2;
+pub use gicv3::GICv3;
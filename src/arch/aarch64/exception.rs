@@ -1 +1,148 @@
 global_asm!(include_str!("exception.S"));
+
+use crate::{printk2, STDOUT};
+use cortex_a::{barrier, regs::*};
+
+use super::{cpu, Regs};
+
+/// Registers saved by the exception vectors, see exception.S.
+#[repr(C)]
+#[derive(Clone)]
+pub struct TrapFrame {
+    /// x0-x30, with sp_el0 in the last slot.
+    pub regs: Regs,
+    /// Address the exception returns to.
+    pub elr: usize,
+    /// Saved processor state.
+    pub spsr: usize,
+}
+
+/// Exception classes from ESR_EL1.EC.
+mod class {
+    pub const UNKNOWN: usize = 0x00;
+    pub const FP_ACCESS: usize = 0x07;
+    pub const SVC64: usize = 0x15;
+    pub const INSTRUCTION_ABORT_LOWER: usize = 0x20;
+    pub const INSTRUCTION_ABORT_SAME: usize = 0x21;
+    pub const DATA_ABORT_LOWER: usize = 0x24;
+    pub const DATA_ABORT_SAME: usize = 0x25;
+}
+
+/// Describes the fault status code of an abort.
+const fn fault_status(fsc: usize) -> &'static str {
+    match fsc & 0b111100 {
+        0b000000 => "address size fault",
+        0b000100 => "translation fault",
+        0b001000 => "access flag fault",
+        0b001100 => "permission fault",
+        0b010000 => "synchronous external abort",
+        _ if fsc == 0b100001 => "alignment fault",
+        _ => "unknown fault",
+    }
+}
+
+/// Points VBAR_EL1 at the exception vectors.
+///
+/// # Safety
+/// The vectors must be mapped at their link address.
+pub unsafe fn init() {
+    extern "C" {
+        static __exception_vectors: u8;
+    }
+    VBAR_EL1.set(&__exception_vectors as *const _ as u64);
+    barrier::isb(barrier::SY);
+}
+
+/// Ends the running task after it exited or faulted at EL0, and returns to
+/// the next one instead.
+fn kill_current(frame: &mut TrapFrame) {
+    frame.elr = crate::sched::kill_current(&mut frame.regs, frame.elr);
+    frame.spsr = cpu::return_spsr();
+}
+
+#[no_mangle]
+extern "C" fn exception_sync(frame: &mut TrapFrame) {
+    let stdout = unsafe { STDOUT.get_mut() };
+    let esr = ESR_EL1.get() as usize;
+    let far = FAR_EL1.get() as usize;
+    let ec = esr >> 26 & 0x3f;
+    let iss = esr & 0x1ff_ffff;
+
+    match ec {
+        // The task's SIMD&FP registers are loaded, so retry the instruction
+        class::FP_ACCESS if crate::sched::fp_trap() => {}
+        class::SVC64 if cpu::trapped_from_user(frame.spsr) => {
+            let args = [frame.regs[0], frame.regs[1], frame.regs[2]];
+            match crate::syscall::dispatch(frame.regs[8], args) {
+                Some(value) => frame.regs[0] = value,
+                None => kill_current(frame),
+            }
+        }
+        class::SVC64 => {
+            // elr already points past the svc
+            printk2!(stdout, "svc #{} from elr=0x{:x}", iss & 0xffff, frame.elr);
+        }
+        // A fault at EL0 only takes down the process
+        _ if cpu::trapped_from_user(frame.spsr) => {
+            printk2!(
+                stdout,
+                "Killing process {}: exception ec=0x{:x} iss=0x{:x} far=0x{:x} elr=0x{:x}",
+                crate::sched::current(),
+                ec,
+                iss,
+                far,
+                frame.elr
+            );
+            kill_current(frame);
+        }
+        class::DATA_ABORT_LOWER | class::DATA_ABORT_SAME => {
+            let write = iss >> 6 & 1 == 1;
+            panic!(
+                "Data abort: {} at level {} on {} of 0x{:x}, elr=0x{:x}",
+                fault_status(iss & 0x3f),
+                iss & 0b11,
+                if write { "write" } else { "read" },
+                far,
+                frame.elr
+            );
+        }
+        class::INSTRUCTION_ABORT_LOWER | class::INSTRUCTION_ABORT_SAME => panic!(
+            "Instruction abort: {} at level {} fetching 0x{:x}, elr=0x{:x}",
+            fault_status(iss & 0x3f),
+            iss & 0b11,
+            far,
+            frame.elr
+        ),
+        class::UNKNOWN => panic!("Undefined instruction at elr=0x{:x}", frame.elr),
+        _ => panic!(
+            "Unhandled synchronous exception: ec=0x{:x} iss=0x{:x} far=0x{:x} elr=0x{:x}",
+            ec, iss, far, frame.elr
+        ),
+    }
+}
+
+#[no_mangle]
+extern "C" fn exception_irq(_frame: &mut TrapFrame) {
+    crate::irq::dispatch();
+}
+
+#[no_mangle]
+extern "C" fn exception_fiq(frame: &mut TrapFrame) {
+    let stdout = unsafe { STDOUT.get_mut() };
+    printk2!(stdout, "Unhandled FIQ, elr=0x{:x}", frame.elr);
+}
+
+#[no_mangle]
+extern "C" fn exception_serror(frame: &mut TrapFrame) {
+    panic!("SError: esr=0x{:x} elr=0x{:x}", ESR_EL1.get(), frame.elr);
+}
+
+#[no_mangle]
+extern "C" fn exception_unexpected(frame: &mut TrapFrame) {
+    panic!(
+        "Exception from an unsupported vector: esr=0x{:x} elr=0x{:x} spsr=0x{:x}",
+        ESR_EL1.get(),
+        frame.elr,
+        frame.spsr
+    );
+}
@@ -0,0 +1,139 @@
+//! Discovery of the GIC, which delivers interrupts to [crate::irq].
+
+use alloc::boxed::Box;
+
+use crate::{
+    bsp,
+    driver_interfaces::IrqChip,
+    fdt::{Fdt, FdtNode},
+    printk,
+};
+
+use super::{
+    cpu,
+    drivers::{
+        bcm2835_intc, bcm2836_local, intid, BCM2835Intc, BCM2836Local, GICv2, GICv3, CNTPNS_IRQ,
+    },
+    mmu::map_device,
+    time,
+};
+
+/// `compatible` strings of GICv2 compatible controllers.
+const GICV2_COMPATIBLE: &[&str] = &[
+    "arm,gic-400",
+    "arm,cortex-a15-gic",
+    "arm,cortex-a9-gic",
+    "arm,cortex-a7-gic",
+];
+
+/// `compatible` strings of GICv3 controllers.
+const GICV3_COMPATIBLE: &[&str] = &["arm,gic-v3"];
+
+/// `compatible` strings of the generic timer.
+const TIMER_COMPATIBLE: &[&str] = &["arm,armv8-timer", "arm,armv7-timer"];
+
+/// Index of the non-secure EL1 physical timer in the timer's `interrupts`.
+const TIMER_NS_EL1_PHYS: usize = 1;
+
+/// An interrupt controller, and the interrupt of the generic timer on it.
+struct Controller {
+    chip: Box<dyn IrqChip>,
+    timer_irq: Option<u32>,
+}
+
+/// Maps some registers as device memory.
+unsafe fn map(name: &str, base: usize, size: usize) -> Option<usize> {
+    match map_device(base, size) {
+        Ok(virt_addr) => Some(virt_addr),
+        Err(e) => {
+            printk!("Failed to map {} registers: {:?}", name, e);
+            None
+        }
+    }
+}
+
+/// Maps the `index`th register block of a node as device memory.
+unsafe fn map_reg(node: &FdtNode, index: usize) -> Option<usize> {
+    let reg = node.reg()?.nth(index)?;
+    map(node.name(), node.translate_address(reg.address)?, reg.size)
+}
+
+/// Finds the generic timer's interrupt on a GIC.
+fn gic_timer_irq(fdt: &Fdt) -> Option<u32> {
+    let timer = fdt.find_compatible(TIMER_COMPATIBLE)?;
+    intid(&timer.interrupts()?.nth(TIMER_NS_EL1_PHYS)?)
+}
+
+/// Creates the driver for the GIC in the device tree, if there is one.
+unsafe fn probe_gic(fdt: &Fdt) -> Option<Controller> {
+    let chip: Box<dyn IrqChip> = if let Some(node) = fdt.find_compatible(GICV3_COMPATIBLE) {
+        let mut gic = GICv3::new(map_reg(&node, 0)?, map_reg(&node, 1)?)?;
+        gic.init();
+        Box::new(gic)
+    } else {
+        let node = fdt.find_compatible(GICV2_COMPATIBLE)?;
+        let mut gic = GICv2::new(map_reg(&node, 0)?, map_reg(&node, 1)?);
+        gic.init();
+        Box::new(gic)
+    };
+    Some(Controller {
+        chip,
+        timer_irq: gic_timer_irq(fdt),
+    })
+}
+
+/// Creates the drivers for the BCM2836 local interrupt controller and the
+/// BCM2835 one chained to it. Their addresses come from the board when the
+/// device tree doesn't have them, since QEMU doesn't pass one to raspi3.
+unsafe fn probe_bcm2836(fdt: &Fdt) -> Option<Controller> {
+    let local = match fdt.find_compatible(bcm2836_local::COMPATIBLE) {
+        Some(node) => map_reg(&node, 0)?,
+        None => {
+            let (local, _) = bsp::fallback_irq_controllers()?;
+            map("local interrupt controller", local, 0x100)?
+        }
+    };
+    let gpu = match fdt.find_compatible(bcm2835_intc::COMPATIBLE) {
+        Some(node) => map_reg(&node, 0),
+        None => bsp::fallback_irq_controllers()
+            .and_then(|(_, armctrl)| map("ARM interrupt controller", armctrl, 0x200)),
+    };
+    let mut chip = BCM2836Local::new(
+        local,
+        cpu::core_num() as usize,
+        gpu.map(|gpu| BCM2835Intc::new(gpu)),
+    );
+    chip.init();
+    Some(Controller {
+        chip: Box::new(chip),
+        // Always wired to the same local source
+        timer_irq: Some(CNTPNS_IRQ),
+    })
+}
+
+/// Routes the generic timer's interrupt, so waits can sleep.
+fn route_timer(timer_irq: Option<u32>) {
+    match timer_irq.map(|irq| crate::irq::request(irq, "arch_timer", time::handle_irq)) {
+        Some(Ok(())) => time::irq_routed(),
+        Some(Err(e)) => printk!("Failed to route the timer interrupt: {:?}", e),
+        None => printk!("No timer interrupt in the device tree"),
+    }
+}
+
+/// Finds the interrupt controller in the device tree, installs it and
+/// unmasks IRQs on this CPU.
+///
+/// # Safety
+/// Must only be called once, after the allocator is set up.
+pub unsafe fn init(fdt: &Fdt) {
+    let controller = match probe_gic(fdt).or_else(|| probe_bcm2836(fdt)) {
+        Some(controller) => controller,
+        None => {
+            printk!("No interrupt controller found, IRQs are disabled");
+            return;
+        }
+    };
+    crate::irq::set_chip(controller.chip);
+    route_timer(controller.timer_irq);
+    asm!("msr daifclr, #2");
+}
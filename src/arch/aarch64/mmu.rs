@@ -1,12 +1,41 @@
 use cortex_a::{barrier, regs::*};
 use modular_bitfield::prelude::*;
-use crate::physical_page_allocator::{ALLOCATOR, PAGE_SIZE};
+use crate::{link_var, printk, STDOUT};
+use crate::driver_interfaces::Console;
+use crate::kmalloc::with_page_allocator;
+use crate::mmu::{AddressSpace, Attributes, MapError, Permissions};
+use crate::oom;
 
-mod level {
-    //pub const GiB_1: usize = 0;
-    pub const MiB_2: usize = 1;
-    //pub const KiB_4: usize = 2;
-}
+/// Start of the TTBR1 half of a 39 bit address space. The kernel and the
+/// first 4GiB of physical memory are mapped here, at their physical address
+/// plus this. Must match `KERNEL_OFFSET` in the linker script.
+pub const HIGHER_HALF_BASE: usize = 0xFFFF_FF80_0000_0000;
+
+/// Size of the translation granule, which is also the page size.
+/// Picked at build time with the `granule_16k` and `granule_64k` features.
+#[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
+pub const PAGE_SIZE: usize = 0x1000;
+#[cfg(feature = "granule_16k")]
+pub const PAGE_SIZE: usize = 0x4000;
+#[cfg(feature = "granule_64k")]
+pub const PAGE_SIZE: usize = 0x1_0000;
+
+/// Number of bits in a virtual address, in both halves.
+const VA_BITS: usize = 39;
+const PAGE_SHIFT: usize = PAGE_SIZE.trailing_zeros() as usize;
+/// Number of address bits each level of tables resolves.
+const BITS_PER_LEVEL: usize = PAGE_SHIFT - 3;
+/// Number of levels of tables: 3 for 4KiB and 16KiB granules, 2 for 64KiB.
+const LEVELS: usize = (VA_BITS - PAGE_SHIFT + BITS_PER_LEVEL - 1) / BITS_PER_LEVEL;
+/// Level of the tables holding pages. The root is level 0.
+const PAGE_LEVEL: usize = LEVELS - 1;
+/// Level of the blocks just above pages: 2MiB, 32MiB or 512MiB.
+const BLOCK_LEVEL: usize = PAGE_LEVEL - 1;
+/// Level of the largest blocks. Only the 4KiB granule has 1GiB blocks.
+#[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
+const MIN_BLOCK_LEVEL: usize = 0;
+#[cfg(any(feature = "granule_16k", feature = "granule_64k"))]
+const MIN_BLOCK_LEVEL: usize = BLOCK_LEVEL;
 #[derive(BitfieldSpecifier, Clone, Copy, PartialEq, Eq)]
 #[bits = 1]
 enum PTEType {
@@ -42,7 +71,7 @@ pub struct PTE {
     sh: Armv8SH,   // SH [8:9]
     af: bool,      // AF [10]
     ng: bool,      // ng [11]
-    addr: B36,     // addr [12:47]
+    addr: B36,     // addr [12:47], low bits are 0 for larger granules
     #[skip]
     res0: B4,     // res [48:51]
     c: bool,       // C [52]
@@ -71,97 +100,515 @@ impl PTE {
         a
     }*/
 }
+/// A table of any level. Tables take up a whole granule.
 #[repr(C)]
-#[repr(align(4096))]
+#[cfg_attr(not(any(feature = "granule_16k", feature = "granule_64k")), repr(align(4096)))]
+#[cfg_attr(feature = "granule_16k", repr(align(16384)))]
+#[cfg_attr(feature = "granule_64k", repr(align(65536)))]
 pub struct PageTable {
-    entries: [PTE; 512]
+    entries: [PTE; PAGE_SIZE / 8]
 }
 
-pub fn map_page(root: &mut PageTable, vaddr: usize, paddr: usize, level: usize) {
-    let indexes = [
-        (vaddr >> 30) & 0x1FF,
-        (vaddr >> 21) & 0x1FF,
-        (vaddr >> 12) & 0x1FF
-    ];
-    // now pte_ptr has the l1 entry pointing to the l2 table
-    let mut pte_ptr = &mut root.entries[indexes[0]];
-    for i in 0..level {
-        if pte_ptr.is_invalid() {
-            let next_level_table = unsafe { ALLOCATOR.try_zallocate(PAGE_SIZE).expect("Couldn't allocate page") };
-            pte_ptr.set_addr((next_level_table as u64) >> 12);
-            pte_ptr.set_ptype(PTEType::Table);
-            pte_ptr.set_af(true);
-            pte_ptr.set_valid(true);
+impl PageTable {
+    const fn new() -> Self {
+        Self { entries: [PTE::new(); PAGE_SIZE / 8] }
+    }
+}
+
+/// The kernel's TTBR1 root table, made by [init].
+static mut KERNEL_TABLE: *mut PageTable = core::ptr::null_mut();
+
+/// Number of tables in [EARLY_TABLES]. The boot mappings take a root for each
+/// half, plus the tables their 4GiB of blocks go in below the root. The last
+/// two are for the start of the device window, which the console is mapped
+/// into before the page allocator is up.
+#[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
+const EARLY_TABLE_COUNT: usize = 6 + 2;
+#[cfg(feature = "granule_16k")]
+const EARLY_TABLE_COUNT: usize = 4 + 2;
+#[cfg(feature = "granule_64k")]
+const EARLY_TABLE_COUNT: usize = 2 + 2;
+
+/// Tables for the boot mappings, which are made before the page allocator is up.
+static mut EARLY_TABLES: [PageTable; EARLY_TABLE_COUNT] = [PageTable::new(); EARLY_TABLE_COUNT];
+static mut EARLY_TABLES_USED: usize = 0;
+
+/// Offset from a physical address to its higher half address.
+/// Zero until we are running in the higher half.
+static mut PHYS_TO_VIRT_OFFSET: usize = 0;
+
+/// Allocates a zeroed table, running the OOM handler if we are out of pages.
+fn alloc_table() -> Result<*mut PageTable, MapError> {
+    unsafe {
+        if !with_page_allocator(|pages| pages.is_initialized()) {
+            let table = EARLY_TABLES
+                .get_mut(EARLY_TABLES_USED)
+                .ok_or(MapError::OutOfMemory)?;
+            EARLY_TABLES_USED += 1;
+            return Ok(table);
         }
-        pte_ptr = unsafe { (pte_ptr.phys_addr() as *mut PTE).add(indexes[i+1]).as_mut().unwrap() };
-    }
-    pte_ptr.set_ptype(PTEType::Block);
-    pte_ptr.set_valid(true);
-    pte_ptr.set_af(true);
-    if paddr < 0xFE00_0000 {
-        pte_ptr.set_mem_attr(1);
-        pte_ptr.set_sh(Armv8SH::InnerShareable);
+        oom::retry(PAGE_SIZE, || with_page_allocator(|pages| pages.try_zallocate(PAGE_SIZE)))
+            .map(|table| table as *mut PageTable)
+            .ok_or(MapError::OutOfMemory)
+    }
+}
+
+/// Gets the lowest address bit resolved by the given level.
+const fn level_shift(level: usize) -> usize {
+    PAGE_SHIFT + BITS_PER_LEVEL * (PAGE_LEVEL - level)
+}
+
+/// Gets the size of a block or page at the given level.
+const fn level_size(level: usize) -> usize {
+    1 << level_shift(level)
+}
+
+/// Gets the index into the table at the given level for a virtual address.
+/// The root may resolve fewer bits than the other levels.
+const fn table_index(vaddr: usize, level: usize) -> usize {
+    let bits = if level == 0 { VA_BITS - level_shift(0) } else { BITS_PER_LEVEL };
+    (vaddr >> level_shift(level)) & ((1 << bits) - 1)
+}
+
+/// Gets the table the given entry points to.
+fn next_table<'a>(pte: &PTE) -> &'a mut PageTable {
+    unsafe { &mut *(phys_to_virt(pte.phys_addr()) as *mut PageTable) }
+}
+
+/// Makes a block or page entry.
+fn leaf(paddr: usize, level: usize, permissions: Permissions, attributes: Attributes) -> PTE {
+    let user = attributes.contains(Attributes::User);
+    let execute = permissions.contains(Permissions::Execute);
+    // There is no write-only, so anything mapped is readable
+    let ap = match (permissions.contains(Permissions::Write), user) {
+        (true, false) => Armv8AP::RwEl1,
+        (true, true) => Armv8AP::RwEl0,
+        (false, false) => Armv8AP::RoEl1,
+        (false, true) => Armv8AP::RoEl0
+    };
+    let (mem_attr, sh) = if attributes.contains(Attributes::Device) {
+        (0, Armv8SH::OuterShareable)
     } else {
-        pte_ptr.set_mem_attr(0);
-        pte_ptr.set_sh(Armv8SH::OuterShareable);
-    }
-    pte_ptr.set_addr((paddr as u64 >> 12) & 0xF_FFFF_FFFF);
-}
-
-pub fn virt_to_phys(root: &PageTable, vaddr: usize) -> Option<usize> {
-    let indexes = [ // address components
-        (vaddr >> 30) & 0x1ff, // level 1 (1 GiB)
-        (vaddr >> 21) & 0x1ff, // level 2 (2 MiB)
-        (vaddr >> 12) & 0x1ff, // level 3 (4 KiB)
-    ];
-    let mut v = &root.entries[indexes[0]];
-    for i in 0..=2 {
-        if v.is_invalid() {
-            break;
-        } else if v.ptype() == PTEType::Block {
-            // i = 2: 4k, i = 1: 2m, i = 0: 1g
-            return Some(v.phys_addr() + (vaddr & match i {
-                0 => 0x3fffffff,
-                1 => 0x1fffff,
-                _ => 0xfff
-            }));
+        (1, Armv8SH::InnerShareable)
+    };
+    PTE::new()
+        .with_valid(true)
+        // Pages at the last level use the table encoding
+        .with_ptype(if level == PAGE_LEVEL { PTEType::Table } else { PTEType::Block })
+        .with_af(true)
+        .with_ap(ap)
+        .with_ng(!attributes.contains(Attributes::Global))
+        .with_mem_attr(mem_attr)
+        .with_sh(sh)
+        // Only the mode that owns the page may execute it
+        .with_pxn(!execute || user)
+        .with_uxn(!execute || !user)
+        .with_addr((paddr as u64 >> 12) & 0xF_FFFF_FFFF)
+}
+
+/// Gets the entry for a virtual address at the given level,
+/// creating the tables above it if `alloc` is set.
+///
+/// # Errors
+/// Fails if a block is mapped over the address, if a table is missing and
+/// `alloc` isn't set, or if there is no memory for a table.
+fn entry_mut(root: &mut PageTable, vaddr: usize, level: usize, alloc: bool) -> Result<&mut PTE, MapError> {
+    let mut table = root;
+    for i in 0..level {
+        let pte = &mut table.entries[table_index(vaddr, i)];
+        if pte.is_invalid() {
+            if !alloc {
+                return Err(MapError::NotMapped);
+            }
+            let next_level_table = alloc_table()?;
+            *pte = PTE::new()
+                .with_addr((virt_to_phys(next_level_table as usize) as u64) >> 12)
+                .with_ptype(PTEType::Table)
+                .with_af(true)
+                .with_valid(true);
+        } else if pte.ptype() == PTEType::Block {
+            return Err(MapError::AlreadyMapped);
         }
-        v = unsafe { (v.phys_addr() as *mut PTE).add(indexes[i+1]).as_mut().unwrap() };
+        table = next_table(pte);
+    }
+    Ok(&mut table.entries[table_index(vaddr, level)])
+}
+
+/// Finds the block or page mapping a virtual address, and its level.
+fn find_leaf(root: &PageTable, vaddr: usize) -> Option<(&PTE, usize)> {
+    let mut table = root;
+    for level in 0..=PAGE_LEVEL {
+        let pte = &table.entries[table_index(vaddr, level)];
+        if pte.is_invalid() {
+            return None;
+        } else if level == PAGE_LEVEL || pte.ptype() == PTEType::Block {
+            return Some((pte, level));
+        }
+        table = next_table(pte);
     }
     None
 }
 
-#[inline(never)]
+/// Maps a block or page at the given level, panicking if that fails.
+pub fn map_page(root: &mut PageTable, vaddr: usize, paddr: usize, level: usize) {
+    if let Err(e) = try_map_page(root, vaddr, paddr, level) {
+        panic!("Failed to map page {:x}: {:?}", vaddr, e);
+    }
+}
+
+/// Maps a global RWX block or page at the given level.
+/// Addresses from 0xFE00_0000 on are the peripherals, and blocks reaching
+/// them are mapped as device memory.
+///
+/// # Errors
+/// Fails if the address is already mapped, or if there is no memory for a table.
+pub fn try_map_page(root: &mut PageTable, vaddr: usize, paddr: usize, level: usize) -> Result<(), MapError> {
+    let attributes = if paddr + level_size(level) <= 0xFE00_0000 {
+        Attributes::Global
+    } else {
+        Attributes::Global | Attributes::Device
+    };
+    try_map(root, vaddr, paddr, level, Permissions::RWX, attributes)
+}
+
+/// Maps a block or page at the given level, [PAGE_LEVEL] being pages.
+///
+/// # Errors
+/// Fails if the address is already mapped, or if there is no memory for a table.
+pub fn try_map(root: &mut PageTable, vaddr: usize, paddr: usize, level: usize, permissions: Permissions, attributes: Attributes) -> Result<(), MapError> {
+    let pte = entry_mut(root, vaddr, level, true)?;
+    if pte.valid() {
+        return Err(MapError::AlreadyMapped);
+    }
+    *pte = leaf(paddr & !(level_size(level) - 1), level, permissions, attributes);
+    Ok(())
+}
+
+/// Maps a range of memory, using the largest block that fits each chunk.
+///
+/// # Errors
+/// Fails if part of the range is already mapped, or if there is no memory
+/// for a table. Whatever was mapped before the failure stays mapped.
+pub fn try_map_range(root: &mut PageTable, vaddr: usize, paddr: usize, len: usize, permissions: Permissions, attributes: Attributes) -> Result<(), MapError> {
+    let mut vaddr_cur = vaddr & !(PAGE_SIZE - 1);
+    let mut paddr_cur = paddr & !(PAGE_SIZE - 1);
+    let vaddr_end = (vaddr + len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    while vaddr_cur < vaddr_end {
+        let level = (MIN_BLOCK_LEVEL..=PAGE_LEVEL)
+            .find(|&level| {
+                let size = level_size(level);
+                (vaddr_cur | paddr_cur) & (size - 1) == 0 && vaddr_end - vaddr_cur >= size
+            })
+            .unwrap_or(PAGE_LEVEL);
+        try_map(root, vaddr_cur, paddr_cur, level, permissions, attributes)?;
+        vaddr_cur += level_size(level);
+        paddr_cur += level_size(level);
+    }
+    Ok(())
+}
+
+/// Runs `update` on the entry of every block and page in a range.
+///
+/// # Errors
+/// Fails if the range covers only part of a block, or if part of it
+/// isn't mapped and `skip_unmapped` is false.
+fn try_update_range(root: &mut PageTable, vaddr: usize, len: usize, skip_unmapped: bool, mut update: impl FnMut(&mut PTE, usize)) -> Result<(), MapError> {
+    let mut vaddr_cur = vaddr & !(PAGE_SIZE - 1);
+    let vaddr_end = (vaddr + len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    while vaddr_cur < vaddr_end {
+        let level = match find_leaf(root, vaddr_cur) {
+            Some((_, level)) => level,
+            None if skip_unmapped => {
+                vaddr_cur += PAGE_SIZE;
+                continue;
+            }
+            None => return Err(MapError::NotMapped)
+        };
+        let size = level_size(level);
+        if vaddr_cur & (size - 1) != 0 || vaddr_end - vaddr_cur < size {
+            return Err(MapError::SplitsLargePage);
+        }
+        update(entry_mut(root, vaddr_cur, level, false)?, level);
+        vaddr_cur += size;
+    }
+    Ok(())
+}
+
+pub fn translate(root: &PageTable, vaddr: usize) -> Option<usize> {
+    let (pte, level) = find_leaf(root, vaddr)?;
+    Some(pte.phys_addr() + (vaddr & (level_size(level) - 1)))
+}
+
+/// Invalidates every TLB entry on all CPUs.
+fn flush_tlb() {
+    unsafe { asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb") };
+}
+
+/// An address space, made of a root table. The kernel's lives in TTBR1,
+/// and all others in TTBR0.
+pub struct RootTable {
+    table: *mut PageTable
+}
+
+impl RootTable {
+    /// Gets the kernel's address space.
+    pub fn kernel() -> Self {
+        Self { table: unsafe { KERNEL_TABLE } }
+    }
+
+    /// Nothing to do, the kernel stacks are mapped through TTBR1, which every
+    /// address space shares.
+    ///
+    /// # Errors
+    /// Never fails.
+    pub const fn share_kernel_stacks() -> Result<(), MapError> {
+        Ok(())
+    }
+
+    /// Creates an empty address space. The kernel stays mapped through TTBR1
+    /// while it is active.
+    ///
+    /// # Errors
+    /// Fails if there is no memory for the root table.
+    pub fn new() -> Result<Self, MapError> {
+        Ok(Self { table: alloc_table()? })
+    }
+
+    /// Frees an address space made by [RootTable::new], along with its tables.
+    /// The memory it maps isn't freed.
+    ///
+    /// # Safety
+    /// The address space must not be active, and mustn't be used again.
+    pub unsafe fn free(self) {
+        free_tables(&mut *self.table, 0);
+        with_page_allocator(|pages| pages.deallocate(self.table as _, PAGE_SIZE));
+    }
+}
+
+/// Frees the tables below a table at the given level.
+fn free_tables(table: &mut PageTable, level: usize) {
+    if level == PAGE_LEVEL {
+        return;
+    }
+    for pte in table.entries.iter().filter(|pte| pte.valid() && pte.ptype() == PTEType::Table) {
+        let next = next_table(pte);
+        free_tables(next, level + 1);
+        with_page_allocator(|pages| pages.deallocate(next as *mut _ as _, PAGE_SIZE));
+    }
+}
+
+impl AddressSpace for RootTable {
+    fn map(&mut self, virt_addr: usize, phys_addr: usize, len: usize, permissions: Permissions, attributes: Attributes) -> Result<(), MapError> {
+        try_map_range(unsafe { &mut *self.table }, virt_addr, phys_addr, len, permissions, attributes)
+    }
+
+    fn unmap(&mut self, virt_addr: usize, len: usize) -> Result<(), MapError> {
+        let result = try_update_range(unsafe { &mut *self.table }, virt_addr, len, true, |pte, _| pte.set_valid(false));
+        flush_tlb();
+        result
+    }
+
+    fn protect(&mut self, virt_addr: usize, len: usize, permissions: Permissions, attributes: Attributes) -> Result<(), MapError> {
+        let result = try_update_range(unsafe { &mut *self.table }, virt_addr, len, false, |pte, level| {
+            *pte = leaf(pte.phys_addr(), level, permissions, attributes);
+        });
+        flush_tlb();
+        result
+    }
+
+    fn translate(&self, virt_addr: usize) -> Option<usize> {
+        translate(unsafe { &*self.table }, virt_addr)
+    }
+
+    unsafe fn activate(&self) {
+        if self.table == KERNEL_TABLE {
+            // Nothing but the kernel, so leave TTBR0 unused
+            TCR_EL1.modify(TCR_EL1::EPD0::DisableTTBR0Walks);
+        } else {
+            TTBR0_EL1.set_baddr(virt_to_phys(self.table as usize) as u64);
+            TCR_EL1.modify(TCR_EL1::EPD0::EnableTTBR0Walks);
+        }
+        barrier::isb(barrier::SY);
+        flush_tlb();
+    }
+}
+
+/// Converts a physical address to an address the kernel can dereference.
+/// Only valid for the first 4GiB, which [init] maps in the higher half.
+pub fn phys_to_virt(phys_addr: usize) -> usize {
+    phys_addr + unsafe { PHYS_TO_VIRT_OFFSET }
+}
+
+/// Converts a kernel address back to a physical address.
+/// Inverse of [phys_to_virt], and also works for kernel image addresses.
+pub fn virt_to_phys(virt_addr: usize) -> usize {
+    if virt_addr >= HIGHER_HALF_BASE {
+        virt_addr - HIGHER_HALF_BASE
+    } else {
+        virt_addr
+    }
+}
+
+/// Physical addresses that [phys_to_virt] can convert.
+/// [init] maps the first 4GiB.
+pub const fn addressable_ram() -> core::ops::Range<usize> {
+    0..0x1_0000_0000
+}
+
+/// Sets up the direct map of RAM. [init] already maps the first 4GiB
+/// in the higher half, so there is nothing to do.
+///
+/// # Safety
+/// Always safe, but kept unsafe to match the other architectures.
+pub unsafe fn init_direct_map(_map: &crate::memory::MemoryMap) {}
+
+/// Start of the window [map_device] maps MMIO registers into, past the
+/// map of physical memory. The map of physical memory uses blocks, which
+/// may hold both RAM and registers, so it can't be made device memory.
+const DEVICE_BASE: usize = HIGHER_HALF_BASE + 0x10_0000_0000;
+/// Next free address in the device window.
+static mut DEVICE_NEXT: usize = DEVICE_BASE;
+
+/// Start of the window kernel thread stacks are mapped into, past the
+/// device window.
+pub const KERNEL_STACK_BASE: usize = HIGHER_HALF_BASE + 0x20_0000_0000;
+
+/// Where user programs are loaded, in the TTBR0 half.
+pub const USER_BASE: usize = 0x1_0000_0000;
+
+/// Top of the stack of user programs.
+pub const USER_STACK_TOP: usize = 0x2_0000_0000;
+
+/// Maps some MMIO registers as device memory, and returns their virtual address.
+/// Before we are running in the higher half the MMU is off, so this is the
+/// physical address.
+///
+/// # Errors
+/// Fails if there is no memory for a table.
+///
+/// # Safety
+/// Must not be called from more than one CPU at a time.
+pub unsafe fn map_device(phys_addr: usize, len: usize) -> Result<usize, MapError> {
+    if PHYS_TO_VIRT_OFFSET == 0 {
+        return Ok(phys_addr);
+    }
+    let offset = phys_addr & (PAGE_SIZE - 1);
+    let size = (offset + len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let virt_addr = DEVICE_NEXT;
+    RootTable::kernel().map(virt_addr, phys_addr - offset, size, Permissions::RW, Attributes::Global | Attributes::Device)?;
+    DEVICE_NEXT += size;
+    Ok(virt_addr + offset)
+}
+
+/// Checks `ID_AA64MMFR0_EL1` for support of the granule we were built for.
+fn granule_supported() -> bool {
+    let mmfr0 = ID_AA64MMFR0_EL1.get();
+    // TGran4 and TGran64 are 0xF when unsupported, but TGran16 is 0
+    if cfg!(feature = "granule_64k") {
+        (mmfr0 >> 24) & 0xF != 0xF
+    } else if cfg!(feature = "granule_16k") {
+        (mmfr0 >> 20) & 0xF != 0
+    } else {
+        (mmfr0 >> 28) & 0xF != 0xF
+    }
+}
+
+/// Clears bss, maps the kernel and the first 4GiB of physical memory at
+/// [HIGHER_HALF_BASE] in TTBR1, and enables the MMU. Then continues in the
+/// higher half, where TTBR0 is turned off, and calls `kinit(dtb_addr)`.
+/// Hangs if the CPU doesn't support our granule, since there is no console yet.
+///
 /// # Safety
-/// Only safe to call once.
-pub unsafe fn init() {
+/// Only safe to call once, at EL1 with the MMU off. Runs at the physical
+/// address the kernel was loaded at, so nothing may be used that holds
+/// link time addresses, like `dyn` vtables or printk, until the MMU is on.
+#[inline(never)]
+pub unsafe fn init(kinit: usize, dtb_addr: usize) -> ! {
+    link_var!(__bss_start, __bss_end);
+    let bss_start = &__bss_start as *const _ as *mut u8;
+    let bss_end = &__bss_end as *const _ as usize;
+    core::ptr::write_bytes(bss_start, 0, bss_end - bss_start as usize);
+    if !granule_supported() {
+        super::cpu::wait_forever();
+    }
+
     // Attr0 -> Normal, Attr1 -> device
     MAIR_EL1.write(
         MAIR_EL1::Attr1_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc
         + MAIR_EL1::Attr1_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc
         + MAIR_EL1::Attr0_Device::nonGathering_nonReordering_EarlyWriteAck
     );
-    let root_table_0_u8 = ALLOCATOR.try_zallocate(PAGE_SIZE).expect("Couldn't allocate page");
-    let table_0 = core::mem::transmute::<&mut u8, &mut PageTable>(&mut *root_table_0_u8);
-    for i in 0..2048 {
-        map_page(table_0, i << 21, i << 21, level::MiB_2);
+    // identity map until we are running in the higher half
+    let identity = &mut *alloc_table().expect("Couldn't allocate table");
+    for i in 0..(0x1_0000_0000 >> level_shift(MIN_BLOCK_LEVEL)) {
+        let addr = i << level_shift(MIN_BLOCK_LEVEL);
+        map_page(identity, addr, addr, MIN_BLOCK_LEVEL);
+    }
+    let kernel = &mut *alloc_table().expect("Couldn't allocate table");
+    for i in 0..(0x1_0000_0000 >> level_shift(BLOCK_LEVEL)) {
+        let addr = i << level_shift(BLOCK_LEVEL);
+        map_page(kernel, HIGHER_HALF_BASE + addr, addr, BLOCK_LEVEL);
     }
-    TTBR0_EL1.set_baddr(root_table_0_u8 as *const _ as u64);
-    TTBR0_EL1.modify(TTBR0_EL1::CnP::SET);
+    let granule = if cfg!(feature = "granule_64k") {
+        TCR_EL1::TG0::KiB_64 + TCR_EL1::TG1::KiB_64
+    } else if cfg!(feature = "granule_16k") {
+        TCR_EL1::TG0::KiB_16 + TCR_EL1::TG1::KiB_16
+    } else {
+        TCR_EL1::TG0::KiB_4 + TCR_EL1::TG1::KiB_4
+    };
+    TTBR0_EL1.set_baddr(identity as *mut _ as u64);
+    TTBR1_EL1.set_baddr(kernel as *mut _ as u64);
+    KERNEL_TABLE = (kernel as *mut PageTable as usize + HIGHER_HALF_BASE) as *mut _;
 
     TCR_EL1.write(
         TCR_EL1::TBI0::Ignored
         + TCR_EL1::IPS.val(
             ID_AA64MMFR0_EL1.read(ID_AA64MMFR0_EL1::PARange)
         )
-        + TCR_EL1::TG0::KiB_4
+        + granule
         + TCR_EL1::SH0::Inner
         + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
         + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
         + TCR_EL1::EPD0::EnableTTBR0Walks
         + TCR_EL1::T0SZ.val(64-39)
+        + TCR_EL1::SH1::Inner
+        + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::EPD1::EnableTTBR1Walks
+        + TCR_EL1::T1SZ.val(64-39)
     );
     barrier::isb(barrier::SY);
     SCTLR_EL1.modify(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
     barrier::isb(barrier::SY);
-}
\ No newline at end of file
+
+    // move the stack and jump to the higher half
+    let jump_to = higher_half_mmu_cont as usize + HIGHER_HALF_BASE;
+    asm!(
+        "add sp, sp, {offset}",
+        "br {jump_to}",
+        offset = in(reg) HIGHER_HALF_BASE,
+        jump_to = in(reg) jump_to,
+        in("x0") kinit + HIGHER_HALF_BASE,
+        in("x1") dtb_addr,
+        options(noreturn)
+    );
+}
+
+unsafe extern "C" fn higher_half_mmu_cont(kinit: usize, dtb_addr: usize) -> ! {
+    PHYS_TO_VIRT_OFFSET = HIGHER_HALF_BASE;
+    // The console was set up with its physical address, move it to device
+    // memory. Without it there is nowhere to print the error.
+    if let Some(console) = STDOUT.get_mut() {
+        match map_device(console.base_address(), PAGE_SIZE) {
+            Ok(base_address) => console.relocate(base_address),
+            Err(_) => *STDOUT.get_mut() = None,
+        }
+    }
+    printk!("Made it to higher half");
+    // TTBR0 is left for user address spaces
+    RootTable::kernel().activate();
+    super::exception::init();
+    let kinit = core::mem::transmute::<_, extern "C" fn(*mut u8)>(kinit);
+    kinit(dtb_addr as *mut u8);
+    super::cpu::wait_forever()
+}
@@ -1,3 +1,36 @@
 pub mod cpu;
+pub mod drivers;
+pub mod exception;
+pub mod irq;
 pub mod time;
-pub mod mmu;
\ No newline at end of file
+pub mod mmu;
+pub mod user;
+
+/// x0-x30, followed by sp_el0.
+pub type Regs = [usize; 32];
+/// The 32 SIMD&FP registers.
+pub type Fregs = [u128; 32];
+
+pub const fn default_regs() -> Regs {
+    [0; 32]
+}
+pub const fn default_fregs() -> Fregs {
+    [0; 32]
+}
+
+/// SIMD&FP registers, FPCR and FPSR, as saved for a task.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FpState {
+    pub fregs: Fregs,
+    pub fpcr: usize,
+    pub fpsr: usize,
+}
+
+pub const fn default_fp_state() -> FpState {
+    FpState {
+        fregs: default_fregs(),
+        fpcr: 0,
+        fpsr: 0,
+    }
+}
@@ -1,11 +1,31 @@
 use crate::time;
-use core::time::Duration;
-use cortex_a::regs::*;
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use cortex_a::{asm, regs::*};
 
 pub struct ARMv8Timer;
 
 static TIME_COUNTER: ARMv8Timer = ARMv8Timer;
 
+/// Set once the timer interrupt reaches this CPU, so waits can sleep.
+static IRQ_ROUTED: AtomicBool = AtomicBool::new(false);
+
+/// Lets [wait_for] sleep until the timer interrupt instead of spinning.
+/// Called once the timer's PPI has a handler.
+///
+/// [wait_for]: time::TimeCounter::wait_for
+pub fn irq_routed() {
+    IRQ_ROUTED.store(true, Ordering::Release);
+}
+
+/// Handles the timer interrupt. The condition stays asserted until the
+/// timer is reprogrammed, so the interrupt is masked at the timer.
+pub fn handle_irq(_irq: u32) {
+    CNTP_CTL_EL0.modify(CNTP_CTL_EL0::IMASK::SET);
+}
+
 pub fn time_counter() -> &'static impl time::TimeCounter {
     &TIME_COUNTER
 }
@@ -34,14 +54,26 @@ impl time::TimeCounter for ARMv8Timer {
             return;
         }
         CNTP_TVAL_EL0.set(tval); // load the timer value
-        CNTP_CTL_EL0.set(0b11); // enable time counting and mask the interrupt for now.
-        loop {
-            // keep checking the ISTATUS bit in a spin loop.
-            // in the future we will be using interrupts and not masking the interrupt LUL
-            if CNTP_CTL_EL0.matches_all(CNTP_CTL_EL0::ISTATUS::SET) {
-                break;
+        if IRQ_ROUTED.load(Ordering::Acquire) {
+            // Sleep with IRQs masked, so the interrupt wakes us up without
+            // racing with the ISTATUS check. Disabling the timer clears it.
+            let daif = DAIF.get();
+            DAIF.modify(DAIF::I::Masked);
+            CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+            while !CNTP_CTL_EL0.matches_all(CNTP_CTL_EL0::ISTATUS::SET) {
+                asm::wfi();
+            }
+            CNTP_CTL_EL0.modify(CNTP_CTL_EL0::ENABLE::CLEAR);
+            DAIF.set(daif);
+        } else {
+            CNTP_CTL_EL0.set(0b11); // enable time counting and mask the interrupt, since nothing handles it
+            loop {
+                // keep checking the ISTATUS bit in a spin loop.
+                if CNTP_CTL_EL0.matches_all(CNTP_CTL_EL0::ISTATUS::SET) {
+                    break;
+                }
             }
+            CNTP_CTL_EL0.modify(CNTP_CTL_EL0::ENABLE::CLEAR); // disable time counting
         }
-        CNTP_CTL_EL0.modify(CNTP_CTL_EL0::ENABLE::CLEAR); // disable time counting
     }
 }
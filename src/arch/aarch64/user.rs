@@ -0,0 +1,16 @@
+//! Programs run at EL0.
+
+global_asm!(include_str!("user.S"));
+
+/// Gets the code of the built-in init program, which starts at its first byte.
+pub fn init_image() -> &'static [u8] {
+    extern "C" {
+        static __user_init_start: u8;
+        static __user_init_end: u8;
+    }
+    unsafe {
+        let start = &__user_init_start as *const u8;
+        let end = &__user_init_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
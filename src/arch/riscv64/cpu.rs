@@ -1,13 +1,15 @@
 use crate::{
-    drivers::known_good_uart,
+    bsp, drivers,
+    fdt::Fdt,
+    kthread::{KernelStack, TRAP_STACK_SLOT},
     link_var,
     mmu::{PageTable, Permissions, HIGHER_HALF_BASE},
+    sched::SpawnError,
 };
 
 use super::{
-    drivers,
     mmu::{SvTable, __root_page_table, ONEGIG},
-    Regs,
+    FpState, Regs,
 };
 
 #[inline(always)]
@@ -24,6 +26,218 @@ pub fn wait_forever() -> ! {
     }
 }
 
+/// Runs `f` with interrupts disabled on this hart.
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let sstatus: usize;
+    // Clear SIE, returning the old sstatus
+    unsafe { asm!("csrrci {0}, sstatus, 2", out(reg) sstatus) };
+    let result = f();
+    if sstatus & 1 << 1 != 0 {
+        unsafe { asm!("csrsi sstatus, 2") };
+    }
+    result
+}
+
+/// Raises a software interrupt on this hart, so the scheduler runs as soon
+/// as interrupts are enabled.
+pub fn request_reschedule() {
+    // Set SSIP
+    unsafe { asm!("csrsi sip, 2") };
+}
+
+/// Clears a software interrupt raised by [request_reschedule].
+pub fn clear_reschedule() {
+    unsafe { asm!("csrci sip, 2") };
+}
+
+/// sstatus.FS, which tracks the state of the FP registers.
+const SSTATUS_FS: usize = 0b11 << 13;
+/// FP registers are usable and unchanged since FS was set.
+const FS_CLEAN: usize = 0b10 << 13;
+/// FP registers were written since FS was set.
+const FS_DIRTY: usize = 0b11 << 13;
+
+/// Checks if the FP registers were written since [set_fp_enabled].
+pub fn fp_dirty() -> bool {
+    let sstatus: usize;
+    unsafe { asm!("csrr {0}, sstatus", out(reg) sstatus) };
+    sstatus & SSTATUS_FS == FS_DIRTY
+}
+
+/// Checks if an illegal instruction exception with the given sstatus could
+/// have been an FP instruction used while FP was off.
+pub const fn fp_was_disabled(sstatus: usize) -> bool {
+    sstatus & SSTATUS_FS == 0
+}
+
+/// Lets the running code use the FP registers, marking them clean, or
+/// makes any use of them raise an illegal instruction exception.
+pub fn set_fp_enabled(enabled: bool) {
+    unsafe {
+        asm!("csrc sstatus, {0}", in(reg) SSTATUS_FS);
+        if enabled {
+            asm!("csrs sstatus, {0}", in(reg) FS_CLEAN);
+        }
+    }
+}
+
+/// Saves the FP registers into `state`.
+///
+/// # Safety
+/// FP must be enabled.
+pub unsafe fn save_fp(state: &mut FpState) {
+    let fcsr: usize;
+    asm!(
+        "fsd f0, 0({0})",
+        "fsd f1, 8({0})",
+        "fsd f2, 16({0})",
+        "fsd f3, 24({0})",
+        "fsd f4, 32({0})",
+        "fsd f5, 40({0})",
+        "fsd f6, 48({0})",
+        "fsd f7, 56({0})",
+        "fsd f8, 64({0})",
+        "fsd f9, 72({0})",
+        "fsd f10, 80({0})",
+        "fsd f11, 88({0})",
+        "fsd f12, 96({0})",
+        "fsd f13, 104({0})",
+        "fsd f14, 112({0})",
+        "fsd f15, 120({0})",
+        "fsd f16, 128({0})",
+        "fsd f17, 136({0})",
+        "fsd f18, 144({0})",
+        "fsd f19, 152({0})",
+        "fsd f20, 160({0})",
+        "fsd f21, 168({0})",
+        "fsd f22, 176({0})",
+        "fsd f23, 184({0})",
+        "fsd f24, 192({0})",
+        "fsd f25, 200({0})",
+        "fsd f26, 208({0})",
+        "fsd f27, 216({0})",
+        "fsd f28, 224({0})",
+        "fsd f29, 232({0})",
+        "fsd f30, 240({0})",
+        "fsd f31, 248({0})",
+        "frcsr {1}",
+        in(reg) state.fregs.as_mut_ptr(),
+        out(reg) fcsr,
+    );
+    state.fcsr = fcsr;
+}
+
+/// Loads the FP registers from `state`, which marks them dirty.
+///
+/// # Safety
+/// FP must be enabled.
+pub unsafe fn restore_fp(state: &FpState) {
+    asm!(
+        "fld f0, 0({0})",
+        "fld f1, 8({0})",
+        "fld f2, 16({0})",
+        "fld f3, 24({0})",
+        "fld f4, 32({0})",
+        "fld f5, 40({0})",
+        "fld f6, 48({0})",
+        "fld f7, 56({0})",
+        "fld f8, 64({0})",
+        "fld f9, 72({0})",
+        "fld f10, 80({0})",
+        "fld f11, 88({0})",
+        "fld f12, 96({0})",
+        "fld f13, 104({0})",
+        "fld f14, 112({0})",
+        "fld f15, 120({0})",
+        "fld f16, 128({0})",
+        "fld f17, 136({0})",
+        "fld f18, 144({0})",
+        "fld f19, 152({0})",
+        "fld f20, 160({0})",
+        "fld f21, 168({0})",
+        "fld f22, 176({0})",
+        "fld f23, 184({0})",
+        "fld f24, 192({0})",
+        "fld f25, 200({0})",
+        "fld f26, 208({0})",
+        "fld f27, 216({0})",
+        "fld f28, 224({0})",
+        "fld f29, 232({0})",
+        "fld f30, 240({0})",
+        "fld f31, 248({0})",
+        "fscsr {1}",
+        in(reg) state.fregs.as_ptr(),
+        in(reg) state.fcsr,
+    );
+}
+
+/// Gets the registers a kernel task starts with, running on the given stack
+/// with `arg` as its first argument. Its entry point must never return.
+pub fn kernel_regs(stack_top: usize, arg: usize) -> Regs {
+    let mut regs = super::default_regs();
+    let gp: usize;
+    unsafe { asm!("mv {0}, gp", out(reg) gp) };
+    regs[2] = stack_top;
+    regs[3] = gp;
+    // a0
+    regs[10] = arg;
+    regs
+}
+
+/// Gets the registers a user process starts with, running on the given stack.
+pub const fn user_regs(stack_top: usize) -> Regs {
+    let mut regs = super::default_regs();
+    regs[2] = stack_top;
+    regs
+}
+
+/// Makes instructions written to `len` bytes at `addr` visible to
+/// instruction fetches on this hart.
+pub fn sync_icache(_addr: usize, _len: usize) {
+    unsafe { asm!("fence.i") };
+}
+
+/// sstatus.SPP, the mode sret returns to. Clear for U-mode.
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// Makes the trap handler return to U-mode if `user` is set, or S-mode
+/// otherwise. Traps from U-mode run on `kernel_stack`.
+pub fn set_return_mode(user: bool, kernel_stack: usize) {
+    unsafe {
+        if user {
+            asm!("csrc sstatus, {0}", in(reg) SSTATUS_SPP);
+        } else {
+            asm!("csrs sstatus, {0}", in(reg) SSTATUS_SPP);
+        }
+        super::trap::__trap_frame.kernel_stack = kernel_stack as _;
+    }
+}
+
+/// Stack traps from S-mode run on, once [init_trap_stack] has mapped it.
+static mut TRAP_STACK: Option<KernelStack> = None;
+
+/// Moves traps from S-mode off the small boot trap stack, onto a stack of a
+/// few pages below a guard page. Switching tasks, syscalls, IRQ handlers and
+/// panics all run there, and overflowing it panics instead of corrupting
+/// whatever is below.
+///
+/// # Errors
+/// Fails if there is no memory for the stack, in which case traps stay on
+/// the boot trap stack.
+pub fn init_trap_stack() -> Result<(), SpawnError> {
+    let stack = KernelStack::new(TRAP_STACK_SLOT)?;
+    without_interrupts(|| unsafe {
+        super::trap::__trap_frame.trap_stack = stack.top() as _;
+        TRAP_STACK = Some(stack);
+    });
+    Ok(())
+}
+
+/// Checks if a trap came from U-mode, given the sstatus it saw.
+pub const fn trapped_from_user(sstatus: usize) -> bool {
+    sstatus & SSTATUS_SPP == 0
+}
+
 /// # Safety
 /// Only safe to call from asm entry.
 #[no_mangle]
@@ -38,7 +252,14 @@ pub extern "C" fn __early_entry(_: *const i8, dtb_addr: *mut u8) -> ! {
     }
 
     // setup uart for super early printk
-    unsafe { *crate::STDOUT.get_mut() = Some(known_good_uart()) };
+    let console = unsafe { Fdt::from_ptr(dtb_addr) }
+        .ok()
+        .and_then(|fdt| drivers::probe_stdout(&fdt))
+        .unwrap_or_else(bsp::fallback_console);
+    unsafe { *crate::STDOUT.get_mut() = Some(console) };
+
+    // Pick the paging mode while satp writes can't affect us
+    unsafe { super::mmu::detect_paging_mode() };
 
     // Enable interrupts and supervisor mode
 
@@ -50,9 +271,15 @@ pub extern "C" fn __early_entry(_: *const i8, dtb_addr: *mut u8) -> ! {
         asm!("csrw mstatus, {0}", in(reg) mstatus);
 
         asm!("csrw mie, zero");
-        // all exceptions and interrupts go to S-mode
-        asm!("csrw medeleg, {0}", in(reg) u64::MAX);
+        // all exceptions and interrupts go to S-mode, except ecalls from
+        // S-mode and the machine timer, which M-mode turns into S-mode timer
+        // interrupts
+        asm!("csrw medeleg, {0}", in(reg) u64::MAX & !(1 << 9));
         asm!("csrw mideleg, {0}", in(reg) u64::MAX);
+        extern "C" {
+            fn asm_machine_trap_vector();
+        }
+        asm!("csrw mtvec, {0}", in(reg) asm_machine_trap_vector);
 
         // Setup root page table and set SATP
         // link_var!(__kern_start, __kern_end);
@@ -78,9 +305,10 @@ pub extern "C" fn __early_entry(_: *const i8, dtb_addr: *mut u8) -> ! {
 extern "C" fn early_entry2(dtb_addr: *mut u8) -> ! {
     // Setup paging and return to higher half early_entry3
 
-    //             ~~~~~~ STIE = 1 (timer interrupt)
-    //                      ~~~~~~ SSIE = 1 (software interrupt)
-    let sie: u64 = 1 << 5 | 1 << 1;
+    //             ~~~~~~ SEIE = 1 (external interrupt)
+    //                      ~~~~~~ STIE = 1 (timer interrupt)
+    //                               ~~~~~~ SSIE = 1 (software interrupt)
+    let sie: u64 = 1 << 9 | 1 << 5 | 1 << 1;
     unsafe { asm!("csrw sie, {0}", in(reg) sie) };
 
     link_var!(__kern_start);
@@ -110,7 +338,7 @@ extern "C" fn early_entry2(dtb_addr: *mut u8) -> ! {
     }
 
     // unsafe {
-    //     asm!("csrw sepc, {0}", in(reg) (crate::memory::setup_environment as usize) - kern_start + HIGHER_HALF_BASE);
+    //     asm!("csrw sepc, {0}", in(reg) (crate::arch::memory::setup_environment as usize) - kern_start + HIGHER_HALF_BASE);
 
     //     // return
     //     asm!(
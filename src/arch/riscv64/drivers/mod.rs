@@ -1,3 +1,5 @@
 mod clint;
+mod plic;
 
 pub use clint::CLINT;
+pub use plic::{MAX_SOURCES, PLIC};
@@ -0,0 +1,178 @@
+use crate::driver_interfaces::IrqChip;
+
+/// Offset of the per-source priority registers.
+const PRIORITY: usize = 0x0;
+/// Offset of the per-context enable bits.
+const ENABLE: usize = 0x2000;
+/// Stride between the enable bits of two contexts.
+const ENABLE_STRIDE: usize = 0x80;
+/// Offset of the per-context threshold and claim/complete registers.
+const CONTEXT: usize = 0x20_0000;
+/// Stride between the registers of two contexts.
+const CONTEXT_STRIDE: usize = 0x1000;
+
+/// The most interrupt sources a PLIC can have. Source 0 does not exist.
+pub const MAX_SOURCES: usize = 1024;
+
+/// Priority given to sources when they are unmasked.
+const DEFAULT_PRIORITY: u32 = 1;
+
+/// Platform-Level Interrupt Controller (PLIC)
+pub struct PLIC {
+    base_address: usize,
+    sources: usize,
+    /// The context used as an [IrqChip].
+    context: usize,
+}
+
+impl PLIC {
+    /// # Safety
+    /// Only safe if the base address is valid, `sources` is at most [MAX_SOURCES]
+    /// and the context exists.
+    pub const unsafe fn new(base_address: usize, sources: usize, context: usize) -> Self {
+        Self {
+            base_address,
+            sources,
+            context,
+        }
+    }
+
+    pub const fn uninit() -> Self {
+        Self {
+            base_address: 0,
+            sources: 0,
+            context: 0,
+        }
+    }
+
+    /// Checks if this PLIC is initialized.
+    pub const fn is_init(&self) -> bool {
+        self.base_address != 0
+    }
+
+    /// Checks that this PLIC is initialized and has the given source.
+    fn assert_source(&self, source: u32) {
+        assert_ne!(self.base_address, 0, "PLIC is uninit!");
+        assert!(
+            source != 0 && (source as usize) < self.sources,
+            "PLIC has no source {}",
+            source
+        );
+    }
+
+    /// Gets the address of a 32-bit register.
+    fn register(&self, offset: usize) -> *mut u32 {
+        assert_ne!(self.base_address, 0, "PLIC is uninit!");
+        (self.base_address + offset) as *mut u32
+    }
+
+    /// Gets the base address of the PLIC.
+    pub const fn base_address(&self) -> usize {
+        self.base_address
+    }
+
+    /// Gets the number of interrupt sources, including the nonexistent source 0.
+    pub const fn sources(&self) -> usize {
+        self.sources
+    }
+
+    /// Sets the priority of a source. Priority 0 never interrupts.
+    pub fn set_priority(&mut self, source: u32, priority: u32) {
+        self.assert_source(source);
+        let reg = self.register(PRIORITY + source as usize * 4);
+        unsafe { reg.write_volatile(priority) }
+    }
+
+    /// Reads the priority of a source.
+    pub fn priority(&self, source: u32) -> u32 {
+        self.assert_source(source);
+        unsafe {
+            self.register(PRIORITY + source as usize * 4)
+                .read_volatile()
+        }
+    }
+
+    /// Gets the enable register holding the given source's bit, and the bit.
+    fn enable_bit(&self, context: usize, source: u32) -> (*mut u32, u32) {
+        self.assert_source(source);
+        let offset = ENABLE + context * ENABLE_STRIDE + (source as usize / 32) * 4;
+        (self.register(offset), 1 << (source % 32))
+    }
+
+    /// Lets a source interrupt the given context.
+    pub fn enable(&mut self, context: usize, source: u32) {
+        let (reg, bit) = self.enable_bit(context, source);
+        unsafe { reg.write_volatile(reg.read_volatile() | bit) }
+    }
+
+    /// Stops a source from interrupting the given context.
+    pub fn disable(&mut self, context: usize, source: u32) {
+        let (reg, bit) = self.enable_bit(context, source);
+        unsafe { reg.write_volatile(reg.read_volatile() & !bit) }
+    }
+
+    /// Sets the priority a source must exceed to interrupt the given context.
+    pub fn set_threshold(&mut self, context: usize, threshold: u32) {
+        let reg = self.register(CONTEXT + context * CONTEXT_STRIDE);
+        unsafe { reg.write_volatile(threshold) }
+    }
+
+    /// Claims the highest priority pending interrupt of the given context, if any.
+    pub fn claim(&mut self, context: usize) -> Option<u32> {
+        let reg = self.register(CONTEXT + context * CONTEXT_STRIDE + 4);
+        match unsafe { reg.read_volatile() } {
+            0 => None,
+            source => Some(source),
+        }
+    }
+
+    /// Signals that a claimed interrupt has been handled.
+    pub fn complete(&mut self, context: usize, source: u32) {
+        let reg = self.register(CONTEXT + context * CONTEXT_STRIDE + 4);
+        unsafe { reg.write_volatile(source) }
+    }
+
+    /// Disables every source for the given context and lets any priority through.
+    pub fn reset_context(&mut self, context: usize) {
+        for word in 0..(self.sources + 31) / 32 {
+            let reg = self.register(ENABLE + context * ENABLE_STRIDE + word * 4);
+            unsafe { reg.write_volatile(0) }
+        }
+        self.set_threshold(context, 0);
+    }
+}
+
+impl IrqChip for PLIC {
+    fn name(&self) -> &'static str {
+        "PLIC"
+    }
+
+    fn irq_count(&self) -> usize {
+        self.sources
+    }
+
+    fn unmask(&mut self, irq: u32) {
+        if irq == 0 {
+            return;
+        }
+        if self.priority(irq) == 0 {
+            self.set_priority(irq, DEFAULT_PRIORITY);
+        }
+        self.enable(self.context, irq);
+    }
+
+    fn mask(&mut self, irq: u32) {
+        if irq == 0 {
+            return;
+        }
+        self.disable(self.context, irq);
+    }
+
+    fn claim(&mut self) -> Option<u32> {
+        self.claim(self.context)
+    }
+
+    fn complete(&mut self, irq: u32) {
+        self.complete(self.context, irq);
+    }
+}
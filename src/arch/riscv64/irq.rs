@@ -0,0 +1,105 @@
+//! Discovery of the PLIC, which delivers external interrupts to [crate::irq].
+
+use alloc::boxed::Box;
+
+use crate::{
+    fdt::{Fdt, FdtNode},
+    mmu::{AddressSpace, Attributes, Permissions, RootTable},
+    printk,
+};
+
+use super::drivers::{MAX_SOURCES, PLIC};
+
+/// `compatible` strings of the PLIC.
+const PLIC_COMPATIBLE: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+
+/// Supervisor external interrupt cause, as used in `interrupts-extended`.
+const SUPERVISOR_EXTERNAL: u32 = 9;
+
+/// Finds the PLIC context that delivers supervisor external interrupts to the
+/// given hart. Every context is an entry of `interrupts-extended`, pointing
+/// at the interrupt controller of a hart.
+fn find_context(fdt: &Fdt, plic: &FdtNode, hart: u32) -> Option<usize> {
+    let mut cells = plic.property("interrupts-extended")?.cells();
+    let mut context = 0;
+    while let Some(phandle) = cells.next() {
+        let intc = fdt.find_phandle(phandle)?;
+        let cause = cells.next()?;
+        // Skip any cells after the cause
+        for _ in 1..intc.interrupt_cells().unwrap_or(1) {
+            cells.next()?;
+        }
+        let cpu_hart = intc
+            .parent()
+            .and_then(|cpu| cpu.property("reg"))
+            .and_then(|reg| reg.as_u32());
+        if cause == SUPERVISOR_EXTERNAL && cpu_hart == Some(hart) {
+            return Some(context);
+        }
+        context += 1;
+    }
+    None
+}
+
+/// Finds the PLIC in the device tree and installs it as the IRQ controller
+/// of the boot hart.
+///
+/// # Safety
+/// Must only be called once, before interrupts are enabled.
+pub unsafe fn init(fdt: &Fdt) {
+    let node = match fdt.find_compatible(PLIC_COMPATIBLE) {
+        Some(node) => node,
+        None => {
+            printk!("No PLIC found, external interrupts are disabled");
+            return;
+        }
+    };
+    let reg = match node.reg().and_then(|mut reg| reg.next()) {
+        Some(reg) => reg,
+        None => return,
+    };
+    let base = match node.translate_address(reg.address) {
+        Some(base) => base,
+        None => return,
+    };
+    // riscv,ndev doesn't count the nonexistent source 0
+    let sources = node
+        .property("riscv,ndev")
+        .and_then(|p| p.as_u32())
+        .map_or(MAX_SOURCES, |ndev| (ndev as usize + 1).min(MAX_SOURCES));
+    let context = match find_context(fdt, &node, fdt.boot_cpuid()) {
+        Some(context) => context,
+        None => {
+            printk!("PLIC has no context for hart {}", fdt.boot_cpuid());
+            return;
+        }
+    };
+
+    // It may share a large page with the console
+    let mut kernel = RootTable::kernel();
+    if kernel.translate(base).is_none() {
+        if let Err(e) = kernel.map(
+            base,
+            base,
+            reg.size,
+            Permissions::RW,
+            Attributes::Global | Attributes::Device,
+        ) {
+            printk!("Failed to map the PLIC: {:?}", e);
+            return;
+        }
+    }
+
+    let mut plic = PLIC::new(base, sources, context);
+    for source in 1..sources as u32 {
+        plic.set_priority(source, 0);
+    }
+    plic.reset_context(context);
+    printk!(
+        "PLIC at 0x{:x} with {} sources, using context {}",
+        base,
+        sources - 1,
+        context
+    );
+    crate::irq::set_chip(Box::new(plic));
+}
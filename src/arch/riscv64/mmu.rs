@@ -1,11 +1,15 @@
 #![allow(dead_code)]
 
+use core::ops::Range;
+
 use modular_bitfield::prelude::*;
 
 use crate::{
+    kmalloc::with_page_allocator,
     link_var,
-    mmu::{PageTable, Permissions, HIGHER_HALF_BASE},
-    physical_page_allocator::ALLOCATOR,
+    memory::MemoryMap,
+    mmu::{AddressSpace, Attributes, MapError, PageTable, Permissions},
+    oom,
     print, printk, STDOUT,
 };
 
@@ -13,25 +17,149 @@ use crate::{
 // 0x100
 pub const PAGE_SIZE: usize = 4096;
 
+pub const TWOMEG: usize = 0x200000;
+
 pub const ONEGIG: usize = 0x40000000;
 
+/// Where the kernel's gigapage is mapped once paging is on.
+pub const HIGHER_HALF_BASE: usize = 0xC0000000;
+
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PagingMode {
     Sv39 = 8,
+    Sv48 = 9,
+    Sv57 = 10,
+}
+
+impl PagingMode {
+    /// Gets the number of page table levels.
+    pub const fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+
+    /// Gets the number of bits in a virtual address.
+    pub const fn va_bits(self) -> usize {
+        12 + 9 * self.levels()
+    }
+}
+
+/// Paging mode the kernel uses, set by [detect_paging_mode].
+static mut PAGING_MODE: PagingMode = PagingMode::Sv39;
+
+/// Gets the paging mode the kernel uses.
+pub fn paging_mode() -> PagingMode {
+    unsafe { PAGING_MODE }
+}
+
+/// Finds the widest paging mode the hart supports and uses it from now on.
+/// Writing a mode the hart doesn't support to satp leaves satp unchanged,
+/// so each mode is written and read back.
+///
+/// # Safety
+/// Only safe to call from M-mode, where satp doesn't affect translation.
+pub unsafe fn detect_paging_mode() -> PagingMode {
+    for &mode in &[PagingMode::Sv57, PagingMode::Sv48] {
+        let satp_value = (mode as usize) << 60;
+        let read_back: usize;
+        asm!(
+            "csrw satp, {0}",
+            "csrr {1}, satp",
+            "csrw satp, zero",
+            in(reg) satp_value,
+            out(reg) read_back,
+        );
+        if read_back >> 60 == mode as usize {
+            PAGING_MODE = mode;
+            return mode;
+        }
+    }
+    PAGING_MODE = PagingMode::Sv39;
+    PagingMode::Sv39
 }
 
 pub trait Sv {
     type PTE: SvPTE;
     type Table: SvTable<PTE = Self::PTE, Sv = Self>;
     const MODE: PagingMode;
+    const LEVELS: usize = Self::MODE.levels();
 }
 
-pub trait SvPTE: Sized {
+pub trait SvPTE: Sized + Copy {
     fn from_physical_addr(addr: usize) -> Self;
     fn physical_addr(&self) -> usize;
     fn valid(&self) -> bool;
     fn global(&self) -> bool;
+    fn user(&self) -> bool;
     fn permissions(&self) -> XWRPermissions;
+    fn set_valid(&mut self, valid: bool);
+
+    /// Makes a leaf entry.
+    fn leaf(addr: usize, permissions: XWRPermissions, attributes: Attributes) -> Self;
+
+    /// Makes an entry pointing to the next level table.
+    fn table(addr: usize) -> Self;
+
+    /// Makes a copy of this entry pointing somewhere else.
+    fn relocated(&self, addr: usize) -> Self;
+
+    /// Checks if this entry points to the next level table.
+    fn is_table(&self) -> bool {
+        self.valid() && self.permissions() == XWRPermissions::Pointer
+    }
+
+    /// Checks if this entry maps a page.
+    fn is_leaf(&self) -> bool {
+        self.valid() && self.permissions() != XWRPermissions::Pointer
+    }
+}
+
+/// Gets the index into the table at the given level for a virtual address.
+/// Level 0 holds the 4KiB leaves.
+const fn vpn(virt_addr: usize, level: usize) -> usize {
+    (virt_addr >> (12 + 9 * level)) & ((1 << 9) - 1)
+}
+
+/// Gets the size of the memory mapped by a leaf at the given level.
+const fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+/// Gets the level of the root table.
+fn root_level<T: SvTable>() -> usize {
+    <T::Sv as Sv>::LEVELS - 1
+}
+
+/// Number of tables in [EARLY_TABLES]. Mapping the kernel section by section
+/// takes a table per level above the megapages, plus one for each 2MiB
+/// block shared by two sections. The direct map is made before the page
+/// allocator too, and takes a few more for RAM that isn't gigapage aligned.
+const EARLY_TABLE_COUNT: usize = 16;
+
+/// Tables for the boot mappings, which are made before the page allocator is up.
+/// Only Sv48 and Sv57 need them, to reach the gigapage level.
+#[link_section = ".data.rpt"]
+static mut EARLY_TABLES: [Sv39Table; EARLY_TABLE_COUNT] = [Sv39Table::new(); EARLY_TABLE_COUNT];
+static mut EARLY_TABLES_USED: usize = 0;
+
+/// Allocates a zeroed table, running the OOM handler if we are out of pages.
+fn alloc_table() -> Result<usize, MapError> {
+    unsafe {
+        if !with_page_allocator(|pages| pages.is_initialized()) {
+            let table = EARLY_TABLES
+                .get_mut(EARLY_TABLES_USED)
+                .ok_or(MapError::OutOfMemory)?;
+            EARLY_TABLES_USED += 1;
+            return Ok(table as *mut _ as usize);
+        }
+        oom::retry(PAGE_SIZE, || with_page_allocator(|pages| pages.try_zallocate(PAGE_SIZE)))
+            .map(|addr| addr as usize)
+            .ok_or(MapError::OutOfMemory)
+    }
 }
 
 pub trait SvTable: PageTable {
@@ -47,26 +175,330 @@ pub trait SvTable: PageTable {
         &mut *(ptr as *mut Self)
     }
 
+    fn entries(&self) -> &[Self::PTE];
+
+    fn entries_mut(&mut self) -> &mut [Self::PTE];
+
+    /// Gets the table the given entry points to.
+    fn next_table<'a>(entry: &Self::PTE) -> &'a mut Self {
+        unsafe { Self::cast_page_table(phys_to_virt(entry.physical_addr()) as _) }
+    }
+
+    /// Gets the entry for a virtual address at the given level,
+    /// if the tables above it exist.
+    fn entry_mut(&mut self, virt_addr: usize, level: usize) -> Option<&mut Self::PTE> {
+        let mut table = self;
+        for l in (level + 1..=root_level::<Self>()).rev() {
+            let entry = &table.entries()[vpn(virt_addr, l)];
+            if !entry.is_table() {
+                return None;
+            }
+            table = Self::next_table(entry);
+        }
+        Some(&mut table.entries_mut()[vpn(virt_addr, level)])
+    }
+
+    /// Gets the entry for a virtual address at the given level,
+    /// creating the tables above it if needed.
+    ///
+    /// # Errors
+    /// Fails if a larger page is mapped over the address, or if there is no
+    /// memory for a table.
+    fn entry_alloc(&mut self, virt_addr: usize, level: usize) -> Result<&mut Self::PTE, MapError> {
+        let mut table = self;
+        for l in (level + 1..=root_level::<Self>()).rev() {
+            let entry = &mut table.entries_mut()[vpn(virt_addr, l)];
+            if entry.is_leaf() {
+                return Err(MapError::AlreadyMapped);
+            }
+            if !entry.valid() {
+                *entry = Self::PTE::table(virt_to_phys(alloc_table()?));
+            }
+            table = Self::next_table(entry);
+        }
+        Ok(&mut table.entries_mut()[vpn(virt_addr, level)])
+    }
+
+    /// Maps a page at the given level, 0 being 4KiB pages.
+    /// Both addresses are rounded down to the page size of the level.
+    /// Device memory is left to the PMAs, so [Attributes::Device] has no effect.
+    ///
+    /// # Errors
+    /// Fails if the address is already mapped, or if there is no memory for a table.
+    fn try_map(
+        &mut self,
+        virt_addr: usize,
+        phys_addr: usize,
+        level: usize,
+        permissions: XWRPermissions,
+        attributes: Attributes,
+    ) -> Result<(), MapError> {
+        let entry = self.entry_alloc(virt_addr, level)?;
+        // check that the entry is not valid already
+        if entry.valid() {
+            return Err(MapError::AlreadyMapped);
+        }
+        *entry = Self::PTE::leaf(
+            phys_addr & !(level_size(level) - 1),
+            permissions,
+            attributes,
+        );
+        Ok(())
+    }
+
+    /// Unmaps a page at the given level, if one is mapped.
+    fn unmap(&mut self, virt_addr: usize, level: usize) {
+        if let Some(entry) = self.entry_mut(virt_addr, level) {
+            if entry.is_leaf() {
+                entry.set_valid(false);
+            }
+        }
+    }
+
     /// Maps a 1GiB gigapage by rounding the given address.
-    fn map_gigapage(&mut self, virt_addr: usize, phys_addr: usize, permissions: XWRPermissions);
+    fn map_gigapage(&mut self, virt_addr: usize, phys_addr: usize, permissions: XWRPermissions) {
+        if let Err(e) = self.try_map(virt_addr, phys_addr, 2, permissions, Attributes::Global) {
+            panic!("Failed to map gigapage {:x}: {:?}", virt_addr, e);
+        }
+    }
 
     /// Maps a 4KiB page by rounding the given address.
-    fn map_page(&mut self, virt_addr: usize, phys_addr: usize, permissions: XWRPermissions);
+    ///
+    /// # Errors
+    /// Fails if the page is already mapped, or if there is no memory for a page table.
+    fn try_map_page(
+        &mut self,
+        virt_addr: usize,
+        phys_addr: usize,
+        permissions: XWRPermissions,
+    ) -> Result<(), MapError> {
+        self.try_map(virt_addr, phys_addr, 0, permissions, Attributes::Global)
+    }
+
+    /// Maps a 4KiB page by rounding the given address.
+    /// Panics if [try_map_page] fails.
+    fn map_page(&mut self, virt_addr: usize, phys_addr: usize, permissions: XWRPermissions) {
+        if let Err(e) = self.try_map_page(virt_addr, phys_addr, permissions) {
+            panic!("Failed to map page {:x}: {:?}", virt_addr, e);
+        }
+    }
 
     /// Unmaps a 4KiB page by rounding the given address.
-    fn unmap_page(&mut self, virt_addr: usize);
+    fn unmap_page(&mut self, virt_addr: usize) {
+        self.unmap(virt_addr, 0);
+    }
 
-    /// Maps a 1GiB gigapage by rounding the given address.
-    fn unmap_gigapage(&mut self, virt_addr: usize);
+    /// Unmaps a 1GiB gigapage by rounding the given address.
+    fn unmap_gigapage(&mut self, virt_addr: usize) {
+        self.unmap(virt_addr, 2);
+    }
+
+    /// Maps a 2MiB megapage by rounding the given address.
+    fn map_megapage(&mut self, virt_addr: usize, phys_addr: usize, permissions: XWRPermissions) {
+        if let Err(e) = self.try_map(virt_addr, phys_addr, 1, permissions, Attributes::Global) {
+            panic!("Failed to map megapage {:x}: {:?}", virt_addr, e);
+        }
+    }
+
+    /// Unmaps a 2MiB megapage by rounding the given address.
+    fn unmap_megapage(&mut self, virt_addr: usize) {
+        self.unmap(virt_addr, 1);
+    }
+
+    /// Finds the level of the leaf mapping a virtual address, if it is mapped.
+    fn leaf_level(&self, virt_addr: usize) -> Option<usize> {
+        let mut table = self;
+        for level in (0..=root_level::<Self>()).rev() {
+            let entry = &table.entries()[vpn(virt_addr, level)];
+            if entry.is_leaf() {
+                return Some(level);
+            }
+            if !entry.is_table() || level == 0 {
+                return None;
+            }
+            table = &*Self::next_table(entry);
+        }
+        None
+    }
+
+    /// Looks up a virtual address, returning None if it isn't mapped.
+    fn translate(&self, virt_addr: usize) -> Option<usize> {
+        let mut table = self;
+        for level in (0..=root_level::<Self>()).rev() {
+            let entry = &table.entries()[vpn(virt_addr, level)];
+            if entry.is_leaf() {
+                return Some(entry.physical_addr() + (virt_addr & (level_size(level) - 1)));
+            }
+            if !entry.is_table() || level == 0 {
+                return None;
+            }
+            table = &*Self::next_table(entry);
+        }
+        None
+    }
+
+    /// Looks up a virtual address.
+    fn virt_to_phys(&self, virt_addr: usize) -> usize {
+        match self.translate(virt_addr) {
+            Some(phys_addr) => phys_addr,
+            None => panic!(
+                "Tried to lookup virtual address {:x}, which is not mapped!",
+                virt_addr
+            ),
+        }
+    }
+
+    /// Copies the entries of this table, which is at the given level, into
+    /// `clone`, cloning the tables below it.
+    ///
+    /// # Errors
+    /// Fails if there is no memory for a table. Whatever was cloned is linked
+    /// into `clone`, so it can be freed with [deep_free].
+    fn clone_tables(&self, clone: &mut Self, level: usize) -> Result<(), MapError> {
+        for (i, entry) in self.entries().iter().enumerate() {
+            if level == 0 || !entry.is_table() {
+                clone.entries_mut()[i] = *entry;
+                continue;
+            }
+            let table_clone = alloc_table()?;
+            // Link it in before filling it, so a failure below frees it too
+            clone.entries_mut()[i] = entry.relocated(virt_to_phys(table_clone));
+            Self::next_table(entry).clone_tables(
+                unsafe { Self::cast_page_table(table_clone as _) },
+                level - 1,
+            )?;
+        }
+        Ok(())
+    }
 
     /// Makes a deep clone of this page table.
-    fn deep_clone(&self, current_pt: &Self, old_base: usize, new_base: usize) -> &mut Self;
+    ///
+    /// # Errors
+    /// Fails if there is no memory for the new tables. Nothing is leaked.
+    fn try_deep_clone(&self) -> Result<&mut Self, MapError> {
+        let root_clone = unsafe { Self::cast_page_table(alloc_table()? as _) };
+        match self.clone_tables(root_clone, root_level::<Self>()) {
+            Ok(()) => Ok(root_clone),
+            Err(e) => {
+                root_clone.deep_free();
+                Err(e)
+            }
+        }
+    }
+
+    /// Makes a deep clone of this page table.
+    /// Panics if [try_deep_clone] fails.
+    fn deep_clone(&self) -> &mut Self {
+        self.try_deep_clone().expect("Failed to clone page table")
+    }
+
+    /// Makes sure this root table links to a table for the given address, so
+    /// root tables copied from it share whatever gets mapped there later.
+    ///
+    /// # Errors
+    /// Fails if a huge page is mapped there, or if there is no memory for the table.
+    fn link_root_entry(&mut self, virt_addr: usize) -> Result<(), MapError> {
+        self.entry_alloc(virt_addr, root_level::<Self>() - 1).map(drop)
+    }
+
+    /// Replaces the tables linked from the lower half of this root table with
+    /// copies, so mappings made there don't show up in the tables it was copied
+    /// from.
+    ///
+    /// # Errors
+    /// Fails if there is no memory for a table. Whatever was copied is linked
+    /// in, so it can be freed with [free_unshared].
+    fn unshare_lower_half(&mut self) -> Result<(), MapError> {
+        for i in 0..Self::ENTRIES / 2 {
+            let entry = self.entries()[i];
+            if !entry.is_table() {
+                continue;
+            }
+            let table_clone = alloc_table()?;
+            self.entries_mut()[i] = entry.relocated(virt_to_phys(table_clone));
+            Self::next_table(&entry).clone_tables(
+                unsafe { Self::cast_page_table(table_clone as _) },
+                root_level::<Self>() - 1,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Frees the tables below this one, which is at the given level.
+    fn free_tables(&mut self, level: usize) {
+        if level == 0 {
+            return;
+        }
+        for entry in self.entries() {
+            if entry.is_table() {
+                let table = Self::next_table(entry);
+                table.free_tables(level - 1);
+                let table = table as *mut Self as *mut u8;
+                with_page_allocator(|pages| pages.deallocate(table, core::mem::size_of::<Self>()));
+            }
+        }
+    }
 
     /// Deep frees this page table.
-    fn deep_free(self: &mut Self);
+    fn deep_free(self: &mut Self) {
+        self.free_tables(root_level::<Self>());
+        // Free ourself
+        let table = self as *mut Self as *mut u8;
+        with_page_allocator(|pages| pages.deallocate(table, core::mem::size_of::<Self>()));
+    }
+
+    /// Frees this root table and the tables below it, except for the ones
+    /// that the root table at `shared` links to as well.
+    fn free_unshared(&mut self, shared: *mut u8) {
+        let shared = unsafe { Self::cast_page_table(shared) };
+        for (entry, shared_entry) in self.entries().iter().zip(shared.entries()) {
+            let linked =
+                shared_entry.is_table() && shared_entry.physical_addr() == entry.physical_addr();
+            if entry.is_table() && !linked {
+                let table = Self::next_table(entry);
+                table.free_tables(root_level::<Self>() - 1);
+                let table = table as *mut Self as *mut u8;
+                with_page_allocator(|pages| pages.deallocate(table, core::mem::size_of::<Self>()));
+            }
+        }
+        let table = self as *mut Self as *mut u8;
+        with_page_allocator(|pages| pages.deallocate(table, core::mem::size_of::<Self>()));
+    }
 
-    /// Maps a range of pages using [map_page]
+    /// Maps a range of memory, using the largest page that fits each chunk.
     /// The begin address is rounded down, and the end address is rounded up.
+    ///
+    /// # Errors
+    /// Fails if part of the range is already mapped, or if there is no memory
+    /// for a page table. Whatever was mapped before the failure stays mapped.
+    fn try_map_range(
+        &mut self,
+        virt_addr_begin: usize,
+        virt_addr_end: usize,
+        phys_addr_begin: usize,
+        permissions: XWRPermissions,
+        attributes: Attributes,
+    ) -> Result<(), MapError> {
+        let mut virt_addr = virt_addr_begin & !(PAGE_SIZE - 1);
+        let mut phys_addr = phys_addr_begin & !(PAGE_SIZE - 1);
+        let virt_addr_end = (virt_addr_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        while virt_addr < virt_addr_end {
+            // Both addresses need the same alignment for a large page
+            let level = (0..=root_level::<Self>())
+                .rev()
+                .find(|&level| {
+                    let size = level_size(level);
+                    (virt_addr | phys_addr) & (size - 1) == 0 && virt_addr_end - virt_addr >= size
+                })
+                .unwrap_or(0);
+            self.try_map(virt_addr, phys_addr, level, permissions, attributes)?;
+            virt_addr += level_size(level);
+            phys_addr += level_size(level);
+        }
+        Ok(())
+    }
+
+    /// Maps a range of global memory using [try_map_range], panicking if it fails.
     fn map_page_range(
         &mut self,
         virt_addr_begin: usize,
@@ -74,49 +506,96 @@ pub trait SvTable: PageTable {
         phys_addr_begin: usize,
         permissions: XWRPermissions,
     ) {
-        let virt_offset = virt_addr_begin / PAGE_SIZE;
-        let phys_offset = phys_addr_begin / PAGE_SIZE;
-        let virt_addr_begin = virt_addr_begin & !(PAGE_SIZE - 1);
-        let virt_addr_end = if virt_addr_end % PAGE_SIZE > 0 {
-            ((virt_addr_end) & !(PAGE_SIZE - 1)) + PAGE_SIZE
-        } else {
-            virt_addr_end
-        };
-        let len = (virt_addr_end - virt_addr_begin) / PAGE_SIZE;
-        printk!("Mapping {} pages", len);
-        for i in 0..len {
-            let virt_addr = (i + virt_offset) * PAGE_SIZE;
-            let phys_addr = (i + phys_offset) * PAGE_SIZE;
-            // printk!("Mapping {:x} -> {:x}", virt_addr, phys_addr);
-            self.map_page(virt_addr, phys_addr, permissions);
+        if let Err(e) = self.try_map_range(
+            virt_addr_begin,
+            virt_addr_end,
+            phys_addr_begin,
+            permissions,
+            Attributes::Global,
+        ) {
+            panic!(
+                "Failed to map {:x}-{:x}: {:?}",
+                virt_addr_begin, virt_addr_end, e
+            );
         }
     }
 
-    /// Unmaps a range of pages using [map_page]
-    /// Same behavior as [map_page_range].
-    fn unmap_page_range(&mut self, virt_addr_begin: usize, virt_addr_end: usize) {
-        let virt_offset = virt_addr_begin / PAGE_SIZE;
-        // let phys_offset = phys_addr_begin / PAGE_SIZE;
-        let virt_addr_begin = virt_addr_begin & !(PAGE_SIZE - 1);
-        let virt_addr_end = if virt_addr_end % PAGE_SIZE > 0 {
-            ((virt_addr_end) & !(PAGE_SIZE - 1)) + PAGE_SIZE
-        } else {
-            virt_addr_end
-        };
-        let len = (virt_addr_end - virt_addr_begin) / PAGE_SIZE;
-        printk!("Unmapping {} pages", len);
-        for i in 0..len {
-            let virt_addr = (i + virt_offset) * PAGE_SIZE;
-            // let phys_addr = (i + phys_offset) * page_size_u64;
-            // printk!("Unmapping {:x}", virt_addr);
-            self.unmap_page(virt_addr);
+    /// Runs `update` on the leaf entry of every page in a range, whatever
+    /// page sizes it is mapped with. Same rounding as [try_map_range].
+    ///
+    /// # Errors
+    /// Fails if the range covers only part of a large page, or if part of it
+    /// isn't mapped and `skip_unmapped` is false.
+    fn try_update_range(
+        &mut self,
+        virt_addr_begin: usize,
+        virt_addr_end: usize,
+        skip_unmapped: bool,
+        mut update: impl FnMut(&mut Self::PTE),
+    ) -> Result<(), MapError> {
+        let mut virt_addr = virt_addr_begin & !(PAGE_SIZE - 1);
+        let virt_addr_end = (virt_addr_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        while virt_addr < virt_addr_end {
+            let level = match self.leaf_level(virt_addr) {
+                Some(level) => level,
+                None if skip_unmapped => {
+                    virt_addr += PAGE_SIZE;
+                    continue;
+                }
+                None => return Err(MapError::NotMapped),
+            };
+            let size = level_size(level);
+            if virt_addr & (size - 1) != 0 || virt_addr_end - virt_addr < size {
+                return Err(MapError::SplitsLargePage);
+            }
+            if let Some(entry) = self.entry_mut(virt_addr, level) {
+                update(entry);
+            }
+            virt_addr += size;
         }
+        Ok(())
     }
 
-    /// Looks up a virtual address.
-    fn virt_to_phys(&self, virt_addr: usize) -> usize;
+    /// Unmaps a range of memory, skipping pages that aren't mapped.
+    ///
+    /// # Errors
+    /// Fails if the range covers only part of a large page.
+    fn try_unmap_range(
+        &mut self,
+        virt_addr_begin: usize,
+        virt_addr_end: usize,
+    ) -> Result<(), MapError> {
+        self.try_update_range(virt_addr_begin, virt_addr_end, true, |entry| {
+            entry.set_valid(false)
+        })
+    }
 
-    fn entries(&self) -> &[Self::PTE];
+    /// Unmaps a range of memory mapped with [map_page_range], panicking if
+    /// [try_unmap_range] fails.
+    fn unmap_page_range(&mut self, virt_addr_begin: usize, virt_addr_end: usize) {
+        if let Err(e) = self.try_unmap_range(virt_addr_begin, virt_addr_end) {
+            panic!(
+                "Failed to unmap {:x}-{:x}: {:?}",
+                virt_addr_begin, virt_addr_end, e
+            );
+        }
+    }
+
+    /// Changes the permissions and attributes of a mapped range of memory.
+    ///
+    /// # Errors
+    /// Fails if part of the range isn't mapped, or if it covers only part of a large page.
+    fn try_protect_range(
+        &mut self,
+        virt_addr_begin: usize,
+        virt_addr_end: usize,
+        permissions: XWRPermissions,
+        attributes: Attributes,
+    ) -> Result<(), MapError> {
+        self.try_update_range(virt_addr_begin, virt_addr_end, false, |entry| {
+            *entry = Self::PTE::leaf(entry.physical_addr(), permissions, attributes)
+        })
+    }
 
     fn print(&self) {
         self.print_indented(0);
@@ -161,7 +640,7 @@ pub trait SvTable: PageTable {
             print!(" -> 0x{:x}\n", ent.physical_addr());
             // If this PTE is a indirection, print out the next level
             if ent.valid() && perms == XWRPermissions::Pointer {
-                let next_level_table = unsafe { &*(ent.physical_addr() as *const Self) };
+                let next_level_table = &*Self::next_table(ent);
                 next_level_table.print_indented(indent + 1);
             }
         }
@@ -169,6 +648,8 @@ pub trait SvTable: PageTable {
 }
 
 pub struct Sv39;
+pub struct Sv48;
+pub struct Sv57;
 
 impl Sv for Sv39 {
     type PTE = Sv39PTE;
@@ -176,6 +657,20 @@ impl Sv for Sv39 {
     const MODE: PagingMode = PagingMode::Sv39;
 }
 
+impl Sv for Sv48 {
+    type PTE = Sv48PTE;
+    type Table = Sv48Table;
+    const MODE: PagingMode = PagingMode::Sv48;
+}
+
+impl Sv for Sv57 {
+    type PTE = Sv57PTE;
+    type Table = Sv57Table;
+    const MODE: PagingMode = PagingMode::Sv57;
+}
+
+/// A page table entry. Sv39, Sv48 and Sv57 all use this layout, they only
+/// differ in how many 9 bit pieces the spec splits the PPN into.
 #[bitfield]
 #[repr(u64)]
 #[derive(Default, Clone, Copy)]
@@ -188,20 +683,20 @@ pub struct Sv39PTE {
     accessed: bool,
     dirty: bool,
     reserved_for_software: B2,
-    ppn0: B9,
-    ppn1: B9,
-    ppn2: B26,
+    ppn: B44,
     reserved: B10,
 }
 
+pub type Sv48PTE = Sv39PTE;
+pub type Sv57PTE = Sv39PTE;
+
 impl SvPTE for Sv39PTE {
     fn from_physical_addr(addr: usize) -> Self {
-        let (ppn2, ppn1, ppn0) = split_phys_addr_sv39(addr);
-        Self::new().with_ppn2(ppn2).with_ppn1(ppn1).with_ppn0(ppn0)
+        Self::new().with_ppn((addr / PAGE_SIZE) as u64)
     }
 
     fn physical_addr(&self) -> usize {
-        unsplit_phys_addr_sv39((self.ppn2(), self.ppn1(), self.ppn0()))
+        self.ppn() as usize * PAGE_SIZE
     }
 
     fn valid(&self) -> bool {
@@ -212,51 +707,35 @@ impl SvPTE for Sv39PTE {
         self.global()
     }
 
+    fn user(&self) -> bool {
+        self.user()
+    }
+
     fn permissions(&self) -> XWRPermissions {
         self.permissions()
     }
-}
 
-/// Splits a Sv39 virtual address into (VPN[2], VPN[1], VPN[0])
-fn split_virt_addr_sv39(addr: usize) -> (u16, u16, u16) {
-    let vpn0 = (addr >> 12) & ((1 << 9) - 1);
-    let vpn1 = (addr >> 21) & ((1 << 9) - 1);
-    let vpn2 = (addr >> 30) & ((1 << 9) - 1);
-    (vpn2 as u16, vpn1 as u16, vpn0 as u16)
-}
-
-/// Splits a physical address into (PPN[2], PPN[1], PPN[0])
-fn split_phys_addr_sv39(addr: usize) -> (u32, u16, u16) {
-    let ppn0 = (addr >> 12) & ((1 << 9) - 1);
-    let ppn1 = (addr >> 21) & ((1 << 9) - 1);
-    let ppn2 = (addr >> 30) & ((1 << 26) - 1);
-    // let ppn2 = (addr >> 12) & ((1 << 26) - 1);
-    // let ppn1 = (addr >> 38) & ((1 << 9) - 1);
-    // let ppn0 = (addr >> 47) & ((1 << 9) - 1);
-    // printk!(
-    //     "Split {:056x} -> {:026x}, {:09x}, {:09x}",
-    //     addr,
-    //     ppn2,
-    //     ppn1,
-    //     ppn0
-    // );
-    (ppn2 as u32, ppn1 as u16, ppn0 as u16)
-}
-
-/// Merges (PPN[2], PPN[1], PPN[0]) into a physical address.
-fn unsplit_phys_addr_sv39(parts: (u32, u16, u16)) -> usize {
-    let ppn2 = parts.0 as usize;
-    let ppn1 = parts.1 as usize;
-    let ppn0 = parts.2 as usize;
-    let res = (ppn0 << 12) | (ppn1 << 21) | (ppn2 << 30);
-    // printk!(
-    //     "Unsplit {:026x}, {:09x}, {:09x} -> {:056x}",
-    //     ppn2,
-    //     ppn1,
-    //     ppn0,
-    //     res
-    // );
-    res
+    fn set_valid(&mut self, valid: bool) {
+        self.set_valid(valid)
+    }
+
+    fn leaf(addr: usize, permissions: XWRPermissions, attributes: Attributes) -> Self {
+        Self::from_physical_addr(addr)
+            .with_valid(true)
+            .with_user(attributes.contains(Attributes::User))
+            .with_global(attributes.contains(Attributes::Global))
+            .with_permissions(permissions)
+    }
+
+    fn table(addr: usize) -> Self {
+        Self::from_physical_addr(addr)
+            .with_valid(true)
+            .with_permissions(XWRPermissions::Pointer)
+    }
+
+    fn relocated(&self, addr: usize) -> Self {
+        self.with_ppn((addr / PAGE_SIZE) as u64)
+    }
 }
 
 /// Divides a constant by the size of a type.
@@ -271,18 +750,42 @@ const fn type_divide<T>(n: usize) -> usize {
     n / t_sz
 }
 
-pub struct Sv39Table {
-    entries: [<Self as SvTable>::PTE; Self::ENTRIES],
-}
+/// Defines the table type of a paging mode.
+macro_rules! sv_table {
+    ($name:ident, $sv:ident, $pte:ident) => {
+        #[repr(C, align(4096))]
+        pub struct $name {
+            entries: [<Self as SvTable>::PTE; Self::ENTRIES],
+        }
 
-impl Sv39Table {
-    const fn new() -> Self {
-        Self {
-            entries: [Sv39PTE::new(); Self::ENTRIES],
+        impl $name {
+            const fn new() -> Self {
+                Self {
+                    entries: [$pte::new(); Self::ENTRIES],
+                }
+            }
         }
-    }
+
+        impl SvTable for $name {
+            type Sv = $sv;
+            type PTE = $pte;
+            const ENTRIES: usize = type_divide::<Self::PTE>(PAGE_SIZE);
+
+            fn entries(&self) -> &[Self::PTE] {
+                &self.entries
+            }
+
+            fn entries_mut(&mut self) -> &mut [Self::PTE] {
+                &mut self.entries
+            }
+        }
+    };
 }
 
+sv_table!(Sv39Table, Sv39, Sv39PTE);
+sv_table!(Sv48Table, Sv48, Sv48PTE);
+sv_table!(Sv57Table, Sv57, Sv57PTE);
+
 impl<T: SvTable<Sv = U>, U: Sv<Table = T>> PageTable for T {
     fn print(&self) {
         SvTable::print(self);
@@ -297,271 +800,6 @@ impl<T: SvTable<Sv = U>, U: Sv<Table = T>> PageTable for T {
     }
 }
 
-impl SvTable for Sv39Table {
-    type Sv = Sv39;
-    type PTE = Sv39PTE;
-    const ENTRIES: usize = type_divide::<Self::PTE>(PAGE_SIZE);
-
-    fn map_gigapage(&mut self, virt_addr: usize, phys_addr: usize, permissions: XWRPermissions) {
-        // let virt_addr = virt_addr & !(onegig - 1);
-        // round the address down
-        let index = virt_addr / ONEGIG;
-        printk!("Mapping root index {}", index);
-        // mask out 1g of phys_addr
-        let phys_addr2 = phys_addr & !(ONEGIG - 1);
-        // set flags to vgrwx for now
-        self.entries[index as usize] = Sv39PTE::from_physical_addr(phys_addr2)
-            .with_valid(true)
-            .with_global(true)
-            .with_permissions(permissions);
-    }
-
-    fn unmap_gigapage(&mut self, virt_addr: usize) {
-        // let virt_addr = virt_addr & !(onegig - 1);
-        // round the address down
-        let index = virt_addr / ONEGIG;
-        printk!("Unmapping root index {}", index);
-        // mask out 1g of phys_addr
-        // let phys_addr2 = phys_addr & !(ONEGIG - 1);
-        // set flags to vgrwx for now
-        self.entries[index as usize].set_valid(false);
-    }
-
-    /// maps a 4k page by rounding the given addr
-    fn map_page(&mut self, virt_addr: usize, phys_addr: usize, permissions: XWRPermissions) {
-        // mask out page_size of phys_addr
-        let phys_addr2 = phys_addr & !(PAGE_SIZE - 1);
-        // split virt addr
-        let (vpn2, vpn1, vpn0) = split_virt_addr_sv39(virt_addr);
-        // printk!(
-        //     "root index = {}, level1 index = {}, level2 index = {}",
-        //     vpn2,
-        //     vpn1,
-        //     vpn0
-        // );
-        let root_entry = &mut self.entries[vpn2 as usize];
-        // if invalid, allocate page
-        // otherwise, use new pointer
-        let level1_table = if root_entry.valid() {
-            unsafe { &mut *(root_entry.physical_addr() as *mut Self) }
-        } else {
-            // allocate page
-            let new_addr = unsafe { &mut ALLOCATOR }
-                .try_zallocate(PAGE_SIZE)
-                .expect("Failed to allocate page!") as usize;
-            // set entry
-            *root_entry = Sv39PTE::from_physical_addr(new_addr)
-                .with_valid(true)
-                .with_permissions(XWRPermissions::Pointer);
-            let table = unsafe { &mut *(new_addr as *mut Self) };
-            table
-        };
-        // same thing for level2
-        let level1_entry = &mut level1_table.entries[vpn1 as usize];
-        let level2_table = if level1_entry.valid() {
-            unsafe { &mut *(level1_entry.physical_addr() as *mut Self) }
-        } else {
-            // allocate page
-            let new_addr = unsafe { &mut ALLOCATOR }
-                .try_zallocate(PAGE_SIZE)
-                .expect("Failed to allocate page!") as usize;
-            // set entry
-            *level1_entry = Sv39PTE::from_physical_addr(new_addr)
-                .with_valid(true)
-                .with_permissions(XWRPermissions::Pointer);
-            let table = unsafe { &mut *(new_addr as *mut Self) };
-            table
-        };
-        let level2_entry = &mut level2_table.entries[vpn0 as usize];
-        // check that the entry is not valid already
-        if level2_entry.valid() {
-            panic!("Trying to overwrite a valid PTE entry");
-        }
-        // set leaf
-        *level2_entry = Sv39PTE::from_physical_addr(phys_addr2)
-            .with_valid(true)
-            .with_global(true)
-            .with_permissions(permissions);
-    }
-
-    /// maps a 4k page by rounding the given addr
-    fn unmap_page(&mut self, virt_addr: usize) {
-        // mask out page_size of phys_addr
-        // let phys_addr2 = phys_addr & !(PAGE_SIZE as u64 - 1);
-        // split virt addr
-        let (vpn2, vpn1, vpn0) = split_virt_addr_sv39(virt_addr);
-        // printk!(
-        //     "root index = {}, level1 index = {}, level2 index = {}",
-        //     vpn2,
-        //     vpn1,
-        //     vpn0
-        // );
-        let root_entry = &mut self.entries[vpn2 as usize];
-        // if invalid, allocate page
-        // otherwise, use new pointer
-        let level1_table = if root_entry.valid() {
-            unsafe { &mut *(root_entry.physical_addr() as *mut Self) }
-        } else {
-            return;
-        };
-        // same thing for level2
-        let level1_entry = &mut level1_table.entries[vpn1 as usize];
-        let level2_table = if level1_entry.valid() {
-            unsafe { &mut *(level1_entry.physical_addr() as *mut Self) }
-        } else {
-            return;
-        };
-        let level2_entry = &mut level2_table.entries[vpn0 as usize];
-        // set leaf
-        // just set invalid
-        level2_entry.set_valid(false);
-    }
-
-    fn virt_to_phys(&self, virt_addr: usize) -> usize {
-        // let virt_addr = virt_addr & ((1 << 39) - 1);
-        // let index = virt_addr / ONEGIG;
-        let (vpn2, vpn1, vpn0) = split_virt_addr_sv39(virt_addr);
-        let root_entry = self.entries[vpn2 as usize];
-        if !root_entry.valid() {
-            panic!(
-                "Tried to lookup virtual address {:039x}, root entry {} is not valid!",
-                virt_addr, vpn2
-            );
-        }
-        if root_entry.permissions() != XWRPermissions::Pointer {
-            // resolve here
-            let phys_gaddr = root_entry.physical_addr();
-            return phys_gaddr
-                + ((vpn1 as usize) << 21)
-                + ((vpn0 as usize) << 12)
-                + (virt_addr % (1 << 9));
-        }
-        let level1_table = unsafe { &*(root_entry.physical_addr() as *const Self) };
-        let level1_entry = level1_table.entries[vpn1 as usize];
-        if !level1_entry.valid() {
-            panic!(
-                "Tried to lookup virtual address {:039x}, level 1 entry {} is not valid!",
-                virt_addr, vpn1
-            );
-        }
-        if level1_entry.permissions() != XWRPermissions::Pointer {
-            let phys_gaddr = level1_entry.physical_addr();
-            return phys_gaddr + ((vpn0 as usize) << 12) + (virt_addr % (1 << 9));
-        }
-        let level2_table = unsafe { &*(level1_entry.physical_addr() as *const Self) };
-        let level2_entry = level2_table.entries[vpn0 as usize];
-        if !level2_entry.valid() || level2_entry.permissions() == XWRPermissions::Pointer {
-            panic!(
-                "Tried to lookup virtual address {:039x}, level 2 entry {} is not valid!",
-                virt_addr, vpn0
-            );
-        }
-        let phys_gaddr = level2_entry.physical_addr();
-        phys_gaddr + (virt_addr % (1 << 9))
-    }
-
-    fn entries(&self) -> &[Self::PTE] {
-        &self.entries
-    }
-
-    fn deep_clone(&self, current_pt: &Self, old_base: usize, new_base: usize) -> &mut Self {
-        let root_clone = unsafe {
-            Self::cast_page_table(
-                ALLOCATOR
-                    .try_zallocate(PAGE_SIZE)
-                    .expect("Failed to allocate page")
-                    .offset((new_base - old_base) as _),
-            )
-        };
-        root_clone.entries = self.entries;
-        for level1_entry in root_clone.entries.iter_mut() {
-            if level1_entry.permissions() == XWRPermissions::Pointer {
-                // Clone this level
-                let level1_clone = unsafe {
-                    Self::cast_page_table(
-                        ALLOCATOR
-                            .try_zallocate(PAGE_SIZE)
-                            .expect("Failed to allocate page")
-                            .offset((new_base - old_base) as _),
-                    )
-                };
-                let old_level1 =
-                    unsafe { Self::cast_page_table(level1_entry.physical_addr() as _) };
-                level1_clone.entries = old_level1.entries;
-
-                for level2_entry in level1_clone.entries.iter_mut() {
-                    if level2_entry.permissions() == XWRPermissions::Pointer {
-                        // clone this level
-                        let level2_clone = unsafe {
-                            Self::cast_page_table(
-                                ALLOCATOR
-                                    .try_zallocate(PAGE_SIZE)
-                                    .expect("Failed to allocate page")
-                                    .offset((new_base - old_base) as _),
-                            )
-                        };
-                        let old_level2 =
-                            unsafe { Self::cast_page_table(level2_entry.physical_addr() as _) };
-                        level2_clone.entries = old_level2.entries;
-
-                        // Level 3 is always leaf, no need to iterate
-
-                        // Set new addr
-                        // Set new addr
-                        *level2_entry = Sv39PTE::from_physical_addr(SvTable::virt_to_phys(
-                            current_pt,
-                            level2_clone as *mut _ as usize,
-                        ))
-                        .with_valid(level2_entry.valid())
-                        .with_global(level2_entry.global())
-                        .with_permissions(level2_entry.permissions());
-                    }
-                }
-
-                // Set new addr
-                *level1_entry = Sv39PTE::from_physical_addr(SvTable::virt_to_phys(
-                    current_pt,
-                    level1_clone as *mut _ as usize,
-                ))
-                .with_valid(level1_entry.valid())
-                .with_global(level1_entry.global())
-                .with_permissions(level1_entry.permissions());
-            }
-        }
-
-        root_clone
-    }
-
-    fn deep_free(self: &mut Self) {
-        for level1_entry in &self.entries {
-            if level1_entry.permissions() == XWRPermissions::Pointer {
-                // Free inner entries first
-                let level1_table =
-                    unsafe { Self::cast_page_table(level1_entry.physical_addr() as _) };
-
-                for level2_entry in &level1_table.entries {
-                    if level2_entry.permissions() == XWRPermissions::Pointer {
-                        // Free this table, level3 is always leaf
-                        unsafe { &mut ALLOCATOR }.deallocate(
-                            level2_entry.physical_addr() as _,
-                            core::mem::size_of::<Self>(),
-                        );
-                    }
-                }
-
-                // Free level1 table
-                unsafe { &mut ALLOCATOR }.deallocate(
-                    level1_entry.physical_addr() as _,
-                    core::mem::size_of::<Self>(),
-                );
-            }
-        }
-
-        // Free ourself
-        unsafe { &mut ALLOCATOR }.deallocate(self as *mut _ as _, core::mem::size_of::<Self>());
-    }
-}
-
 /// Enables S-mode
 ///
 /// # Safety
@@ -593,7 +831,7 @@ pub unsafe fn enable_smode(return_to: usize, trap_vector: usize) {
 /// Only safe to call when the table's address is page-aligned,
 /// and this code's physical page is identity mapped.
 pub unsafe fn enable_paging<PageSystem: Sv + ?Sized>(table: &PageSystem::Table) {
-    let addr = table as *const _ as usize;
+    let addr = virt_to_phys(table as *const _ as usize);
     if addr % PAGE_SIZE != 0 {
         panic!("Table is not page-aligned");
     }
@@ -708,6 +946,290 @@ use super::trap::TrapFrame;
 #[allow(non_upper_case_globals)]
 pub static mut __root_page_table: Sv39Table = Sv39Table::new();
 
+/// Runs `$body` with `$table` bound to the root table at `$ptr`, typed for
+/// the active paging mode. All modes share the same table layout.
+macro_rules! with_table {
+    ($ptr:expr, |$table:ident| $body:expr) => {
+        match paging_mode() {
+            PagingMode::Sv39 => {
+                let $table = Sv39Table::cast_page_table($ptr);
+                $body
+            }
+            PagingMode::Sv48 => {
+                let $table = Sv48Table::cast_page_table($ptr);
+                $body
+            }
+            PagingMode::Sv57 => {
+                let $table = Sv57Table::cast_page_table($ptr);
+                $body
+            }
+        }
+    };
+}
+
+/// Runs `$body` with `$table` bound to [__root_page_table].
+macro_rules! with_root_table {
+    (|$table:ident| $body:expr) => {
+        with_table!(&mut __root_page_table as *mut _ as _, |$table| $body)
+    };
+}
+
+/// Flushes the whole TLB of this hart.
+fn flush_tlb() {
+    unsafe { asm!("sfence.vma") };
+}
+
+/// An address space, made of a root table in the active paging mode.
+pub struct RootTable {
+    table: *mut u8,
+}
+
+impl RootTable {
+    /// Gets the kernel's address space.
+    pub fn kernel() -> Self {
+        Self {
+            table: unsafe { &mut __root_page_table as *mut _ as _ },
+        }
+    }
+
+    /// Links the kernel's root table to the table the kernel stacks are
+    /// mapped in, so every address space made by [RootTable::new] after this
+    /// shares the stacks, even the ones mapped later. The stack window fits
+    /// in a single root entry in every paging mode.
+    ///
+    /// # Errors
+    /// Fails if there is no memory for the table.
+    pub fn share_kernel_stacks() -> Result<(), MapError> {
+        unsafe { with_root_table!(|table| table.link_root_entry(KERNEL_STACK_BASE)) }
+    }
+
+    /// Creates an address space with the same mappings as the kernel's, so the
+    /// kernel stays mapped while it is active. The upper half shares the kernel's
+    /// tables, so it keeps up with changes to them. The lower half gets copies,
+    /// so user memory can be mapped there.
+    ///
+    /// # Errors
+    /// Fails if there is no memory for the tables. Nothing is leaked.
+    pub fn new() -> Result<Self, MapError> {
+        let table = alloc_table()? as *mut u8;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                &__root_page_table as *const _ as *const u8,
+                table,
+                core::mem::size_of::<Sv39Table>(),
+            )
+        };
+        let root = Self { table };
+        match unsafe { with_table!(table, |table| table.unshare_lower_half()) } {
+            Ok(()) => Ok(root),
+            Err(e) => {
+                unsafe { root.free() };
+                Err(e)
+            }
+        }
+    }
+
+    /// Frees an address space made by [RootTable::new], along with the page
+    /// tables it doesn't share with the kernel's. The memory it maps isn't freed.
+    ///
+    /// # Safety
+    /// The address space must not be active, and mustn't be used again.
+    pub unsafe fn free(self) {
+        let kernel = &mut __root_page_table as *mut _ as _;
+        with_table!(self.table, |table| table.free_unshared(kernel))
+    }
+}
+
+impl AddressSpace for RootTable {
+    fn map(
+        &mut self,
+        virt_addr: usize,
+        phys_addr: usize,
+        len: usize,
+        permissions: Permissions,
+        attributes: Attributes,
+    ) -> Result<(), MapError> {
+        unsafe {
+            with_table!(self.table, |table| table.try_map_range(
+                virt_addr,
+                virt_addr + len,
+                phys_addr,
+                permissions.into(),
+                attributes
+            ))
+        }
+    }
+
+    fn unmap(&mut self, virt_addr: usize, len: usize) -> Result<(), MapError> {
+        let result = unsafe {
+            with_table!(self.table, |table| table
+                .try_unmap_range(virt_addr, virt_addr + len))
+        };
+        flush_tlb();
+        result
+    }
+
+    fn protect(
+        &mut self,
+        virt_addr: usize,
+        len: usize,
+        permissions: Permissions,
+        attributes: Attributes,
+    ) -> Result<(), MapError> {
+        let result = unsafe {
+            with_table!(self.table, |table| table.try_protect_range(
+                virt_addr,
+                virt_addr + len,
+                permissions.into(),
+                attributes
+            ))
+        };
+        flush_tlb();
+        result
+    }
+
+    fn translate(&self, virt_addr: usize) -> Option<usize> {
+        unsafe { with_table!(self.table, |table| table.translate(virt_addr)) }
+    }
+
+    unsafe fn activate(&self) {
+        // Unlike enable_paging this doesn't print, since the scheduler calls
+        // it from the trap handler
+        let ppn = virt_to_phys(self.table as usize) / PAGE_SIZE;
+        let satp_value = (paging_mode() as usize) << 60 | ppn;
+        asm!("csrw satp, {0}", in(reg) satp_value);
+        flush_tlb();
+    }
+}
+
+/// Offset from a physical address in the kernel's gigapage to its
+/// higher half address. Zero until we are running in the higher half.
+static mut PHYS_TO_VIRT_OFFSET: usize = 0;
+
+/// Start of the direct map, where physical address `p` is mapped at
+/// `DIRECT_MAP_BASE + p`. This is the bottom of the upper half of the
+/// Sv39 address space, so it is valid in every paging mode.
+pub const DIRECT_MAP_BASE: usize = 0xFFFF_FFC0_0000_0000;
+
+/// Start of the window kernel thread stacks are mapped into. The direct map
+/// stops below it, which leaves it 192GiB of RAM.
+pub const KERNEL_STACK_BASE: usize = 0xFFFF_FFF0_0000_0000;
+
+/// Where user programs are loaded, past the kernel's gigapage and the
+/// identity mapped devices in the first 4GiB.
+pub const USER_BASE: usize = 0x1_0000_0000;
+
+/// Top of the stack of user programs.
+pub const USER_STACK_TOP: usize = 0x2_0000_0000;
+
+/// Physical addresses covered by the direct map. Empty until [init_direct_map].
+static mut DIRECT_MAP: Range<usize> = 0..0;
+
+/// Converts a physical address to an address the kernel can dereference.
+/// RAM goes through the direct map once it is up, anything else
+/// must be inside the kernel's gigapage.
+pub fn phys_to_virt(phys_addr: usize) -> usize {
+    unsafe {
+        if DIRECT_MAP.contains(&phys_addr) {
+            DIRECT_MAP_BASE + phys_addr
+        } else {
+            phys_addr.wrapping_add(PHYS_TO_VIRT_OFFSET)
+        }
+    }
+}
+
+/// Converts a kernel address back to a physical address.
+/// Inverse of [phys_to_virt], and also works for kernel image addresses.
+pub fn virt_to_phys(virt_addr: usize) -> usize {
+    if virt_addr >= DIRECT_MAP_BASE {
+        virt_addr - DIRECT_MAP_BASE
+    } else {
+        virt_addr.wrapping_sub(unsafe { PHYS_TO_VIRT_OFFSET })
+    }
+}
+
+/// Physical addresses that [phys_to_virt] can convert.
+pub fn addressable_ram() -> Range<usize> {
+    unsafe {
+        if !DIRECT_MAP.is_empty() {
+            return DIRECT_MAP.clone();
+        }
+        match PHYS_TO_VIRT_OFFSET {
+            0 => 0..usize::MAX,
+            offset => {
+                let base = HIGHER_HALF_BASE.wrapping_sub(offset);
+                base..base + ONEGIG
+            }
+        }
+    }
+}
+
+/// Maps all of the RAM in the memory map at [DIRECT_MAP_BASE], after which
+/// [phys_to_virt] works for any of it. Tables come from the early pool,
+/// since the page allocator needs the direct map to start.
+///
+/// # Safety
+/// Only safe to call once, from the higher half, before the page allocator is initialized.
+pub unsafe fn init_direct_map(map: &MemoryMap) {
+    let mut start = usize::MAX;
+    let mut end = 0;
+    for ram in map.ram() {
+        // Would run into the kernel stacks, usually because the address space is Sv39
+        if ram.end > KERNEL_STACK_BASE - DIRECT_MAP_BASE {
+            printk!("RAM {:x?} doesn't fit in the direct map", ram);
+            continue;
+        }
+        with_root_table!(|table| table.map_page_range(
+            DIRECT_MAP_BASE + ram.start,
+            DIRECT_MAP_BASE + ram.end,
+            ram.start,
+            Permissions::RW.into()
+        ));
+        start = start.min(ram.start);
+        end = end.max(ram.end);
+    }
+    if start < end {
+        printk!(
+            "Direct map of {:x}-{:x} at {:x}",
+            start,
+            end,
+            DIRECT_MAP_BASE
+        );
+        // The holes between RAM regions are never handed out
+        DIRECT_MAP = start..end;
+    }
+}
+
+/// Maps the kernel's gigapage at [HIGHER_HALF_BASE], giving each section of
+/// the kernel only the permissions it needs. Everything from `.data` on,
+/// including the stack and the memory after the kernel, is read-write.
+fn map_kernel<T: SvTable>(table: &mut T, kern_start: usize) {
+    link_var!(__text_start, __rodata_start, __data_start);
+    let base = kern_start & !(ONEGIG - 1);
+    let (text_start, rodata_start, data_start) = unsafe {
+        (
+            &__text_start as *const _ as usize,
+            &__rodata_start as *const _ as usize,
+            &__data_start as *const _ as usize,
+        )
+    };
+    let sections = [
+        (base, text_start, Permissions::RW),
+        (text_start, rodata_start, Permissions::RX),
+        (rodata_start, data_start, Permissions::Read),
+        (data_start, base + ONEGIG, Permissions::RW),
+    ];
+    for &(start, end, permissions) in &sections {
+        printk!("Mapping {:x}-{:x} as {:?}", start, end, permissions);
+        table.map_page_range(
+            start - base + HIGHER_HALF_BASE,
+            end - base + HIGHER_HALF_BASE,
+            start,
+            permissions.into(),
+        );
+    }
+}
+
 #[inline(never)]
 pub unsafe extern "C" fn init(return_to: usize, ra: usize, a0: usize, a1: usize) -> ! {
     // let ra: usize;
@@ -720,9 +1242,12 @@ pub unsafe extern "C" fn init(return_to: usize, ra: usize, a0: usize, a1: usize)
     // map in kernel
     link_var!(__kern_start, __kern_end);
     let kern_start = &__kern_start as *const _ as usize;
-    // identity map until we disable it later
-    __root_page_table.map_gigapage(kern_start, kern_start, Permissions::RWX.into());
-    __root_page_table.map_gigapage(HIGHER_HALF_BASE as _, kern_start, Permissions::RWX.into());
+    printk!("Using {:?} paging", paging_mode());
+    with_root_table!(|table| {
+        // identity map until we disable it later
+        table.map_gigapage(kern_start, kern_start, Permissions::RWX.into());
+        map_kernel(table, kern_start);
+    });
     // page_table.map_page_range(kern_start, kern_end, kern_start, Permissions::RWX.into());
     // page_table.map_page_range(
     //     HIGHER_HALF_BASE as _,
@@ -735,9 +1260,18 @@ pub unsafe extern "C" fn init(return_to: usize, ra: usize, a0: usize, a1: usize)
     if let Some(ref stdout) = *STDOUT.get_mut() {
         let base = stdout.base_address();
         // map gigapage since no alloc setup yet
-        __root_page_table.map_gigapage(base as _, base as _, Permissions::RW.into());
+        // it may share a gigapage with the kernel, which is already mapped
+        with_root_table!(|table| {
+            let _ = table.try_map(
+                base as _,
+                base as _,
+                2,
+                Permissions::RW.into(),
+                Attributes::Global | Attributes::Device,
+            );
+        });
     }
-    __root_page_table.enable();
+    with_root_table!(|table| table.enable());
     // test if paging worked
     let ptest = *(((&crate::PAGING_TEST as *const _ as usize) - kern_start + HIGHER_HALF_BASE)
         as *const usize);
@@ -801,8 +1335,9 @@ unsafe extern "C" fn higher_half_mmu_cont(
     // link_var!(__kern_start, __kern_end);
     // let kern_start = &__kern_start as *const _ as u64;
     // let kern_end = &__kern_end as *const _ as u64;
+    PHYS_TO_VIRT_OFFSET = HIGHER_HALF_BASE - (old_kern_start & !(ONEGIG - 1));
     printk!("Gonna unmap old kern gigapage");
-    __root_page_table.unmap_gigapage(old_kern_start);
+    with_root_table!(|table| table.unmap_gigapage(old_kern_start));
     // switch to new page table
     // let new_phys_addr = PageTable::virt_to_phys(page_table, &new_page_table as *const _ as usize);
     // printk!("New phys addr = {:x}", new_phys_addr);
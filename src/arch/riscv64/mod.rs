@@ -2,12 +2,17 @@ use crate::util::UnsafeMutex;
 
 global_asm!(include_str!("header.S"));
 global_asm!(include_str!("trap.S"));
+global_asm!(include_str!("mtrap.S"));
+global_asm!(include_str!("user.S"));
 
 pub mod cpu;
 pub mod drivers;
+pub mod irq;
+pub mod memory;
 pub mod mmu;
 pub mod time;
 pub mod trap;
+pub mod user;
 
 pub static INTERRUPT_CONTROLLER: UnsafeMutex<drivers::CLINT> =
     UnsafeMutex::new(drivers::CLINT::uninit());
@@ -21,3 +26,18 @@ pub const fn default_regs() -> Regs {
 pub const fn default_fregs() -> Fregs {
     [0.; 32]
 }
+
+/// Floating point registers and fcsr, as saved for a task.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FpState {
+    pub fregs: Fregs,
+    pub fcsr: usize,
+}
+
+pub const fn default_fp_state() -> FpState {
+    FpState {
+        fregs: default_fregs(),
+        fcsr: 0,
+    }
+}
@@ -1,6 +1,103 @@
 use crate::bsp::{HAS_RDTIME, NANOS_PER_TICK, TICKS_PER_NANO};
-use crate::time;
-use core::time::Duration;
+use crate::{
+    fdt::Fdt,
+    mmu::{AddressSpace, Attributes, Permissions, RootTable},
+    printk, time,
+};
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use super::{drivers::CLINT, INTERRUPT_CONTROLLER};
+
+/// `compatible` strings of the CLINT.
+const CLINT_COMPATIBLE: &[&str] = &["riscv,clint0", "sifive,clint0"];
+
+/// Frequency of mtime, from the device tree.
+static TIMEBASE_FREQUENCY: AtomicUsize = AtomicUsize::new(0);
+
+/// mtime ticks between timer interrupts, or 0 if they are off.
+static TICK_INTERVAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Finds the CLINT in the device tree, so timer interrupts can be scheduled.
+///
+/// # Safety
+/// Must only be called once, before interrupts are enabled.
+pub unsafe fn init_timer(fdt: &Fdt) {
+    let node = match fdt.find_compatible(CLINT_COMPATIBLE) {
+        Some(node) => node,
+        None => {
+            printk!("No CLINT found, timer interrupts are disabled");
+            return;
+        }
+    };
+    let reg = match node.reg().and_then(|mut reg| reg.next()) {
+        Some(reg) => reg,
+        None => return,
+    };
+    let base = match node.translate_address(reg.address) {
+        Some(base) => base,
+        None => return,
+    };
+    let frequency = match fdt
+        .find_node("/cpus")
+        .and_then(|cpus| cpus.property("timebase-frequency"))
+        .and_then(|p| p.as_u32())
+    {
+        Some(frequency) if frequency > 0 => frequency,
+        _ => {
+            printk!("No timebase-frequency, timer interrupts are disabled");
+            return;
+        }
+    };
+
+    let mut kernel = RootTable::kernel();
+    if kernel.translate(base).is_none() {
+        if let Err(e) = kernel.map(
+            base,
+            base,
+            reg.size,
+            Permissions::RW,
+            Attributes::Global | Attributes::Device,
+        ) {
+            printk!("Failed to map the CLINT: {:?}", e);
+            return;
+        }
+    }
+
+    *INTERRUPT_CONTROLLER.lock() = CLINT::new(base);
+    TIMEBASE_FREQUENCY.store(frequency as usize, Ordering::Relaxed);
+    printk!("CLINT at 0x{:x}, timebase {} Hz", base, frequency);
+}
+
+/// Starts raising a timer interrupt `hz` times a second.
+/// Returns false if there is no timer to do it with.
+pub fn start_ticks(hz: usize) -> bool {
+    let frequency = TIMEBASE_FREQUENCY.load(Ordering::Relaxed);
+    if frequency == 0 || hz == 0 {
+        return false;
+    }
+    TICK_INTERVAL.store((frequency / hz).max(1), Ordering::Relaxed);
+    next_tick();
+    true
+}
+
+/// Schedules the next timer interrupt one tick from now, which also
+/// acknowledges the current one.
+pub fn next_tick() {
+    let interval = TICK_INTERVAL.load(Ordering::Relaxed);
+    if interval == 0 {
+        return;
+    }
+    // Only the trap handler and start_ticks get here, both with
+    // interrupts disabled or before any ticks
+    let clint = unsafe { INTERRUPT_CONTROLLER.get_mut() };
+    clint.set_mtimecmp(clint.mtime() + interval);
+    // Ask M-mode to clear the pending supervisor timer interrupt and
+    // watch for the new deadline
+    unsafe { asm!("ecall") };
+}
 
 // TODO: support fractional nanos/tick or ticks/nano
 pub struct RISCVTimer;
@@ -1,6 +1,6 @@
 use crate::{printk2, STDOUT};
 
-use super::{default_fregs, default_regs, Fregs, Regs, INTERRUPT_CONTROLLER};
+use super::{default_fregs, default_regs, Fregs, Regs};
 
 #[repr(C)]
 #[derive(Clone)]
@@ -10,6 +10,8 @@ pub struct TrapFrame {
     pub satp: usize,
     pub trap_stack: *mut u8,
     pub hartid: usize,
+    /// Stack that traps from U-mode run on, the kernel stack of the process.
+    pub kernel_stack: *mut u8,
 }
 
 impl TrapFrame {
@@ -22,6 +24,7 @@ impl TrapFrame {
             satp: 0,
             trap_stack: sp,
             hartid: 0,
+            kernel_stack: core::ptr::null_mut(),
         }
     }
 }
@@ -34,6 +37,9 @@ pub(super) static mut __trap_frame: TrapFrame = unsafe {
 };
 
 /// Stack storage. 1kb to encourage keeping trap handlers small.
+/// Only used until [init_trap_stack] maps a bigger one.
+///
+/// [init_trap_stack]: super::cpu::init_trap_stack
 #[allow(non_upper_case_globals)]
 static mut __trap_stack: [u8; 1024] = [0; 1024];
 
@@ -50,34 +56,70 @@ extern "C" fn trap_vector(
     let cause_num = cause & 0xfff;
     let mut return_pc = epc;
     let stdout = unsafe { STDOUT.get_mut() };
-    let clint = unsafe { INTERRUPT_CONTROLLER.get_mut() };
     // let orig_uart_locked = UART.is_locked();
     // unsafe { UART.force_unlock() };
 
-    printk2!(
-        stdout,
-        "Interrupt epc=0x{:x} tval=0x{:x} cause={} hart={} status={} frame=0x{:x}",
-        epc,
-        tval,
-        cause,
-        hart,
-        status,
-        frame as *mut _ as usize
-    );
-    // panic_println!("Stuff happened");
-
     if is_async {
         match cause_num {
-            // timer
-            7 => {
-                printk2!(stdout, "Timer interrupt! mtime = {}", clint.mtime());
-                printk2!(stdout, "Rescheduling mtimecmp to 2s from now...");
-                clint.set_mtimecmp(clint.mtime() + 20_000_000);
+            // software, raised to reschedule
+            1 => {
+                crate::cpu::clear_reschedule();
+                return_pc = crate::sched::switch(&mut frame.regs, epc);
+            }
+            // timer, passed on from M-mode
+            5 => {
+                crate::time::next_tick();
+                return_pc = crate::sched::tick(&mut frame.regs, epc);
             }
+            // external
+            9 => crate::irq::dispatch(),
             _ => {}
         }
+    } else if cause_num == 2 && crate::cpu::fp_was_disabled(status) && crate::sched::fp_trap() {
+        // illegal instruction from using FP while it was off, retry it now
+        // that the task's FP registers are loaded
+    } else if crate::cpu::trapped_from_user(status) {
+        match cause_num {
+            // ecall from U-mode
+            8 => {
+                let args = [frame.regs[10], frame.regs[11], frame.regs[12]];
+                match crate::syscall::dispatch(frame.regs[17], args) {
+                    Some(value) => {
+                        frame.regs[10] = value;
+                        return_pc += 4;
+                    }
+                    None => return_pc = crate::sched::kill_current(&mut frame.regs, epc),
+                }
+            }
+            // a fault, which only takes down the process
+            _ => {
+                printk2!(
+                    stdout,
+                    "Killing process {}: exception epc=0x{:x} tval=0x{:x} cause={}",
+                    crate::sched::current(),
+                    epc,
+                    tval,
+                    cause
+                );
+                return_pc = crate::sched::kill_current(&mut frame.regs, epc);
+            }
+        }
     } else {
+        printk2!(
+            stdout,
+            "Exception epc=0x{:x} tval=0x{:x} cause={} hart={} status={} frame=0x{:x}",
+            epc,
+            tval,
+            cause,
+            hart,
+            status,
+            frame as *mut _ as usize
+        );
         match cause_num {
+            // page fault in a thread's guard page
+            5 | 13 | 15 if crate::kthread::is_guard_page(tval) => {
+                panic!("Kernel stack overflow at epc=0x{:x} tval=0x{:x}", epc, tval)
+            }
             // page fault
             5 | 13 | 15 => {
                 let instruction = unsafe { core::slice::from_raw_parts(epc as *const u8, 4) };
@@ -101,7 +143,5 @@ extern "C" fn trap_vector(
     //     core::mem::forget(UART.lock());
     // }
 
-    printk2!(stdout, "Returning from interrupt");
-
     return_pc
 }
@@ -9,3 +9,9 @@ mod riscvirt;
 
 #[cfg(feature = "bsp_riscvirt")]
 pub use riscvirt::*;
+
+#[cfg(feature = "bsp_virt64")]
+mod virt64;
+
+#[cfg(feature = "bsp_virt64")]
+pub use virt64::*;
@@ -1,9 +1,58 @@
-use crate::drivers::pl011::PL011;
+use crate::driver_interfaces::{Console, UartConsole};
+use crate::{arch::mmu::map_device, cpu, drivers::pl011::PL011};
 use cortex_a::regs::*;
-use spin::Mutex;
+use register::{mmio::*, register_bitfields, register_structs};
 
-pub static UART: Mutex<PL011> = Mutex::new(PL011::new());
-//pub static mut UNSAFE_UART: PL011 = PL011::new();
+register_bitfields! {
+    // 32 bit wide registers
+    u32,
+    //GPIO Function Select 1
+    GPFSEL1 [
+        FSEL15 OFFSET(15) NUMBITS(0b11) [
+            Input = 0b000,
+            Output = 0b001,
+            AltFunc0 = 0b100 // PL011 RX
+        ],
+        FSEL14 OFFSET(12) NUMBITS(0b11) [
+            Input = 0b000,
+            Output = 0b001,
+            AltFunc0 = 0b100 // PL011 TX
+        ]
+    ],
+    // GPIO Pin Pull-up/down Enable
+    GPPUD [
+        // GPIO Pin Pull-up/down
+        PUD OFFSET(0) NUMBITS(0b10) [
+            Off = 0b00,
+            PullDownControl = 0b01,
+            PullUpControl = 0b10
+        ]
+    ],
+    // GPIO Pull-up/down Clock Register 0
+    GPPUDCLK0 [
+        // Assert Clock for pin 15
+        PUDCLK15 OFFSET(15) NUMBITS(0b1) [
+            NoEffect = 0b0,
+            AssertClock = 0b1
+        ],
+        // Assert Clock for pin 14
+        PUDCLK14 OFFSET(14) NUMBITS(0b1) [
+            NoEffect = 0b0,
+            AssertClock = 0b1
+        ]
+    ]
+}
+register_structs! {
+    #[allow(non_snake_case)]
+    pub gpio {
+        (0x00 => _reserved0),
+        (0x04 => GPFSEL1: ReadWrite<u32, GPFSEL1::Register>),
+        (0x08 => _reserved1),
+        (0x94 => GPPUD: ReadWrite<u32, GPPUD::Register>),
+        (0x98 => GPPUDCLK0: ReadWrite<u32, GPPUDCLK0::Register>),
+        (0x9c => @END),
+    }
+}
 
 pub fn mmio_base() -> usize {
     match (MIDR_EL1.get() >> 4) & 0xFFF {
@@ -15,5 +64,67 @@ pub fn mmio_base() -> usize {
     }
 }
 
+/// Routes GPIO pins 14 and 15 to the PL011.
+///
+/// # Safety
+/// Only safe to call while nothing else is using the GPIO block.
+pub unsafe fn init_uart_pins() {
+    let gpio_base = match map_device(mmio_base() + 0x20_0000, core::mem::size_of::<gpio>()) {
+        Ok(gpio_base) => gpio_base,
+        // Nothing to print with yet
+        Err(_) => return,
+    };
+    let gpio_regs = &*(gpio_base as *const gpio);
+    // map pins 14 and 15 to PL011 TX and RX respectively
+    gpio_regs
+        .GPFSEL1
+        .modify(GPFSEL1::FSEL15::AltFunc0 + GPFSEL1::FSEL14::AltFunc0);
+    // enable pins 14 and 15 by disabling pull up/down
+    gpio_regs.GPPUD.write(GPPUD::PUD::Off);
+    cpu::spin_for_cycles(150);
+    // Assert Clock for both
+    gpio_regs
+        .GPPUDCLK0
+        .write(GPPUDCLK0::PUDCLK15::AssertClock + GPPUDCLK0::PUDCLK14::AssertClock);
+    cpu::spin_for_cycles(150);
+    // Flush GPIO setup
+    gpio_regs.GPPUDCLK0.set(0);
+}
+
+/// Routes the pins of a console found in the device tree to it. The PL011
+/// is the only UART with its pins on the GPIO header.
+///
+/// # Safety
+/// Only safe to call while nothing else is using the GPIO block.
+pub unsafe fn init_console_pins(console: &UartConsole) {
+    if let UartConsole::PL011(_) = console {
+        init_uart_pins();
+    }
+}
+
+/// Console used when the device tree does not name one.
+pub fn fallback_console() -> UartConsole {
+    let mut uart = unsafe {
+        init_uart_pins();
+        let uart_phys = mmio_base() + 0x20_1000;
+        let base_address = map_device(uart_phys, PAGE_SIZE).unwrap_or(uart_phys);
+        UartConsole::PL011(PL011::new(base_address))
+    };
+    uart.init();
+    uart
+}
+
+/// Physical addresses of the BCM2836 local interrupt controller and the
+/// BCM2835 ARM interrupt controller, for when the device tree has neither.
+pub fn fallback_irq_controllers() -> Option<(usize, usize)> {
+    let local = match mmio_base() {
+        // BCM2711
+        0xFE00_0000 => 0xFF80_0000,
+        _ => 0x4000_0000,
+    };
+    Some((local, mmio_base() + 0xB200))
+}
+
 pub const HEAP_SIZE: usize = 0x100000; // PAGE_SIZE * 1048576; // 1m allocations
-pub const PAGE_SIZE: usize = 0x1000;
+
+pub use crate::arch::mmu::PAGE_SIZE;
@@ -1,3 +1,6 @@
+use crate::driver_interfaces::{Console, UartConsole};
+use crate::drivers::ns16550a::NS16550A;
+
 // Dumped dtb with `-M virt,dumpdtb=virt.out` to check timebase_freq
 // which is 10,000,000
 // Linux also uses HZ which is default to 1000
@@ -11,3 +14,16 @@ pub const HAS_RDTIME: bool = false;
 pub const HEAP_SIZE: usize = 0x100000; // PAGE_SIZE * 1048576; // 1m allocations=
 
 pub use crate::arch::mmu::PAGE_SIZE;
+
+/// Nothing to do, the UARTs of this board aren't behind a pin mux.
+///
+/// # Safety
+/// Always safe, but kept unsafe to match the other boards.
+pub const unsafe fn init_console_pins(_console: &UartConsole) {}
+
+/// Console used when the device tree does not name one.
+pub fn fallback_console() -> UartConsole {
+    let mut uart = UartConsole::NS16550A(unsafe { NS16550A::new(0x1000_0000) });
+    uart.init();
+    uart
+}
@@ -0,0 +1,24 @@
+use crate::driver_interfaces::{Console, UartConsole};
+use crate::drivers::pl011::PL011;
+
+pub const HEAP_SIZE: usize = 0x100000; // PAGE_SIZE * 1048576; // 1m allocations
+
+pub use crate::arch::mmu::PAGE_SIZE;
+
+/// Nothing to do, the UARTs of this board aren't behind a pin mux.
+///
+/// # Safety
+/// Always safe, but kept unsafe to match the other boards.
+pub const unsafe fn init_console_pins(_console: &UartConsole) {}
+
+/// Console used when the device tree does not name one.
+pub fn fallback_console() -> UartConsole {
+    let mut uart = UartConsole::PL011(unsafe { PL011::new(0x0900_0000) });
+    uart.init();
+    uart
+}
+
+/// The virt board's GIC is always in the device tree.
+pub const fn fallback_irq_controllers() -> Option<(usize, usize)> {
+    None
+}
@@ -0,0 +1,271 @@
+//! Kernel command line, taken from `/chosen/bootargs`.
+//!
+//! Options are separated by whitespace and are either bare flags
+//! or `key=value` pairs. Values may be double quoted to include spaces,
+//! and everything after a lone `--` is ignored.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::{drivers, fdt::Fdt, print, printk, process, sched};
+
+/// Every parameter the kernel understands.
+static PARAMS: &[&dyn KernelParam] = &[
+    &print::LOGLEVEL,
+    &drivers::CONSOLE,
+    &process::INIT,
+    &sched::HZ,
+];
+
+/// Errors from setting a parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamError {
+    /// The parameter needs a value but was given as a flag.
+    MissingValue,
+    /// The value could not be parsed.
+    InvalidValue,
+}
+
+/// Types a parameter value can be parsed into.
+pub trait ParamValue: Copy + Send {
+    /// Parses the value, which is None if the parameter was given as a flag.
+    ///
+    /// # Errors
+    /// Returns an error if the value is missing or malformed.
+    fn parse(value: Option<&'static str>) -> Result<Self, ParamError>;
+}
+
+/// Values that fit in a word, so they can be kept in an atomic.
+pub trait ScalarValue: ParamValue {
+    /// Converts the value to a word.
+    fn into_word(self) -> usize;
+
+    /// Converts a word made by [ScalarValue::into_word] back.
+    fn from_word(word: usize) -> Self;
+}
+
+impl ParamValue for bool {
+    fn parse(value: Option<&'static str>) -> Result<Self, ParamError> {
+        match value {
+            None | Some("1") => Ok(true),
+            Some("0") => Ok(false),
+            Some(_) => Err(ParamError::InvalidValue),
+        }
+    }
+}
+
+impl ScalarValue for bool {
+    fn into_word(self) -> usize {
+        self as usize
+    }
+
+    fn from_word(word: usize) -> Self {
+        word != 0
+    }
+}
+
+impl ParamValue for usize {
+    fn parse(value: Option<&'static str>) -> Result<Self, ParamError> {
+        let value = value.ok_or(ParamError::MissingValue)?;
+        let parsed = match value.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => value.parse(),
+        };
+        parsed.map_err(|_| ParamError::InvalidValue)
+    }
+}
+
+impl ScalarValue for usize {
+    fn into_word(self) -> usize {
+        self
+    }
+
+    fn from_word(word: usize) -> Self {
+        word
+    }
+}
+
+impl ParamValue for Option<&'static str> {
+    fn parse(value: Option<&'static str>) -> Result<Self, ParamError> {
+        value.map(Some).ok_or(ParamError::MissingValue)
+    }
+}
+
+/// Object safe interface to a [Param], used by the parser.
+pub trait KernelParam: Sync {
+    /// Gets the name of the parameter.
+    fn name(&self) -> &'static str;
+
+    /// Parses and stores a value.
+    ///
+    /// # Errors
+    /// Returns an error if the value is missing or malformed.
+    fn set(&self, value: Option<&'static str>) -> Result<(), ParamError>;
+}
+
+/// A typed kernel parameter with a default value. The value is kept in
+/// atomics, so it can be read from trap handlers without taking a lock.
+pub struct Param<T> {
+    name: &'static str,
+    default: T,
+    value: AtomicUsize,
+    /// Whether `value` was given on the command line.
+    set: AtomicBool,
+}
+
+impl<T: ScalarValue> Param<T> {
+    pub const fn new(name: &'static str, default: T) -> Self {
+        Self {
+            name,
+            default,
+            value: AtomicUsize::new(0),
+            set: AtomicBool::new(false),
+        }
+    }
+
+    /// Gets the current value.
+    pub fn get(&self) -> T {
+        if self.set.load(Ordering::Acquire) {
+            T::from_word(self.value.load(Ordering::Relaxed))
+        } else {
+            self.default
+        }
+    }
+}
+
+impl<T: ScalarValue + Sync> KernelParam for Param<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn set(&self, value: Option<&'static str>) -> Result<(), ParamError> {
+        self.value
+            .store(T::parse(value)?.into_word(), Ordering::Relaxed);
+        self.set.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// A string kernel parameter, unset by default.
+pub struct StrParam {
+    name: &'static str,
+    value: Mutex<Option<&'static str>>,
+}
+
+impl StrParam {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: Mutex::new(None),
+        }
+    }
+
+    /// Gets the current value.
+    pub fn get(&self) -> Option<&'static str> {
+        *self.value.lock()
+    }
+}
+
+impl KernelParam for StrParam {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn set(&self, value: Option<&'static str>) -> Result<(), ParamError> {
+        *self.value.lock() = <Option<&str>>::parse(value)?;
+        Ok(())
+    }
+}
+
+/// Checks if two parameter names are equal, treating `-` and `_` the same.
+fn name_matches(a: &str, b: &str) -> bool {
+    let normalize = |c| if c == '-' { '_' } else { c };
+    a.len() == b.len() && a.chars().map(normalize).eq(b.chars().map(normalize))
+}
+
+/// Strips one pair of surrounding double quotes.
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+/// Iterator over the `key[=value]` options of a command line.
+pub struct Options<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Options<'a> {
+    pub const fn new(cmdline: &'a str) -> Self {
+        Self { rest: cmdline }
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (&'a str, Option<&'a str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c.is_whitespace() && !quoted
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (option, rest) = rest.split_at(end);
+        self.rest = rest;
+        if option == "--" {
+            self.rest = "";
+            return None;
+        }
+        Some(match option.find('=') {
+            Some(i) => (unquote(&option[..i]), Some(unquote(&option[i + 1..]))),
+            None => (unquote(option), None),
+        })
+    }
+}
+
+/// Reads the command line from `/chosen/bootargs` and applies it.
+pub fn init(fdt: &Fdt<'static>) {
+    let cmdline = match fdt
+        .chosen()
+        .and_then(|chosen| chosen.property("bootargs"))
+        .and_then(|p| p.as_str())
+    {
+        Some(cmdline) => cmdline,
+        None => {
+            printk!("No kernel command line");
+            return;
+        }
+    };
+    printk!("Kernel command line: {}", cmdline);
+    parse(cmdline);
+}
+
+/// Applies every option in the given command line to the registered parameters.
+pub fn parse(cmdline: &'static str) {
+    for (name, value) in Options::new(cmdline) {
+        match PARAMS.iter().find(|p| name_matches(p.name(), name)) {
+            Some(param) => {
+                if let Err(e) = param.set(value) {
+                    printk!(
+                        "Bad value for kernel parameter {}: {:?} ({:?})",
+                        name,
+                        value,
+                        e
+                    );
+                }
+            }
+            None => printk!("Unknown kernel parameter {}, ignoring", name),
+        }
+    }
+}
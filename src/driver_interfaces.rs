@@ -1,6 +1,9 @@
 use core::fmt::{Debug, Write};
 
-use crate::drivers::ns16550a::NS16550A;
+use crate::{
+    drivers::{ns16550a::NS16550A, pl011::PL011, serial::UartBuffers},
+    fdt::{FdtNode, InterruptSpecifier},
+};
 
 /// A UART that can be written to.
 pub trait Uart: Write {
@@ -11,6 +14,21 @@ pub trait Uart: Write {
     fn get(&mut self) -> Option<u8>;
     /// Writes a byte to the output.
     fn put(&mut self, value: u8);
+    /// Writes a byte to the output if it has room, without waiting.
+    /// Returns false if it is full.
+    fn try_put(&mut self, value: u8) -> bool;
+    /// Enables or disables the interrupts for received data.
+    fn set_rx_interrupt(&mut self, enabled: bool);
+    /// Enables or disables the interrupt for room in the output.
+    fn set_tx_interrupt(&mut self, enabled: bool);
+    /// Acknowledges interrupts that don't go away once the receiver is
+    /// drained and the transmitter filled.
+    fn clear_interrupts(&mut self) {}
+    /// Gets the buffers interrupts move data through, if any.
+    fn buffers(&self) -> Option<&'static UartBuffers>;
+    /// Sets the buffers interrupts move data through. Without any, input
+    /// and output go straight to the device.
+    fn set_buffers(&mut self, buffers: Option<&'static UartBuffers>);
 }
 
 /// Some kind of console that can be used for output.
@@ -25,27 +43,100 @@ pub trait Console: Write + Debug {
 #[derive(Debug, Clone)]
 /// Statically sized enum that represents all UARTs.
 pub enum UartConsole {
-    #[cfg(feature = "bsp_riscvirt")]
     NS16550A(NS16550A),
-    #[cfg(feature = "bsp_raspi64")]
     PL011(PL011),
 }
 
 impl UartConsole {
+    /// Moves the UART's registers to `base_address`, after they have been
+    /// mapped there.
+    ///
+    /// # Safety
+    /// The registers must be mapped at the new address.
+    pub unsafe fn relocate(&mut self, base_address: usize) {
+        let buffers = self.uart().buffers();
+        let mut relocated = match self {
+            Self::NS16550A(_) => Self::NS16550A(NS16550A::new(base_address)),
+            Self::PL011(_) => Self::PL011(PL011::new(base_address)),
+        };
+        relocated.uart_mut().set_buffers(buffers);
+        *self = relocated;
+    }
+
+    /// Makes input and output go through `buffers`, moved to and from the
+    /// device by [handle_irq], and enables the receive interrupts.
+    ///
+    /// [handle_irq]: Self::handle_irq
+    pub fn attach_buffers(&mut self, buffers: &'static UartBuffers) {
+        let uart = self.uart_mut();
+        uart.set_buffers(Some(buffers));
+        uart.set_rx_interrupt(true);
+    }
+
+    /// Sends any buffered output and goes back to using the device directly,
+    /// such as when interrupts won't be handled anymore.
+    pub fn detach_buffers(&mut self) {
+        let uart = self.uart_mut();
+        if let Some(buffers) = uart.buffers() {
+            uart.set_rx_interrupt(false);
+            uart.set_tx_interrupt(false);
+            uart.set_buffers(None);
+            buffers.flush(uart);
+        }
+    }
+
+    /// Handles an interrupt from the device.
+    pub fn handle_irq(&mut self) {
+        let uart = self.uart_mut();
+        if let Some(buffers) = uart.buffers() {
+            buffers.handle_irq(uart);
+        }
+    }
+
+    /// Reads the input received so far into `buf`, without waiting.
+    /// Returns the number of bytes read.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let uart = self.uart_mut();
+        match uart.buffers() {
+            Some(buffers) => buffers.read(buf),
+            None => {
+                let mut count = 0;
+                while count < buf.len() {
+                    match uart.get() {
+                        Some(value) => buf[count] = value,
+                        None => break,
+                    }
+                    count += 1;
+                }
+                count
+            }
+        }
+    }
+
+    fn uart(&self) -> &dyn Uart {
+        match self {
+            Self::NS16550A(ref uart) => uart,
+            Self::PL011(ref uart) => uart,
+        }
+    }
+
+    fn uart_mut(&mut self) -> &mut dyn Uart {
+        match self {
+            Self::NS16550A(ref mut uart) => uart,
+            Self::PL011(ref mut uart) => uart,
+        }
+    }
+
     fn console(&self) -> &dyn Console {
         match self {
-            #[cfg(feature = "bsp_riscvirt")]
             Self::NS16550A(ref uart) => uart,
-            #[cfg(feature = "bsp_raspi64")]
             Self::PL011(ref uart) => uart,
         }
     }
 
     fn console_mut(&mut self) -> &mut dyn Console {
         match self {
-            #[cfg(feature = "bsp_riscvirt")]
             Self::NS16550A(ref mut uart) => uart,
-            #[cfg(feature = "bsp_raspi64")]
             Self::PL011(ref mut uart) => uart,
         }
     }
@@ -66,3 +157,25 @@ impl Console for UartConsole {
         self.console().base_address()
     }
 }
+
+/// An interrupt controller that the IRQ subsystem dispatches from.
+pub trait IrqChip: Send {
+    /// Gets the name of the controller, for diagnostics.
+    fn name(&self) -> &'static str;
+    /// Gets the number of IRQ numbers, including any that don't exist.
+    fn irq_count(&self) -> usize;
+    /// Lets the given IRQ interrupt this CPU.
+    fn unmask(&mut self, irq: u32);
+    /// Stops the given IRQ from interrupting this CPU.
+    fn mask(&mut self, irq: u32);
+    /// Claims the next pending IRQ, if any.
+    fn claim(&mut self) -> Option<u32>;
+    /// Signals that a claimed IRQ has been handled.
+    fn complete(&mut self, irq: u32);
+    /// Converts an interrupt specifier from the device tree to an IRQ number.
+    /// `parent` is the controller the specifier is for, which may be one
+    /// chained to this one. Most controllers just use the first cell.
+    fn translate(&self, _parent: &FdtNode, specifier: &InterruptSpecifier) -> Option<u32> {
+        specifier.cell(0)
+    }
+}
@@ -1,19 +1,193 @@
-use crate::driver_interfaces::{Console, UartConsole};
+use crate::{
+    bsp,
+    cmdline::StrParam,
+    driver_interfaces::{Console, UartConsole},
+    fdt::{Fdt, FdtNode},
+    printk,
+    util::UnsafeMutex,
+};
 
-use self::ns16550a::NS16550A;
-
-#[cfg(feature = "bsp_riscvirt")]
 pub mod ns16550a;
-
-#[cfg(feature = "bsp_raspi64")]
 pub mod pl011;
+pub mod serial;
+
+/// Maximum number of devices the registry can hold.
+const MAX_DEVICES: usize = 16;
+
+/// Every driver that can be matched against the device tree.
+static DRIVERS: &[DriverInfo] = &[ns16550a::DRIVER, pl011::DRIVER];
+
+/// Devices found by [probe_all].
+static DEVICES: UnsafeMutex<[Option<Device>; MAX_DEVICES]> = UnsafeMutex::new([None; MAX_DEVICES]);
+
+/// Device tree path or alias of the console, overriding `/chosen/stdout-path`.
+pub static CONSOLE: StrParam = StrParam::new("console");
+
+/// Describes a driver and the device tree nodes it handles.
+pub struct DriverInfo {
+    /// Name of the driver, for diagnostics.
+    pub name: &'static str,
+    /// `compatible` strings this driver can handle.
+    pub compatible: &'static [&'static str],
+    /// Creates an instance of the driver without touching the hardware.
+    ///
+    /// # Safety
+    /// The resources must describe a device this driver handles.
+    pub probe: unsafe fn(&DeviceResources) -> UartConsole,
+}
+
+/// Resources a device was given in the device tree.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceResources {
+    /// Physical base address of the register block.
+    pub base_address: usize,
+    /// Size of the register block.
+    pub size: usize,
+}
+
+impl DeviceResources {
+    /// Reads the resources of the given node.
+    pub fn from_node(node: &FdtNode) -> Option<Self> {
+        let reg = node.reg()?.next()?;
+        Some(Self {
+            base_address: node.translate_address(reg.address)?,
+            size: reg.size,
+        })
+    }
+}
+
+/// A device instance created by a driver.
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// The driver that created this device.
+    pub driver: &'static DriverInfo,
+    /// Resources the device was created with.
+    pub resources: DeviceResources,
+    /// The driver instance.
+    pub instance: UartConsole,
+}
+
+impl core::fmt::Debug for DriverInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "DriverInfo({})", self.name)
+    }
+}
+
+/// Checks the `status` property, which defaults to enabled.
+fn is_enabled(node: &FdtNode) -> bool {
+    node.property("status")
+        .and_then(|p| p.as_str())
+        .map_or(true, |status| status == "okay" || status == "ok")
+}
+
+/// Finds the driver for the given node, if any.
+pub fn find_driver(node: &FdtNode) -> Option<&'static DriverInfo> {
+    // compatible is ordered from most to least specific
+    node.compatible()?
+        .find_map(|c| DRIVERS.iter().find(|d| d.compatible.contains(&c)))
+}
+
+/// Creates a device for the given node, if a driver handles it.
+pub fn probe_node(node: &FdtNode) -> Option<Device> {
+    if !is_enabled(node) {
+        return None;
+    }
+    let driver = find_driver(node)?;
+    let resources = DeviceResources::from_node(node)?;
+    Some(Device {
+        driver,
+        resources,
+        instance: unsafe { (driver.probe)(&resources) },
+    })
+}
+
+/// Error from [probe_all] when there were more devices than [MAX_DEVICES].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegistryFull {
+    /// Number of devices registered.
+    pub registered: usize,
+    /// Number of devices that didn't fit.
+    pub dropped: usize,
+}
+
+/// Probes every node in the device tree and registers the devices found,
+/// except for the console, which is already in use.
+/// Returns the number of devices registered.
+///
+/// # Errors
+/// Fails if some devices didn't fit in the registry. The ones that did stay
+/// registered.
+pub fn probe_all(fdt: &Fdt) -> Result<usize, RegistryFull> {
+    let mut devices = DEVICES.lock();
+    let console = console_node(fdt);
+    let mut count = 0;
+    let mut dropped = 0;
+    for node in fdt.all_nodes() {
+        if console.map_or(false, |console| console.is(&node)) {
+            continue;
+        }
+        if count == MAX_DEVICES {
+            dropped += (is_enabled(&node) && find_driver(&node).is_some()) as usize;
+            continue;
+        }
+        if let Some(device) = probe_node(&node) {
+            printk!(
+                "Probed {} at 0x{:x} with driver {}",
+                node.name(),
+                device.resources.base_address,
+                device.driver.name
+            );
+            devices[count] = Some(device);
+            count += 1;
+        }
+    }
+    match dropped {
+        0 => Ok(count),
+        _ => Err(RegistryFull {
+            registered: count,
+            dropped,
+        }),
+    }
+}
+
+/// Finds a registered device by its base address.
+pub fn device_at(base_address: usize) -> Option<Device> {
+    DEVICES
+        .lock()
+        .iter()
+        .flatten()
+        .find(|d| d.resources.base_address == base_address)
+        .cloned()
+}
+
+/// Gets the console path from `/chosen/stdout-path`.
+fn stdout_path<'a>(fdt: &Fdt<'a>) -> Option<&'a str> {
+    let chosen = fdt.chosen()?;
+    chosen
+        .property("stdout-path")
+        .or_else(|| chosen.property("linux,stdout-path"))?
+        .as_str()
+}
+
+/// Creates and initializes the console named by `/chosen/stdout-path`.
+pub fn probe_stdout(fdt: &Fdt) -> Option<UartConsole> {
+    probe_console(fdt, stdout_path(fdt)?)
+}
+
+/// Finds the node of the console in use, named by the `console` parameter
+/// or `/chosen/stdout-path`.
+pub fn console_node<'a>(fdt: &Fdt<'a>) -> Option<FdtNode<'a>> {
+    let path = CONSOLE.get().or_else(|| stdout_path(fdt))?;
+    fdt.find_node(path.split(':').next()?)
+}
 
-/// Gets a known good UART.
-pub fn known_good_uart() -> UartConsole {
-    #[cfg(target_arch = "riscv64")]
-    let mut uart = UartConsole::NS16550A(unsafe { NS16550A::new(0x1000_0000) });
-    #[cfg(target_arch = "aarch64")]
-    let mut uart = UartConsole::PL011(unsafe { PL011::new(0x4000_0000) });
-    uart.init();
-    uart
+/// Creates and initializes the console at the given device tree path or alias.
+pub fn probe_console(fdt: &Fdt, path: &str) -> Option<UartConsole> {
+    // Options such as the baud rate follow a colon
+    let path = path.split(':').next()?;
+    let mut console = probe_node(&fdt.find_node(path)?)?.instance;
+    // Nothing else uses the pins before the console
+    unsafe { bsp::init_console_pins(&console) };
+    console.init();
+    Some(console)
 }
@@ -1,4 +1,5 @@
-use crate::driver_interfaces::{Console, Uart};
+use super::{serial::UartBuffers, DeviceResources, DriverInfo};
+use crate::driver_interfaces::{Console, Uart, UartConsole};
 use register::{mmio::*, register_bitfields, register_structs};
 
 // Info from the datasheet
@@ -36,6 +37,8 @@ register_bitfields! {
     LSR [
         // Transmitter Empty
         TEMT OFFSET(6) NUMBITS(0b1) [],
+        // Transmitter Holding Register Empty
+        THRE OFFSET(5) NUMBITS(0b1) [],
         // Data Ready
         DR OFFSET(0) NUMBITS(0b1) []
     ]
@@ -54,16 +57,31 @@ register_structs! {
     }
 }
 
+/// Device tree registration for this driver.
+pub const DRIVER: DriverInfo = DriverInfo {
+    name: "ns16550a",
+    compatible: &["ns16550a", "ns16550"],
+    probe,
+};
+
+unsafe fn probe(resources: &DeviceResources) -> UartConsole {
+    UartConsole::NS16550A(NS16550A::new(resources.base_address))
+}
+
 #[derive(Debug, Clone)]
 pub struct NS16550A {
     base_address: usize,
+    buffers: Option<&'static UartBuffers>,
 }
 
 impl NS16550A {
     /// # Safety
     /// The given base address must be valid.
     pub const unsafe fn new(base_address: usize) -> Self {
-        Self { base_address }
+        Self {
+            base_address,
+            buffers: None,
+        }
     }
 
     fn regs(&mut self) -> &UARTBlock {
@@ -73,22 +91,21 @@ impl NS16550A {
 
 impl core::fmt::Write for NS16550A {
     fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
-        for c in s.bytes() {
-            self.put(c);
-        }
+        super::serial::write_bytes(self, s.as_bytes());
         Ok(())
     }
 }
 
 impl Uart for NS16550A {
     fn init(&mut self) {
+        // Receiver interrupts are only wanted once buffers are attached
+        let buffered = self.buffers.is_some();
         let regs = self.regs();
         // Word length = 8 bits
         regs.LCR.write(LCR::WLS::EightBits);
         // Enable FIFO
         regs.FCR.write(FCR::FEN::SET);
-        // Enable receiver buffer interrupts
-        regs.IER.write(IER::ERBFI::SET);
+        regs.IER.write(IER::ERBFI.val(buffered as u8));
         // Set the divisor to 2400 baud or whatever
         // doesn't really do anything in qemu
         // open the DLB to set divisor
@@ -120,6 +137,31 @@ impl Uart for NS16550A {
         // just set the value
         regs.RBR.set(value)
     }
+
+    fn try_put(&mut self, value: u8) -> bool {
+        let regs = self.regs();
+        if regs.LSR.matches_all(LSR::THRE::CLEAR) {
+            return false;
+        }
+        regs.RBR.set(value);
+        true
+    }
+
+    fn set_rx_interrupt(&mut self, enabled: bool) {
+        self.regs().IER.modify(IER::ERBFI.val(enabled as u8));
+    }
+
+    fn set_tx_interrupt(&mut self, enabled: bool) {
+        self.regs().IER.modify(IER::ETBEI.val(enabled as u8));
+    }
+
+    fn buffers(&self) -> Option<&'static UartBuffers> {
+        self.buffers
+    }
+
+    fn set_buffers(&mut self, buffers: Option<&'static UartBuffers>) {
+        self.buffers = buffers;
+    }
 }
 
 impl Console for NS16550A {
@@ -1,44 +1,9 @@
-use crate::driver_interfaces::Uart;
-use crate::{bsp, cpu};
+use super::{serial::UartBuffers, DeviceResources, DriverInfo};
+use crate::driver_interfaces::{Console, Uart, UartConsole};
 use register::{mmio::*, register_bitfields, register_structs};
 register_bitfields! {
     // 32 bit wide registers
     u32,
-    //GPIO Function Select 1
-    GPFSEL1 [
-        FSEL15 OFFSET(15) NUMBITS(0b11) [
-            Input = 0b000,
-            Output = 0b001,
-            AltFunc0 = 0b100 // PL011 RX
-        ],
-        FSEL14 OFFSET(12) NUMBITS(0b11) [
-            Input = 0b000,
-            Output = 0b001,
-            AltFunc0 = 0b100 // PL011 TX
-        ]
-    ],
-    // GPIO Pin Pull-up/down Enable
-    GPPUD [
-        // GPIO Pin Pull-up/down
-        PUD OFFSET(0) NUMBITS(0b10) [
-            Off = 0b00,
-            PullDownControl = 0b01,
-            PullUpControl = 0b10
-        ]
-    ],
-    // GPIO Pull-up/down Clock Register 0
-    GPPUDCLK0 [
-        // Assert Clock for pin 15
-        PUDCLK15 OFFSET(15) NUMBITS(0b1) [
-            NoEffect = 0b0,
-            AssertClock = 0b1
-        ],
-        // Assert Clock for pin 14
-        PUDCLK14 OFFSET(14) NUMBITS(0b1) [
-            NoEffect = 0b0,
-            AssertClock = 0b1
-        ]
-    ],
     // UART Flag Register
     FR [
         // Transmit FIFO Empty
@@ -83,23 +48,27 @@ register_bitfields! {
         // UART ENable
         UARTEN OFFSET(0) NUMBITS(0b1) []
     ],
+    // Interrupt Mask Set/Clear Register
+    IMSC [
+        // Receive Timeout Interrupt Mask
+        RTIM OFFSET(6) NUMBITS(0b1) [],
+        // Transmit Interrupt Mask
+        TXIM OFFSET(5) NUMBITS(0b1) [],
+        // Receive Interrupt Mask
+        RXIM OFFSET(4) NUMBITS(0b1) []
+    ],
     // Interrupt Clear Register
     ICR [
         // 11 bit field
-        ALL OFFSET(0) NUMBITS(0b1011) []
+        ALL OFFSET(0) NUMBITS(0b1011) [],
+        // Receive Timeout Interrupt Clear
+        RTIC OFFSET(6) NUMBITS(0b1) [],
+        // Transmit Interrupt Clear
+        TXIC OFFSET(5) NUMBITS(0b1) [],
+        // Receive Interrupt Clear
+        RXIC OFFSET(4) NUMBITS(0b1) []
     ]
 }
-register_structs! {
-    #[allow(non_snake_case)]
-    pub gpio {
-        (0x00 => _reserved0),
-        (0x04 => GPFSEL1: ReadWrite<u32, GPFSEL1::Register>),
-        (0x08 => _reserved1),
-        (0x94 => GPPUD: ReadWrite<u32, GPPUD::Register>),
-        (0x98 => GPPUDCLK0: ReadWrite<u32, GPPUDCLK0::Register>),
-        (0x9c => @END),
-    }
-}
 register_structs! {
     #[allow(non_snake_case)]
     pub uart {
@@ -112,87 +81,135 @@ register_structs! {
         (0x2c => LCRH: WriteOnly<u32, LCRH::Register>),
         (0x30 => CR: WriteOnly<u32, CR::Register>),
         (0x34 => _reserved2),
+        (0x38 => IMSC: ReadWrite<u32, IMSC::Register>),
+        (0x3c => _reserved3),
         (0x44 => ICR: WriteOnly<u32, ICR::Register>),
         (0x48 => @END),
     }
 }
+/// Device tree registration for this driver.
+pub const DRIVER: DriverInfo = DriverInfo {
+    name: "pl011",
+    compatible: &["arm,pl011"],
+    probe,
+};
+
+unsafe fn probe(resources: &DeviceResources) -> UartConsole {
+    UartConsole::PL011(PL011::new(resources.base_address))
+}
+
+#[derive(Debug, Clone)]
 pub struct PL011 {
-    base: usize,
+    base_address: usize,
+    buffers: Option<&'static UartBuffers>,
 }
 
 impl PL011 {
-    pub const fn new() -> Self {
-        Self { base: 0x2000_0000 }
+    /// # Safety
+    /// The given base address must be valid.
+    pub const unsafe fn new(base_address: usize) -> Self {
+        Self {
+            base_address,
+            buffers: None,
+        }
+    }
+
+    fn regs(&self) -> &uart {
+        unsafe { &*(self.base_address as *const uart) }
     }
 }
 
 impl core::fmt::Write for PL011 {
     fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
-        for c in s.bytes() {
-            self.put(c);
-        }
+        super::serial::write_bytes(self, s.as_bytes());
         Ok(())
     }
 }
 
 impl Uart for PL011 {
-    unsafe fn init(&mut self) {
-        self.base = bsp::mmio_base();
-        let gpio_regs = (self.base + 0x20_0000) as *const gpio;
-        // map pins 14 and 15 to PL011 TX and RX respectively
-        (*gpio_regs)
-            .GPFSEL1
-            .modify(GPFSEL1::FSEL15::AltFunc0 + GPFSEL1::FSEL14::AltFunc0);
-        // enable pins 14 and 15 by disabling pull up/down
-        (*gpio_regs).GPPUD.write(GPPUD::PUD::Off);
-        cpu::spin_for_cycles(150);
-        // Assert Clock for both
-        (*gpio_regs)
-            .GPPUDCLK0
-            .write(GPPUDCLK0::PUDCLK15::AssertClock + GPPUDCLK0::PUDCLK14::AssertClock);
-        cpu::spin_for_cycles(150);
-        // Flush GPIO setup
-        (*gpio_regs).GPPUDCLK0.set(0);
-
-        let uart_regs = (self.base + 0x20_1000) as *const uart;
+    fn init(&mut self) {
+        // receiver interrupts are only wanted once buffers are attached
+        let buffered = self.buffers.is_some() as u32;
+        let regs = self.regs();
         // Turn off UART temporarily with CR (Control Register)
-        (*uart_regs).CR.set(0);
+        regs.CR.set(0);
         // clear all interrupts with ICR (Interrupt Clear Register)
-        (*uart_regs).ICR.write(ICR::ALL::CLEAR);
+        regs.ICR.write(ICR::ALL::CLEAR);
         // set IBRD (Integer Baud Rate Divisor) to 13
         // because (48MHz/16)/230400 = 13.02083, margin of error is acceptable
-        (*uart_regs).IBRD.write(IBRD::IBRD.val(13));
+        regs.IBRD.write(IBRD::IBRD.val(13));
         // set FBRD (Fractional Baud Rate Divisor) to 1
         // because 0.02083*64 = 1.3312, rounded to 1
-        (*uart_regs).FBRD.write(FBRD::FBRD.val(1));
+        regs.FBRD.write(FBRD::FBRD.val(1));
         // set LCRH to 8 bit chars and enable FIFO
-        (*uart_regs)
-            .LCRH
+        regs.LCRH
             .write(LCRH::WLEN::EightBits + LCRH::FEN::FifoEnabled);
+        regs.IMSC
+            .write(IMSC::RXIM.val(buffered) + IMSC::RTIM.val(buffered));
         // set CR to enable UART, TX, and RX
-        (*uart_regs)
-            .CR
-            .write(CR::UARTEN::SET + CR::TXE::SET + CR::RXE::SET);
+        regs.CR.write(CR::UARTEN::SET + CR::TXE::SET + CR::RXE::SET);
     }
+
     fn get(&mut self) -> Option<u8> {
-        let uart_regs = (self.base + 0x20_1000) as *const uart;
+        let regs = self.regs();
         // match on emptiness of RX fifo
-        // safety: accessing register properly
-        unsafe {
-            match (*uart_regs).FR.matches_all(FR::RXFE::SET) {
-                true => None,
-                false => Some((*uart_regs).DR.get() as u8),
-            }
+        match regs.FR.matches_all(FR::RXFE::SET) {
+            true => None,
+            false => Some(regs.DR.get() as u8),
         }
     }
+
     fn put(&mut self, value: u8) {
-        let uart_regs = (self.base + 0x20_1000) as *const uart;
-        // safety: accessing register properly
-        unsafe {
-            while (*uart_regs).FR.matches_all(FR::TXFF::SET) {
-                cpu::nop();
-            }
-            (*uart_regs).DR.set(value as u32);
+        let regs = self.regs();
+        while regs.FR.matches_all(FR::TXFF::SET) {
+            core::hint::spin_loop();
+        }
+        regs.DR.set(value as u32);
+    }
+
+    fn try_put(&mut self, value: u8) -> bool {
+        let regs = self.regs();
+        if regs.FR.matches_all(FR::TXFF::SET) {
+            return false;
         }
+        regs.DR.set(value as u32);
+        true
+    }
+
+    fn set_rx_interrupt(&mut self, enabled: bool) {
+        let enabled = enabled as u32;
+        self.regs()
+            .IMSC
+            .modify(IMSC::RXIM.val(enabled) + IMSC::RTIM.val(enabled));
+    }
+
+    fn set_tx_interrupt(&mut self, enabled: bool) {
+        self.regs().IMSC.modify(IMSC::TXIM.val(enabled as u32));
+    }
+
+    fn clear_interrupts(&mut self) {
+        // The FIFOs have been drained and filled, so whatever is still
+        // pending will be raised again
+        self.regs()
+            .ICR
+            .write(ICR::RXIC::SET + ICR::RTIC::SET + ICR::TXIC::SET);
+    }
+
+    fn buffers(&self) -> Option<&'static UartBuffers> {
+        self.buffers
+    }
+
+    fn set_buffers(&mut self, buffers: Option<&'static UartBuffers>) {
+        self.buffers = buffers;
+    }
+}
+
+impl Console for PL011 {
+    fn init(&mut self) {
+        Uart::init(self)
+    }
+
+    fn base_address(&self) -> usize {
+        self.base_address
     }
 }
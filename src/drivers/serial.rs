@@ -0,0 +1,169 @@
+//! Interrupt driven UART input and output.
+//!
+//! Once the console's interrupt is routed, its input and output go through
+//! [UartBuffers]. The interrupt handler moves received bytes into one ring
+//! buffer and feeds the transmitter from another, so writers only wait when
+//! the output buffer is full and no input is lost between reads.
+
+use crate::{
+    cpu, driver_interfaces::Uart, fdt::Fdt, irq, printk, util::ring_buffer::RingBuffer, STDOUT,
+};
+
+/// Size of each of the ring buffers of a UART.
+const BUFFER_SIZE: usize = 1024;
+
+/// Buffers of the console.
+static CONSOLE_BUFFERS: UartBuffers = UartBuffers::new();
+
+/// Input and output buffers of an interrupt driven UART.
+pub struct UartBuffers {
+    /// Received bytes, filled by the interrupt handler.
+    rx: RingBuffer<BUFFER_SIZE>,
+    /// Bytes to send, drained by the interrupt handler.
+    tx: RingBuffer<BUFFER_SIZE>,
+}
+
+impl core::fmt::Debug for UartBuffers {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "UartBuffers({} received, {} to send)",
+            self.rx.len(),
+            self.tx.len()
+        )
+    }
+}
+
+impl UartBuffers {
+    pub const fn new() -> Self {
+        Self {
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+        }
+    }
+
+    /// Moves bytes from the output buffer to the device until it is full,
+    /// and only asks for an interrupt when there is more to send.
+    fn drain_tx(&self, uart: &mut dyn Uart) {
+        while let Some(value) = self.tx.peek() {
+            if !uart.try_put(value) {
+                break;
+            }
+            self.tx.pop();
+        }
+        uart.set_tx_interrupt(!self.tx.is_empty());
+    }
+
+    /// Moves data between the device and the buffers. Called from the
+    /// device's interrupt handler. Input is dropped if nobody reads it.
+    pub fn handle_irq(&self, uart: &mut dyn Uart) {
+        while let Some(value) = uart.get() {
+            self.rx.push(value);
+        }
+        self.drain_tx(uart);
+        uart.clear_interrupts();
+    }
+
+    /// Queues a byte to be sent, only waiting if the output buffer is full.
+    pub fn write(&self, uart: &mut dyn Uart, value: u8) {
+        // Interrupts are off so the handler doesn't race us for the buffer,
+        // and so trap handlers can print too
+        cpu::without_interrupts(|| {
+            if self.tx.is_empty() && uart.try_put(value) {
+                return;
+            }
+            while !self.tx.push(value) {
+                self.drain_tx(uart);
+            }
+            uart.set_tx_interrupt(true);
+        });
+    }
+
+    /// Sends everything in the output buffer, waiting for the device.
+    pub fn flush(&self, uart: &mut dyn Uart) {
+        cpu::without_interrupts(|| {
+            while let Some(value) = self.tx.pop() {
+                uart.put(value);
+            }
+        });
+    }
+
+    /// Reads the input received so far into `buf`, without waiting.
+    /// Returns the number of bytes read.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        while count < buf.len() {
+            match self.rx.pop() {
+                Some(value) => buf[count] = value,
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
+/// Writes bytes to a UART, through its buffers if it has any.
+pub fn write_bytes(uart: &mut dyn Uart, bytes: &[u8]) {
+    match uart.buffers() {
+        Some(buffers) => bytes.iter().for_each(|&c| buffers.write(uart, c)),
+        None => bytes.iter().for_each(|&c| uart.put(c)),
+    }
+}
+
+/// Reads console input into `buf`, without waiting.
+/// Returns the number of bytes read.
+pub fn try_read_console(buf: &mut [u8]) -> usize {
+    match *STDOUT.lock() {
+        Some(ref mut console) => console.read(buf),
+        None => 0,
+    }
+}
+
+/// Reads console input into `buf`, waiting until there is some.
+/// Returns the number of bytes read, which is only 0 if `buf` is empty.
+pub fn read_console(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    loop {
+        // The lock is only held while reading, so output isn't held up
+        let count = try_read_console(buf);
+        if count > 0 {
+            return count;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Routes the console's interrupt, after which its input and output are
+/// buffered. The console stays polled if it has no usable interrupt.
+pub fn init_console(fdt: &Fdt) {
+    let node = match super::console_node(fdt) {
+        Some(node) => node,
+        None => return,
+    };
+    let irq = match irq::translate(&node) {
+        Some(irq) => irq,
+        None => {
+            printk!("Console {} has no interrupt, polling it", node.name());
+            return;
+        }
+    };
+    match *STDOUT.lock() {
+        Some(ref mut console) => console.attach_buffers(&CONSOLE_BUFFERS),
+        None => return,
+    }
+    let handler = |_: u32| {
+        // The interrupted code may hold the lock
+        if let Some(console) = unsafe { STDOUT.get_mut() } {
+            console.handle_irq();
+        }
+    };
+    if let Err(e) = irq::request(irq, "console", handler) {
+        printk!("Failed to route the console interrupt: {:?}", e);
+        if let Some(ref mut console) = *STDOUT.lock() {
+            console.detach_buffers();
+        }
+    }
+}
@@ -0,0 +1,923 @@
+//! Flattened device tree (DTB) parser.
+//!
+//! Implements enough of the devicetree specification (v0.3, chapter 5)
+//! to walk the structure block, look nodes up by path or compatible
+//! string, and decode the standard properties.
+
+use core::{convert::TryInto, str};
+
+use crate::util::UnsafeMutex;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Size of the v17 header in bytes.
+const HEADER_SIZE: usize = 40;
+
+/// Deepest node nesting we are willing to track.
+const MAX_DEPTH: usize = 16;
+
+/// The device tree handed to us by the bootloader, if any.
+static DEVICE_TREE: UnsafeMutex<Option<Fdt<'static>>> = UnsafeMutex::new(None);
+
+/// Parses the device tree at `ptr` and stores it for [device_tree].
+///
+/// # Errors
+/// Returns an error if the blob fails validation.
+///
+/// # Safety
+/// `ptr` must point to a device tree blob (or garbage, which will be
+/// rejected) that stays mapped and unmodified forever.
+pub unsafe fn init(ptr: *const u8) -> Result<Fdt<'static>, FdtError> {
+    let fdt = Fdt::from_ptr(ptr)?;
+    *DEVICE_TREE.lock() = Some(fdt);
+    Ok(fdt)
+}
+
+/// Gets the device tree registered with [init].
+pub fn device_tree() -> Option<Fdt<'static>> {
+    *DEVICE_TREE.lock()
+}
+
+/// Errors that can occur while validating a device tree blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    /// The blob pointer was null.
+    NullPointer,
+    /// The blob pointer was not 4-byte aligned.
+    Misaligned,
+    /// The magic number did not match.
+    BadMagic(u32),
+    /// The blob is not compatible with version 17.
+    UnsupportedVersion(u32),
+    /// A block or token extends past the end of the blob.
+    Truncated,
+    /// An unknown token was found in the structure block.
+    BadToken {
+        /// Offset of the token in the structure block.
+        offset: usize,
+        /// The token value.
+        token: u32,
+    },
+    /// A node or property name was not valid UTF-8.
+    BadString,
+    /// The node nesting is unbalanced.
+    Unbalanced,
+    /// Nodes are nested deeper than we can track.
+    TooDeep,
+}
+
+/// Header fields we care about.
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    total_size: usize,
+    version: u32,
+    boot_cpuid: u32,
+}
+
+/// A validated device tree blob.
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    header: Header,
+    data: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    reservations: &'a [u8],
+}
+
+/// A single token of the structure block.
+enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Prop(FdtProperty<'a>),
+    End,
+}
+
+/// Reads a big-endian u32 at the given offset.
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Reads a big-endian u64 at the given offset.
+fn be64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Reads a number made of `cells` big-endian u32 cells.
+/// Only the low 64 bits are kept if there are more than two cells.
+fn read_cells(data: &[u8], cells: usize) -> Option<usize> {
+    let mut value: u64 = 0;
+    for i in 0..cells {
+        value = (value << 32) | be32(data, i * 4)? as u64;
+    }
+    Some(value as usize)
+}
+
+/// Reads a NUL-terminated string starting at `offset`.
+fn cstr(data: &[u8], offset: usize) -> Result<&str, FdtError> {
+    let tail = data.get(offset..).ok_or(FdtError::Truncated)?;
+    let len = tail
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(FdtError::Truncated)?;
+    str::from_utf8(&tail[..len]).map_err(|_| FdtError::BadString)
+}
+
+const fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Gets `len` bytes of `data` starting at `offset`.
+fn block(data: &[u8], offset: u32, len: u32) -> Result<&[u8], FdtError> {
+    let start = offset as usize;
+    let end = start.checked_add(len as usize).ok_or(FdtError::Truncated)?;
+    data.get(start..end).ok_or(FdtError::Truncated)
+}
+
+impl<'a> Fdt<'a> {
+    /// Validates the device tree at the given address.
+    ///
+    /// # Errors
+    /// Returns an error if the blob fails validation.
+    ///
+    /// # Safety
+    /// `ptr` must be readable for at least the header and the size the
+    /// header claims, for the whole lifetime `'a`.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError> {
+        if ptr.is_null() {
+            return Err(FdtError::NullPointer);
+        }
+        if ptr as usize % 4 != 0 {
+            return Err(FdtError::Misaligned);
+        }
+        let header = core::slice::from_raw_parts(ptr, HEADER_SIZE);
+        let magic = be32(header, 0).ok_or(FdtError::Truncated)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total_size = be32(header, 4).ok_or(FdtError::Truncated)? as usize;
+        Self::from_slice(core::slice::from_raw_parts(ptr, total_size))
+    }
+
+    /// Validates the device tree contained in `data`.
+    ///
+    /// # Errors
+    /// Returns an error if the blob fails validation.
+    pub fn from_slice(data: &'a [u8]) -> Result<Self, FdtError> {
+        let magic = be32(data, 0).ok_or(FdtError::Truncated)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total_size = be32(data, 4).ok_or(FdtError::Truncated)? as usize;
+        if total_size < HEADER_SIZE || total_size > data.len() {
+            return Err(FdtError::Truncated);
+        }
+        let data = &data[..total_size];
+        // Only read from the validated blob
+        let field = |i: usize| be32(data, i * 4).ok_or(FdtError::Truncated);
+        let version = field(5)?;
+        let last_comp_version = field(6)?;
+        if version < 17 || last_comp_version > 17 {
+            return Err(FdtError::UnsupportedVersion(version));
+        }
+        let reservations_offset = field(4)? as usize;
+        if reservations_offset % 8 != 0 {
+            return Err(FdtError::Misaligned);
+        }
+        let fdt = Self {
+            header: Header {
+                total_size,
+                version,
+                boot_cpuid: field(7)?,
+            },
+            data,
+            structs: block(data, field(2)?, field(9)?)?,
+            strings: block(data, field(3)?, field(8)?)?,
+            reservations: data.get(reservations_offset..).ok_or(FdtError::Truncated)?,
+        };
+        fdt.validate()?;
+        Ok(fdt)
+    }
+
+    /// Walks the whole structure block once so that later lookups
+    /// can treat malformed data as "not found".
+    fn validate(&self) -> Result<(), FdtError> {
+        let mut offset = 0;
+        let mut depth = 0;
+        loop {
+            let (token, next) = self.token(offset)?;
+            match token {
+                Token::BeginNode(_) if depth == 0 && offset != 0 => {
+                    return Err(FdtError::Unbalanced)
+                }
+                Token::BeginNode(_) => {
+                    depth += 1;
+                    if depth > MAX_DEPTH {
+                        return Err(FdtError::TooDeep);
+                    }
+                }
+                Token::EndNode => {
+                    if depth == 0 {
+                        return Err(FdtError::Unbalanced);
+                    }
+                    depth -= 1;
+                }
+                Token::Prop(_) if depth == 0 => return Err(FdtError::Unbalanced),
+                Token::Prop(_) => {}
+                Token::End if depth == 0 && offset != 0 => return Ok(()),
+                Token::End => return Err(FdtError::Unbalanced),
+            }
+            offset = next;
+        }
+    }
+
+    /// Reads the token at `offset`, skipping NOPs.
+    /// Returns the token and the offset of the token after it.
+    fn token(&self, offset: usize) -> Result<(Token<'a>, usize), FdtError> {
+        let structs = self.structs;
+        let mut offset = offset;
+        loop {
+            let tag = be32(structs, offset).ok_or(FdtError::Truncated)?;
+            offset += 4;
+            match tag {
+                FDT_NOP => continue,
+                FDT_BEGIN_NODE => {
+                    let name = cstr(structs, offset)?;
+                    return Ok((Token::BeginNode(name), align4(offset + name.len() + 1)));
+                }
+                FDT_END_NODE => return Ok((Token::EndNode, offset)),
+                FDT_PROP => {
+                    let len = be32(structs, offset).ok_or(FdtError::Truncated)? as usize;
+                    let name_offset = be32(structs, offset + 4).ok_or(FdtError::Truncated)?;
+                    let value = structs
+                        .get(offset + 8..offset + 8 + len)
+                        .ok_or(FdtError::Truncated)?;
+                    let name = cstr(self.strings, name_offset as usize)?;
+                    return Ok((
+                        Token::Prop(FdtProperty { name, value }),
+                        align4(offset + 8 + len),
+                    ));
+                }
+                FDT_END => return Ok((Token::End, offset)),
+                token => {
+                    return Err(FdtError::BadToken {
+                        offset: offset - 4,
+                        token,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Returns the offset just past the END_NODE matching the
+    /// BEGIN_NODE at `offset`.
+    fn skip_node(&self, offset: usize) -> Option<usize> {
+        let mut offset = offset;
+        let mut depth = 0usize;
+        loop {
+            let (token, next) = self.token(offset).ok()?;
+            offset = next;
+            match token {
+                Token::BeginNode(_) => depth += 1,
+                Token::EndNode => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(offset);
+                    }
+                }
+                Token::Prop(_) => {}
+                Token::End => return None,
+            }
+        }
+    }
+
+    /// Gets the raw blob, including the header.
+    pub const fn as_slice(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Gets the total size of the blob in bytes.
+    pub const fn total_size(&self) -> usize {
+        self.header.total_size
+    }
+
+    /// Gets the devicetree format version.
+    pub const fn version(&self) -> u32 {
+        self.header.version
+    }
+
+    /// Gets the physical ID of the boot CPU.
+    pub const fn boot_cpuid(&self) -> u32 {
+        self.header.boot_cpuid
+    }
+
+    /// Gets the root node.
+    pub fn root(&self) -> FdtNode<'a> {
+        let (token, props) = self.token(0).expect("validated device tree");
+        let name = match token {
+            Token::BeginNode(name) => name,
+            _ => unreachable!(),
+        };
+        FdtNode {
+            fdt: *self,
+            name,
+            offset: 0,
+            props,
+            parent_cells: CellSizes::default(),
+        }
+    }
+
+    /// Iterates over every node in depth-first order.
+    pub fn all_nodes(&self) -> NodeIter<'a> {
+        NodeIter {
+            fdt: *self,
+            offset: 0,
+            depth: 0,
+            cells: [CellSizes::default(); MAX_DEPTH],
+        }
+    }
+
+    /// Looks up a node by its full path, e.g. `/soc/uart@10000000`.
+    /// Unit addresses may be left out if they are unambiguous, and a path
+    /// that does not start with `/` is resolved through `/aliases`.
+    pub fn find_node(&self, path: &str) -> Option<FdtNode<'a>> {
+        let (mut node, rest) = if path.starts_with('/') {
+            (self.root(), path)
+        } else {
+            let (alias, rest) = match path.find('/') {
+                Some(i) => path.split_at(i),
+                None => (path, ""),
+            };
+            (self.find_node(self.resolve_alias(alias)?)?, rest)
+        };
+        for component in rest.split('/').filter(|c| !c.is_empty()) {
+            node = node.children().find(|c| name_matches(c.name, component))?;
+        }
+        Some(node)
+    }
+
+    /// Looks up an alias in `/aliases`.
+    pub fn resolve_alias(&self, alias: &str) -> Option<&'a str> {
+        self.root()
+            .children()
+            .find(|n| n.name == "aliases")?
+            .property(alias)?
+            .as_str()
+    }
+
+    /// Finds the first node compatible with any of the given strings.
+    pub fn find_compatible(&self, compatible: &[&str]) -> Option<FdtNode<'a>> {
+        self.all_nodes()
+            .find(|node| compatible.iter().any(|c| node.is_compatible(c)))
+    }
+
+    /// Finds the node with the given phandle.
+    pub fn find_phandle(&self, phandle: u32) -> Option<FdtNode<'a>> {
+        self.all_nodes()
+            .find(|node| node.phandle() == Some(phandle))
+    }
+
+    /// Gets the `/chosen` node.
+    pub fn chosen(&self) -> Option<FdtNode<'a>> {
+        self.find_node("/chosen")
+    }
+
+    /// Finds the parent of the given node.
+    pub fn parent_of(&self, node: &FdtNode<'a>) -> Option<FdtNode<'a>> {
+        let mut stack: [Option<FdtNode<'a>>; MAX_DEPTH] = [None; MAX_DEPTH];
+        let mut depth = 0usize;
+        let mut offset = 0;
+        loop {
+            let (token, next) = self.token(offset).ok()?;
+            match token {
+                Token::BeginNode(name) => {
+                    if offset == node.offset {
+                        return stack[depth.checked_sub(1)?];
+                    }
+                    let parent_cells = match depth {
+                        0 => CellSizes::default(),
+                        _ => stack[depth - 1]?.cell_sizes(),
+                    };
+                    stack[depth] = Some(FdtNode {
+                        fdt: *self,
+                        name,
+                        offset,
+                        props: next,
+                        parent_cells,
+                    });
+                    depth += 1;
+                }
+                Token::EndNode => depth -= 1,
+                Token::Prop(_) => {}
+                Token::End => return None,
+            }
+            offset = next;
+        }
+    }
+
+    /// Iterates over the memory reservation block.
+    pub const fn memory_reservations(&self) -> MemoryReservationIter<'a> {
+        MemoryReservationIter {
+            data: self.reservations,
+            offset: 0,
+        }
+    }
+}
+
+/// Checks if a path component names the given node.
+fn name_matches(name: &str, component: &str) -> bool {
+    name == component || (!component.contains('@') && name.split('@').next() == Some(component))
+}
+
+/// Values of `#address-cells` and `#size-cells`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellSizes {
+    /// Number of u32 cells in an address.
+    pub address_cells: usize,
+    /// Number of u32 cells in a size.
+    pub size_cells: usize,
+}
+
+impl Default for CellSizes {
+    /// The defaults mandated by the spec when the properties are missing.
+    fn default() -> Self {
+        Self {
+            address_cells: 2,
+            size_cells: 1,
+        }
+    }
+}
+
+/// A node in the device tree.
+#[derive(Clone, Copy)]
+pub struct FdtNode<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    /// Offset of the BEGIN_NODE token.
+    offset: usize,
+    /// Offset of the first token after the node name.
+    props: usize,
+    /// Cell sizes of the parent, used to decode `reg`.
+    parent_cells: CellSizes,
+}
+
+impl<'a> FdtNode<'a> {
+    /// Gets the node name, including the unit address.
+    pub const fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Checks if two nodes are the same node of the same tree.
+    pub fn is(&self, other: &FdtNode) -> bool {
+        self.fdt.data.as_ptr() == other.fdt.data.as_ptr() && self.offset == other.offset
+    }
+
+    /// Gets the unit address part of the name, if any.
+    pub fn unit_address(&self) -> Option<&'a str> {
+        self.name.split('@').nth(1)
+    }
+
+    /// Iterates over this node's properties.
+    pub const fn properties(&self) -> PropertyIter<'a> {
+        PropertyIter {
+            fdt: self.fdt,
+            offset: self.props,
+        }
+    }
+
+    /// Looks up a property by name.
+    pub fn property(&self, name: &str) -> Option<FdtProperty<'a>> {
+        self.properties().find(|p| p.name == name)
+    }
+
+    /// Iterates over the direct children of this node.
+    pub fn children(&self) -> ChildIter<'a> {
+        ChildIter {
+            fdt: self.fdt,
+            offset: self.props,
+            cells: self.cell_sizes(),
+        }
+    }
+
+    /// Gets the parent of this node, or None for the root.
+    pub fn parent(&self) -> Option<FdtNode<'a>> {
+        self.fdt.parent_of(self)
+    }
+
+    /// Iterates over the strings in the `compatible` property.
+    pub fn compatible(&self) -> Option<StrListIter<'a>> {
+        self.property("compatible").map(|p| p.as_str_list())
+    }
+
+    /// Checks if `compatible` contains the given string.
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible()
+            .map_or(false, |mut list| list.any(|c| c == compatible))
+    }
+
+    /// Gets the `phandle` of this node, if it has one.
+    pub fn phandle(&self) -> Option<u32> {
+        self.property("phandle")
+            .or_else(|| self.property("linux,phandle"))?
+            .as_u32()
+    }
+
+    /// Gets `#address-cells`, used to decode children's addresses.
+    pub fn address_cells(&self) -> usize {
+        self.property("#address-cells")
+            .and_then(|p| p.as_u32())
+            .map_or(CellSizes::default().address_cells, |v| v as usize)
+    }
+
+    /// Gets `#size-cells`, used to decode children's sizes.
+    pub fn size_cells(&self) -> usize {
+        self.property("#size-cells")
+            .and_then(|p| p.as_u32())
+            .map_or(CellSizes::default().size_cells, |v| v as usize)
+    }
+
+    /// Gets both `#address-cells` and `#size-cells`.
+    pub fn cell_sizes(&self) -> CellSizes {
+        CellSizes {
+            address_cells: self.address_cells(),
+            size_cells: self.size_cells(),
+        }
+    }
+
+    /// Iterates over the `reg` property, decoded with the parent's cell sizes.
+    pub fn reg(&self) -> Option<RegIter<'a>> {
+        Some(RegIter {
+            value: self.property("reg")?.value,
+            cells: self.parent_cells,
+        })
+    }
+
+    /// Gets the `ranges` property. An empty iterator means the child
+    /// address space is identity mapped onto the parent's.
+    pub fn ranges(&self) -> Option<RangesIter<'a>> {
+        Some(RangesIter {
+            value: self.property("ranges")?.value,
+            child_address_cells: self.address_cells(),
+            parent_address_cells: self.parent_cells.address_cells,
+            size_cells: self.size_cells(),
+        })
+    }
+
+    /// Translates an address from this node's `reg` into a CPU physical
+    /// address by walking the `ranges` of every parent bus.
+    /// Returns None if some bus on the way has no mapping for it.
+    pub fn translate_address(&self, address: usize) -> Option<usize> {
+        let mut address = address;
+        let mut bus = self.parent()?;
+        while let Some(parent) = bus.parent() {
+            let mut ranges = bus.ranges()?.peekable();
+            // An empty `ranges` is an identity mapping
+            if ranges.peek().is_some() {
+                let range = ranges
+                    .find(|r| address >= r.child_address && address - r.child_address < r.size)?;
+                address = address - range.child_address + range.parent_address;
+            }
+            bus = parent;
+        }
+        Some(address)
+    }
+
+    /// Gets `#interrupt-cells`, for interrupt controllers.
+    pub fn interrupt_cells(&self) -> Option<usize> {
+        self.property("#interrupt-cells")?
+            .as_u32()
+            .map(|v| v as usize)
+    }
+
+    /// Finds the interrupt parent, which may be inherited from an ancestor.
+    pub fn interrupt_parent(&self) -> Option<FdtNode<'a>> {
+        let mut node = *self;
+        loop {
+            if let Some(phandle) = node.property("interrupt-parent").and_then(|p| p.as_u32()) {
+                return self.fdt.find_phandle(phandle);
+            }
+            node = node.parent()?;
+        }
+    }
+
+    /// Iterates over the `interrupts` property, split according to the
+    /// interrupt parent's `#interrupt-cells`.
+    pub fn interrupts(&self) -> Option<InterruptIter<'a>> {
+        let value = self.property("interrupts")?.value;
+        let cells = self
+            .interrupt_parent()
+            .and_then(|p| p.interrupt_cells())
+            .unwrap_or(1);
+        Some(InterruptIter {
+            value,
+            cells: cells.max(1),
+        })
+    }
+}
+
+/// Iterator over the properties of a node.
+pub struct PropertyIter<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for PropertyIter<'a> {
+    type Item = FdtProperty<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.fdt.token(self.offset).ok()? {
+            (Token::Prop(prop), next) => {
+                self.offset = next;
+                Some(prop)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Iterator over the children of a node.
+pub struct ChildIter<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    cells: CellSizes,
+}
+
+impl<'a> Iterator for ChildIter<'a> {
+    type Item = FdtNode<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (token, next) = self.fdt.token(self.offset).ok()?;
+            match token {
+                Token::Prop(_) => self.offset = next,
+                Token::BeginNode(name) => {
+                    let node = FdtNode {
+                        fdt: self.fdt,
+                        name,
+                        offset: self.offset,
+                        props: next,
+                        parent_cells: self.cells,
+                    };
+                    self.offset = self.fdt.skip_node(self.offset)?;
+                    return Some(node);
+                }
+                Token::EndNode | Token::End => return None,
+            }
+        }
+    }
+}
+
+/// Depth-first iterator over every node in the tree.
+pub struct NodeIter<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    depth: usize,
+    cells: [CellSizes; MAX_DEPTH],
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+    type Item = FdtNode<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (token, next) = self.fdt.token(self.offset).ok()?;
+            match token {
+                Token::BeginNode(name) => {
+                    let node = FdtNode {
+                        fdt: self.fdt,
+                        name,
+                        offset: self.offset,
+                        props: next,
+                        parent_cells: match self.depth {
+                            0 => CellSizes::default(),
+                            depth => self.cells[depth - 1],
+                        },
+                    };
+                    self.offset = next;
+                    self.cells[self.depth] = node.cell_sizes();
+                    self.depth += 1;
+                    return Some(node);
+                }
+                Token::EndNode => self.depth -= 1,
+                Token::Prop(_) => {}
+                Token::End => return None,
+            }
+            self.offset = next;
+        }
+    }
+}
+
+/// A property of a node.
+#[derive(Clone, Copy)]
+pub struct FdtProperty<'a> {
+    /// Property name.
+    pub name: &'a str,
+    /// Raw big-endian value.
+    pub value: &'a [u8],
+}
+
+impl<'a> FdtProperty<'a> {
+    /// Reads the value as a single u32 cell.
+    pub fn as_u32(&self) -> Option<u32> {
+        match self.value.len() {
+            4 => be32(self.value, 0),
+            _ => None,
+        }
+    }
+
+    /// Reads the value as a u64, which may be stored as one or two cells.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => be32(self.value, 0).map(u64::from),
+            8 => be64(self.value, 0),
+            _ => None,
+        }
+    }
+
+    /// Reads the value as a NUL-terminated string.
+    pub fn as_str(&self) -> Option<&'a str> {
+        let (last, rest) = self.value.split_last()?;
+        if *last != 0 {
+            return None;
+        }
+        str::from_utf8(rest).ok()
+    }
+
+    /// Iterates over the value as a list of NUL-terminated strings.
+    pub const fn as_str_list(&self) -> StrListIter<'a> {
+        StrListIter { value: self.value }
+    }
+
+    /// Iterates over the value as u32 cells.
+    pub fn cells(&self) -> impl Iterator<Item = u32> + 'a {
+        self.value
+            .chunks_exact(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+    }
+}
+
+/// Iterator over a string list property.
+pub struct StrListIter<'a> {
+    value: &'a [u8],
+}
+
+impl<'a> Iterator for StrListIter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.value.iter().position(|&b| b == 0)?;
+        let s = str::from_utf8(&self.value[..len]).ok();
+        self.value = &self.value[len + 1..];
+        s
+    }
+}
+
+/// An entry of a `reg` property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegEntry {
+    /// Start address, in the parent's address space.
+    pub address: usize,
+    /// Size in bytes. Zero if the parent's `#size-cells` is zero.
+    pub size: usize,
+}
+
+/// Iterator over a `reg` property.
+pub struct RegIter<'a> {
+    value: &'a [u8],
+    cells: CellSizes,
+}
+
+impl<'a> Iterator for RegIter<'a> {
+    type Item = RegEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let address_len = self.cells.address_cells * 4;
+        let entry_len = address_len + self.cells.size_cells * 4;
+        if entry_len == 0 || self.value.len() < entry_len {
+            return None;
+        }
+        let (entry, rest) = self.value.split_at(entry_len);
+        self.value = rest;
+        Some(RegEntry {
+            address: read_cells(entry, self.cells.address_cells)?,
+            size: read_cells(&entry[address_len..], self.cells.size_cells)?,
+        })
+    }
+}
+
+/// An entry of a `ranges` property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    /// Start address in the child's address space.
+    pub child_address: usize,
+    /// Start address in the parent's address space.
+    pub parent_address: usize,
+    /// Size in bytes.
+    pub size: usize,
+}
+
+/// Iterator over a `ranges` property.
+pub struct RangesIter<'a> {
+    value: &'a [u8],
+    child_address_cells: usize,
+    parent_address_cells: usize,
+    size_cells: usize,
+}
+
+impl<'a> Iterator for RangesIter<'a> {
+    type Item = Range;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let child_len = self.child_address_cells * 4;
+        let parent_len = self.parent_address_cells * 4;
+        let entry_len = child_len + parent_len + self.size_cells * 4;
+        if entry_len == 0 || self.value.len() < entry_len {
+            return None;
+        }
+        let (entry, rest) = self.value.split_at(entry_len);
+        self.value = rest;
+        Some(Range {
+            child_address: read_cells(entry, self.child_address_cells)?,
+            parent_address: read_cells(&entry[child_len..], self.parent_address_cells)?,
+            size: read_cells(&entry[child_len + parent_len..], self.size_cells)?,
+        })
+    }
+}
+
+/// One interrupt specifier from an `interrupts` property.
+#[derive(Clone, Copy)]
+pub struct InterruptSpecifier<'a> {
+    value: &'a [u8],
+}
+
+impl<'a> InterruptSpecifier<'a> {
+    /// Gets the given cell of the specifier.
+    pub fn cell(&self, index: usize) -> Option<u32> {
+        be32(self.value, index * 4)
+    }
+
+    /// Gets the number of cells in the specifier.
+    pub const fn len(&self) -> usize {
+        self.value.len() / 4
+    }
+
+    /// Checks if the specifier has no cells.
+    pub const fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+}
+
+/// Iterator over an `interrupts` property.
+pub struct InterruptIter<'a> {
+    value: &'a [u8],
+    cells: usize,
+}
+
+impl<'a> Iterator for InterruptIter<'a> {
+    type Item = InterruptSpecifier<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.cells * 4;
+        if self.value.len() < len {
+            return None;
+        }
+        let (value, rest) = self.value.split_at(len);
+        self.value = rest;
+        Some(InterruptSpecifier { value })
+    }
+}
+
+/// An entry of the memory reservation block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryReservation {
+    /// Physical start address.
+    pub address: usize,
+    /// Size in bytes.
+    pub size: usize,
+}
+
+/// Iterator over the memory reservation block.
+pub struct MemoryReservationIter<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for MemoryReservationIter<'a> {
+    type Item = MemoryReservation;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let address = be64(self.data, self.offset)? as usize;
+        let size = be64(self.data, self.offset + 8)? as usize;
+        if address == 0 && size == 0 {
+            return None;
+        }
+        self.offset += 16;
+        Some(MemoryReservation { address, size })
+    }
+}
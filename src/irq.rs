@@ -0,0 +1,238 @@
+//! Architecture independent IRQ handling.
+//!
+//! Each architecture finds its interrupt controller and installs it with
+//! [set_chip]. Drivers then [request] IRQs with a handler, and the trap code
+//! calls [dispatch] whenever the controller raises an interrupt.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{driver_interfaces::IrqChip, fdt::FdtNode, printk, println, util::UnsafeMutex};
+
+/// Handles an IRQ. Implemented for closures taking the IRQ number.
+pub trait IrqHandler: Send {
+    /// Handles the given IRQ.
+    fn handle(&mut self, irq: u32);
+}
+
+impl<F: FnMut(u32) + Send> IrqHandler for F {
+    fn handle(&mut self, irq: u32) {
+        self(irq)
+    }
+}
+
+/// Errors from requesting or changing an IRQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// No interrupt controller has been installed.
+    NoController,
+    /// The controller has no such IRQ.
+    InvalidIrq,
+    /// The IRQ already has a handler.
+    Busy,
+    /// The IRQ has no handler.
+    NotRequested,
+}
+
+/// State of one IRQ.
+#[derive(Default)]
+struct IrqDesc {
+    /// Name of the owner, for diagnostics.
+    name: &'static str,
+    handler: Option<Box<dyn IrqHandler>>,
+    /// Whether the owner wants the IRQ delivered.
+    enabled: bool,
+    /// Number of times the IRQ was handled.
+    count: usize,
+}
+
+/// The installed controller and the state of its IRQs.
+struct Irqs {
+    chip: Option<Box<dyn IrqChip>>,
+    descs: Vec<IrqDesc>,
+    /// Interrupts that had no handler, or that vanished before being claimed.
+    spurious: usize,
+}
+
+static IRQS: UnsafeMutex<Irqs> = UnsafeMutex::new(Irqs {
+    chip: None,
+    descs: Vec::new(),
+    spurious: 0,
+});
+
+impl Irqs {
+    /// Gets the controller and the descriptor of the given IRQ.
+    fn get(&mut self, irq: u32) -> Result<(&mut dyn IrqChip, &mut IrqDesc), IrqError> {
+        let chip = self.chip.as_deref_mut().ok_or(IrqError::NoController)?;
+        let desc = self
+            .descs
+            .get_mut(irq as usize)
+            .ok_or(IrqError::InvalidIrq)?;
+        Ok((chip, desc))
+    }
+
+    /// Gets the controller and the descriptor of an IRQ that has a handler.
+    fn get_requested(&mut self, irq: u32) -> Result<(&mut dyn IrqChip, &mut IrqDesc), IrqError> {
+        let (chip, desc) = self.get(irq)?;
+        match desc.handler {
+            Some(_) => Ok((chip, desc)),
+            None => Err(IrqError::NotRequested),
+        }
+    }
+}
+
+/// Installs the interrupt controller, with every IRQ masked.
+/// Handlers requested from a previous controller are dropped.
+pub fn set_chip(mut chip: Box<dyn IrqChip>) {
+    let count = chip.irq_count();
+    for irq in 0..count as u32 {
+        chip.mask(irq);
+    }
+    printk!("Using {} with {} IRQs", chip.name(), count);
+    let mut irqs = IRQS.lock();
+    irqs.descs = (0..count).map(|_| IrqDesc::default()).collect();
+    irqs.chip = Some(chip);
+}
+
+/// Finds the IRQ number of the first interrupt of a device tree node.
+pub fn translate(node: &FdtNode) -> Option<u32> {
+    let parent = node.interrupt_parent()?;
+    let specifier = node.interrupts()?.next()?;
+    IRQS.lock().chip.as_ref()?.translate(&parent, &specifier)
+}
+
+/// Installs a handler for the given IRQ and enables it.
+///
+/// # Errors
+/// Fails if there is no such IRQ or it already has a handler.
+pub fn request(
+    irq: u32,
+    name: &'static str,
+    handler: impl IrqHandler + 'static,
+) -> Result<(), IrqError> {
+    let mut irqs = IRQS.lock();
+    let (chip, desc) = irqs.get(irq)?;
+    if desc.handler.is_some() {
+        return Err(IrqError::Busy);
+    }
+    *desc = IrqDesc {
+        name,
+        handler: Some(Box::new(handler)),
+        enabled: true,
+        count: 0,
+    };
+    chip.unmask(irq);
+    Ok(())
+}
+
+/// Disables an IRQ and removes its handler.
+///
+/// # Errors
+/// Fails if the IRQ has no handler.
+pub fn free(irq: u32) -> Result<(), IrqError> {
+    let mut irqs = IRQS.lock();
+    let (chip, desc) = irqs.get_requested(irq)?;
+    chip.mask(irq);
+    *desc = IrqDesc::default();
+    Ok(())
+}
+
+/// Starts delivering an IRQ again after [disable].
+///
+/// # Errors
+/// Fails if the IRQ has no handler.
+pub fn enable(irq: u32) -> Result<(), IrqError> {
+    let mut irqs = IRQS.lock();
+    let (chip, desc) = irqs.get_requested(irq)?;
+    desc.enabled = true;
+    chip.unmask(irq);
+    Ok(())
+}
+
+/// Stops delivering an IRQ until [enable] is called, keeping its handler.
+///
+/// # Errors
+/// Fails if the IRQ has no handler.
+pub fn disable(irq: u32) -> Result<(), IrqError> {
+    let mut irqs = IRQS.lock();
+    let (chip, desc) = irqs.get_requested(irq)?;
+    desc.enabled = false;
+    chip.mask(irq);
+    Ok(())
+}
+
+/// Masks an IRQ at the controller for a short while, such as around code
+/// that shares state with its handler.
+///
+/// # Errors
+/// Fails if the IRQ has no handler.
+pub fn mask(irq: u32) -> Result<(), IrqError> {
+    let mut irqs = IRQS.lock();
+    let (chip, _) = irqs.get_requested(irq)?;
+    chip.mask(irq);
+    Ok(())
+}
+
+/// Undoes [mask]. Disabled IRQs stay masked.
+///
+/// # Errors
+/// Fails if the IRQ has no handler.
+pub fn unmask(irq: u32) -> Result<(), IrqError> {
+    let mut irqs = IRQS.lock();
+    let (chip, desc) = irqs.get_requested(irq)?;
+    if desc.enabled {
+        chip.unmask(irq);
+    }
+    Ok(())
+}
+
+/// Handles every pending IRQ. Called from the trap handlers, so the state
+/// is used without locking, as the interrupted code may hold the lock.
+pub fn dispatch() {
+    let irqs = unsafe { IRQS.get_mut() };
+    let chip = match irqs.chip.as_deref_mut() {
+        Some(chip) => chip,
+        None => {
+            irqs.spurious += 1;
+            return;
+        }
+    };
+    let mut handled = false;
+    while let Some(irq) = chip.claim() {
+        handled = true;
+        match irqs.descs.get_mut(irq as usize) {
+            Some(IrqDesc {
+                handler: Some(handler),
+                count,
+                ..
+            }) => {
+                *count += 1;
+                handler.handle(irq);
+            }
+            _ => irqs.spurious += 1,
+        }
+        chip.complete(irq);
+    }
+    if !handled {
+        irqs.spurious += 1;
+    }
+}
+
+/// Prints the handled count of every requested IRQ.
+pub fn print_stats() {
+    let irqs = IRQS.lock();
+    if let Some(ref chip) = irqs.chip {
+        println!("IRQs on {}:", chip.name());
+    }
+    for (irq, desc) in irqs.descs.iter().enumerate() {
+        if desc.handler.is_some() {
+            println!(
+                "{:>5} {:>10} {:<16}{}",
+                irq,
+                desc.count,
+                desc.name,
+                if desc.enabled { "" } else { " (disabled)" }
+            );
+        }
+    }
+    println!("  spurious {:>6}", irqs.spurious);
+}
@@ -0,0 +1,317 @@
+//! Small object allocator, used as the global allocator.
+//!
+//! Allocations up to [MAX_SLAB_OBJECT] bytes come from per size class slab
+//! caches. Every slab is a naturally aligned block from the page allocator
+//! with a [Slab] header in front of its objects, so an object's slab is found
+//! by masking its address. Larger allocations go straight to the page allocator.
+//!
+//! The page allocator and the slab caches share one lock, which is only taken
+//! with interrupts disabled, so an interrupt can't spin on it while the code
+//! it interrupted holds it. Other users of the page allocator go through
+//! [with_page_allocator].
+//!
+//! Failed allocations go through the [oom] handler. Box and Vec abort when it
+//! gives up, so code that can recover should use [try_box] and [try_vec].
+
+use crate::{
+    cpu, oom,
+    physical_page_allocator::{PhysicalPageAllocator, PAGE_SIZE},
+    util::UnsafeMutex,
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{null_mut, NonNull},
+};
+
+/// Object sizes of the slab caches. Objects are aligned to their size.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Largest allocation served from a slab cache.
+pub const MAX_SLAB_OBJECT: usize = SIZE_CLASSES[SIZE_CLASSES.len() - 1];
+
+/// Minimum number of object slots in a slab, which decides the slab size.
+const MIN_SLAB_OBJECTS: usize = 8;
+
+#[global_allocator]
+static KMALLOC: Kmalloc = Kmalloc;
+
+/// Everything behind the allocator lock.
+struct Allocators {
+    pages: PhysicalPageAllocator,
+    caches: [SlabCache; SIZE_CLASSES.len()],
+}
+
+static ALLOCATORS: UnsafeMutex<Allocators> = UnsafeMutex::new(Allocators {
+    pages: PhysicalPageAllocator::new(),
+    caches: [
+        SlabCache::new(SIZE_CLASSES[0]),
+        SlabCache::new(SIZE_CLASSES[1]),
+        SlabCache::new(SIZE_CLASSES[2]),
+        SlabCache::new(SIZE_CLASSES[3]),
+        SlabCache::new(SIZE_CLASSES[4]),
+        SlabCache::new(SIZE_CLASSES[5]),
+        SlabCache::new(SIZE_CLASSES[6]),
+        SlabCache::new(SIZE_CLASSES[7]),
+    ],
+});
+
+/// Error from a fallible allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError {
+    /// The layout that could not be allocated.
+    pub layout: Layout,
+}
+
+/// A free object, linked into its slab's free list.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Header at the start of every slab.
+struct Slab {
+    /// Next slab with free objects.
+    next: *mut Slab,
+    /// Previous slab with free objects.
+    prev: *mut Slab,
+    /// Free objects in this slab.
+    free: *mut FreeObject,
+    /// Number of objects handed out.
+    in_use: usize,
+}
+
+/// A cache of equally sized objects.
+struct SlabCache {
+    object_size: usize,
+    /// Slabs with at least one free object.
+    partial: *mut Slab,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            partial: null_mut(),
+        }
+    }
+
+    /// Gets the size of each slab, which is also its alignment.
+    fn slab_size(&self) -> usize {
+        (self.object_size * MIN_SLAB_OBJECTS)
+            .max(PAGE_SIZE)
+            .next_power_of_two()
+    }
+
+    /// Gets the offset of the first object, past the header.
+    fn first_object(&self) -> usize {
+        core::mem::size_of::<Slab>().max(self.object_size)
+    }
+
+    /// Gets the number of objects in each slab.
+    fn objects_per_slab(&self) -> usize {
+        (self.slab_size() - self.first_object()) / self.object_size
+    }
+
+    /// Finds the slab an object belongs to.
+    fn slab_of(&self, object: *mut u8) -> *mut Slab {
+        (object as usize & !(self.slab_size() - 1)) as *mut Slab
+    }
+
+    /// Adds a slab to the front of the partial list.
+    unsafe fn push_partial(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if let Some(next) = self.partial.as_mut() {
+            next.prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    /// Removes a slab from the partial list.
+    unsafe fn unlink_partial(&mut self, slab: *mut Slab) {
+        let Slab { next, prev, .. } = *slab;
+        match prev.as_mut() {
+            Some(prev) => prev.next = next,
+            None => self.partial = next,
+        }
+        if let Some(next) = next.as_mut() {
+            next.prev = prev;
+        }
+    }
+
+    /// Gets a new slab from the page allocator and adds it to the partial list.
+    unsafe fn grow(&mut self, pages: &mut PhysicalPageAllocator) -> Option<*mut Slab> {
+        let base = pages.try_allocate(self.slab_size())?;
+        let slab = base as *mut Slab;
+        // Thread the free list through the objects, lowest address first
+        let mut free = null_mut();
+        for i in (0..self.objects_per_slab()).rev() {
+            let object = base.add(self.first_object() + i * self.object_size) as *mut FreeObject;
+            object.write(FreeObject { next: free });
+            free = object;
+        }
+        slab.write(Slab {
+            next: null_mut(),
+            prev: null_mut(),
+            free,
+            in_use: 0,
+        });
+        self.push_partial(slab);
+        Some(slab)
+    }
+
+    unsafe fn alloc(&mut self, pages: &mut PhysicalPageAllocator) -> *mut u8 {
+        let slab = match self.partial.as_mut() {
+            Some(slab) => slab,
+            None => match self.grow(pages) {
+                Some(slab) => &mut *slab,
+                None => return null_mut(),
+            },
+        };
+        let object = slab.free;
+        slab.free = (*object).next;
+        slab.in_use += 1;
+        if slab.free.is_null() {
+            self.unlink_partial(slab);
+        }
+        object as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, pages: &mut PhysicalPageAllocator, object: *mut u8) {
+        let slab = self.slab_of(object);
+        let was_full = (*slab).free.is_null();
+        let object = object as *mut FreeObject;
+        object.write(FreeObject { next: (*slab).free });
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        if was_full {
+            self.push_partial(slab);
+        }
+        // Give empty slabs back, but keep one around so a single object
+        // being allocated and freed doesn't keep hitting the page allocator
+        if (*slab).in_use == 0 && !((*slab).prev.is_null() && (*slab).next.is_null()) {
+            self.release(pages, slab);
+        }
+    }
+
+    /// Returns an empty slab to the page allocator.
+    unsafe fn release(&mut self, pages: &mut PhysicalPageAllocator, slab: *mut Slab) {
+        self.unlink_partial(slab);
+        pages.deallocate(slab as *mut u8, self.slab_size());
+    }
+
+    /// Returns every empty slab to the page allocator, returning the number of pages freed.
+    unsafe fn shrink(&mut self, pages: &mut PhysicalPageAllocator) -> usize {
+        let mut freed = 0;
+        let mut slab = self.partial;
+        while let Some(s) = slab.as_mut() {
+            slab = s.next;
+            if s.in_use == 0 {
+                self.release(pages, s);
+                freed += self.slab_size() / PAGE_SIZE;
+            }
+        }
+        freed
+    }
+}
+
+/// Finds the slab cache for the given layout, if it is small enough for one.
+fn cache_index(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+/// Gets the size to ask the page allocator for. Page allocations are aligned
+/// to their size rounded up to a power of two, so this covers the alignment.
+fn page_alloc_size(layout: &Layout) -> usize {
+    layout.size().max(layout.align())
+}
+
+/// Runs `f` on the page allocator, with the allocator lock held and
+/// interrupts disabled. `f` must not allocate.
+pub fn with_page_allocator<T>(f: impl FnOnce(&mut PhysicalPageAllocator) -> T) -> T {
+    cpu::without_interrupts(|| f(&mut ALLOCATORS.lock().pages))
+}
+
+/// Frees the empty slabs of every cache, returning the number of pages freed.
+pub fn reclaim() -> usize {
+    cpu::without_interrupts(|| {
+        // Memory may have run out while the allocators were locked
+        let mut allocators = match ALLOCATORS.try_lock() {
+            Some(allocators) => allocators,
+            None => return 0,
+        };
+        let Allocators { pages, caches } = &mut *allocators;
+        caches.iter_mut().map(|c| unsafe { c.shrink(pages) }).sum()
+    })
+}
+
+/// Allocates a Box, returning an error instead of aborting when out of memory.
+///
+/// # Errors
+/// Fails if the OOM handler could not free enough memory.
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+    let ptr = unsafe { alloc::alloc::alloc(layout) } as *mut T;
+    if ptr.is_null() {
+        return Err(AllocError { layout });
+    }
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Creates an empty Vec with room for `capacity` elements, returning an
+/// error instead of aborting when out of memory.
+///
+/// # Errors
+/// Fails if the OOM handler could not free enough memory.
+pub fn try_vec<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    let layout = Layout::array::<T>(capacity).map_err(|_| AllocError {
+        layout: Layout::new::<T>(),
+    })?;
+    if layout.size() == 0 {
+        return Ok(Vec::with_capacity(capacity));
+    }
+    let ptr = NonNull::new(unsafe { alloc::alloc::alloc(layout) } as *mut T)
+        .ok_or(AllocError { layout })?;
+    Ok(unsafe { Vec::from_raw_parts(ptr.as_ptr(), 0, capacity) })
+}
+
+/// The global allocator.
+pub struct Kmalloc;
+
+unsafe impl GlobalAlloc for Kmalloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The lock is dropped before the OOM handler runs, since it frees
+        oom::retry(layout.size(), || {
+            let ptr = cpu::without_interrupts(|| {
+                let mut allocators = ALLOCATORS.lock();
+                let Allocators { pages, caches } = &mut *allocators;
+                match cache_index(&layout) {
+                    Some(index) => caches[index].alloc(pages),
+                    None => pages
+                        .try_allocate(page_alloc_size(&layout))
+                        .unwrap_or(null_mut()),
+                }
+            });
+            NonNull::new(ptr)
+        })
+        .map_or(null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        cpu::without_interrupts(|| {
+            let mut allocators = ALLOCATORS.lock();
+            let Allocators { pages, caches } = &mut *allocators;
+            match cache_index(&layout) {
+                Some(index) => caches[index].dealloc(pages, ptr),
+                None => pages.deallocate(ptr, page_alloc_size(&layout)),
+            }
+        })
+    }
+}
@@ -0,0 +1,141 @@
+//! Kernel threads, for concurrent work inside the kernel.
+//!
+//! [spawn] runs a closure as a new task of the scheduler, on its own stack,
+//! and returns a [JoinHandle] for its result. Every stack is mapped below an
+//! unmapped guard page, so overflowing it faults instead of corrupting the
+//! memory below.
+
+use alloc::{boxed::Box, sync::Arc};
+
+use spin::Mutex;
+
+pub use crate::sched::{exit, sleep, yield_now, SpawnError};
+use crate::{
+    arch::mmu::virt_to_phys,
+    bsp::PAGE_SIZE,
+    kmalloc::with_page_allocator,
+    mmu::{AddressSpace, Attributes, Permissions, RootTable, KERNEL_STACK_BASE},
+    process::MAX_PROCESSES,
+    sched,
+};
+
+/// Size of a thread's stack, not counting its guard page.
+pub const STACK_SIZE: usize = 4 * PAGE_SIZE;
+
+/// Distance between the stacks of neighbouring process slots, which leaves
+/// a guard page below each one.
+const STACK_STRIDE: usize = STACK_SIZE + PAGE_SIZE;
+
+/// Slot of the stack that traps from the kernel run on, if the architecture
+/// needs one, past the process slots.
+pub const TRAP_STACK_SLOT: usize = MAX_PROCESSES;
+
+/// A kernel stack mapped into the stack window, freed when dropped.
+#[derive(Debug)]
+pub struct KernelStack {
+    /// Lowest mapped address.
+    base: usize,
+    /// Where the allocator gave us the memory.
+    memory: usize,
+}
+
+impl KernelStack {
+    /// Allocates and maps the stack of a process slot, or [TRAP_STACK_SLOT].
+    ///
+    /// # Errors
+    /// Fails if there is no memory for the stack or its page tables.
+    pub fn new(slot: usize) -> Result<Self, SpawnError> {
+        let memory = with_page_allocator(|pages| pages.try_allocate(STACK_SIZE))
+            .ok_or(SpawnError::OutOfMemory)? as usize;
+        let base = KERNEL_STACK_BASE + slot * STACK_STRIDE + PAGE_SIZE;
+        let mapped = RootTable::kernel().map(
+            base,
+            virt_to_phys(memory),
+            STACK_SIZE,
+            Permissions::RW,
+            Attributes::Global,
+        );
+        if mapped.is_err() {
+            with_page_allocator(|pages| pages.deallocate(memory as _, STACK_SIZE));
+            return Err(SpawnError::OutOfMemory);
+        }
+        Ok(Self { base, memory })
+    }
+
+    /// Gets the initial stack pointer.
+    pub const fn top(&self) -> usize {
+        self.base + STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        // Can't fail, the stack was mapped with small pages
+        let _ = RootTable::kernel().unmap(self.base, STACK_SIZE);
+        with_page_allocator(|pages| pages.deallocate(self.memory as _, STACK_SIZE));
+    }
+}
+
+/// Checks if an address is in the guard page of a thread's stack or the trap
+/// stack, meaning that it overflowed.
+pub fn is_guard_page(addr: usize) -> bool {
+    let end = KERNEL_STACK_BASE + (TRAP_STACK_SLOT + 1) * STACK_STRIDE;
+    (KERNEL_STACK_BASE..end).contains(&addr)
+        && (addr - KERNEL_STACK_BASE) % STACK_STRIDE < PAGE_SIZE
+}
+
+/// The closure of a thread, as passed to [thread_start].
+type Main = Box<dyn FnOnce() + Send>;
+
+/// Entry point of every thread.
+extern "C" fn thread_start(main: usize) -> ! {
+    let main = unsafe { Box::from_raw(main as *mut Main) };
+    main();
+    exit()
+}
+
+/// Handle to a thread, used to wait for its result.
+#[derive(Debug)]
+pub struct JoinHandle<T> {
+    pid: u64,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Gets the pid of the thread.
+    pub const fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// Waits for the thread to finish, and returns what it returned.
+    /// Returns None if it called [exit] instead.
+    pub fn join(self) -> Option<T> {
+        sched::wait(self.pid);
+        self.result.lock().take()
+    }
+}
+
+/// Runs `f` in a new thread.
+///
+/// # Errors
+/// Fails if the scheduler isn't running, or there is no room for the thread.
+pub fn spawn<F, T>(name: &'static str, f: F) -> Result<JoinHandle<T>, SpawnError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let their_result = result.clone();
+    let main: Main = Box::new(move || {
+        let value = f();
+        *their_result.lock() = Some(value);
+    });
+    let main = Box::into_raw(Box::new(main));
+    match sched::spawn(name, thread_start, main as usize) {
+        Ok(pid) => Ok(JoinHandle { pid, result }),
+        Err(e) => {
+            drop(unsafe { Box::from_raw(main) });
+            Err(e)
+        }
+    }
+}
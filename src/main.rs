@@ -1,4 +1,5 @@
 #![feature(asm)]
+#![feature(alloc_error_handler)]
 #![feature(alloc_prelude)]
 #![feature(global_asm)]
 #![feature(const_fn)]
@@ -8,7 +9,6 @@
 #![feature(const_panic)]
 #![feature(const_ptr_offset)]
 #![feature(const_size_of_val)]
-#![feature(default_alloc_error_handler)]
 #![feature(label_break_value)]
 #![feature(layout_for_ptr)]
 #![feature(naked_functions)]
@@ -28,21 +28,29 @@ extern crate alloc;
 
 mod arch;
 mod bsp;
+mod cmdline;
 mod cpu;
 mod driver_interfaces;
 mod drivers;
+mod fdt;
+mod irq;
+mod kmalloc;
+mod kthread;
 mod memory;
 mod mmu;
+mod oom;
 mod panic;
 mod physical_page_allocator;
 mod print;
 mod process;
+mod sched;
+mod syscall;
 mod time;
 mod util;
 
-use arch::mmu::{SvTable, XWRPermissions, __root_page_table};
 use driver_interfaces::{Console, UartConsole};
-use process::Process;
+use memory::{MemoryMap, MemoryRegion};
+use process::{Process, MAX_PROCESSES};
 use util::UnsafeMutex;
 
 /// Creates a static ref to a linker variable
@@ -63,15 +71,14 @@ const PAGING_TEST: usize = 0x1010101010101010;
 static STDOUT: UnsafeMutex<Option<UartConsole>> = UnsafeMutex::new(None);
 
 /// Processes!
-static PROCESSES: UnsafeMutex<[Option<Process>; 4]> = UnsafeMutex::new([None; 4]);
+static PROCESSES: UnsafeMutex<[Option<Process>; MAX_PROCESSES]> =
+    UnsafeMutex::new([None; MAX_PROCESSES]);
 
 /// The early entry point for initializing the OS.
 /// Paging, DTB, etc. are setup here.
 ///
 /// # Safety
-/// Safe only to call from [setup_environment].
-///
-/// [setup_environment]: memory::setup_environment
+/// Safe only to call once, from the boot code of the architecture.
 #[no_mangle]
 pub extern "C" fn kinit(dtb_addr: *mut u8) {
     // unmap old kernel base
@@ -99,7 +106,55 @@ pub extern "C" fn kinit(dtb_addr: *mut u8) {
     // };
 
     printk!("dtb_addr = {:x}", dtb_addr as usize);
+    match unsafe { fdt::init(arch::mmu::phys_to_virt(dtb_addr as usize) as *const u8) } {
+        Ok(fdt) => {
+            printk!(
+                "Device tree v{}, {} bytes, boot cpu {}",
+                fdt.version(),
+                fdt.total_size(),
+                fdt.boot_cpuid()
+            );
+            if let Some(model) = fdt.root().property("model").and_then(|p| p.as_str()) {
+                printk!("Model: {}", model);
+            }
+            cmdline::init(&fdt);
+            if let Some(path) = drivers::CONSOLE.get() {
+                match drivers::probe_console(&fdt, path) {
+                    Some(console) => unsafe { *STDOUT.get_mut() = Some(console) },
+                    None => printk!("No usable console at {}", path),
+                }
+            }
+            match drivers::probe_all(&fdt) {
+                Ok(probed) => printk!("Probed {} devices", probed),
+                Err(e) => printk!(
+                    "Probed {} devices, {} more didn't fit in the registry",
+                    e.registered,
+                    e.dropped
+                ),
+            }
+
+            let mut memory_map = MemoryMap::from_fdt(
+                &fdt,
+                memory::kernel_region(),
+                MemoryRegion::new(dtb_addr as usize, fdt.total_size()),
+            );
+            unsafe { arch::mmu::init_direct_map(&memory_map) };
+            memory_map.clamp_ram(arch::mmu::addressable_ram());
+            memory_map.print();
+            kmalloc::with_page_allocator(|pages| unsafe { pages.init(&memory_map) });
+            unsafe { arch::irq::init(&fdt) };
+            drivers::serial::init_console(&fdt);
+            unsafe { arch::time::init_timer(&fdt) };
+        }
+        Err(e) => {
+            printk!("Failed to parse device tree: {:?}", e);
+            printk!("Falling back to the boot heap");
+            kmalloc::with_page_allocator(|pages| unsafe { pages.default_init() });
+        }
+    }
     printk!("Initialized ppa and mmu");
+    unsafe { sched::init() };
+    process::start_init();
 
     printk!("Stack is broken, right?");
     printk!("HAHA NO ITS NOT!!!!!!");
@@ -1,49 +1,215 @@
-use crate::{link_var, mmu::HIGHER_HALF_BASE};
-link_var!(__bss_start);
-link_var!(__bss_end);
-
-const fn subdivide_size<T: Sized>(size: usize) -> usize {
-    let t_size = core::mem::size_of::<T>();
-    assert!(
-        size % t_size == 0,
-        "bss size must be a multiple of given type"
-    );
-    size / t_size
+use core::ops::Range;
+
+use crate::{
+    arch::mmu::virt_to_phys, fdt::Fdt, link_var, physical_page_allocator::PAGE_SIZE, printk,
+};
+
+/// Maximum number of RAM regions we track.
+const MAX_RAM_REGIONS: usize = 8;
+/// Maximum number of reserved regions we track.
+const MAX_RESERVED_REGIONS: usize = 32;
+
+/// A half-open range of physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// First byte of the region.
+    pub start: usize,
+    /// One past the last byte of the region.
+    pub end: usize,
 }
 
-/// # Safety
-/// Safe only to be called from asm entry.
-#[no_mangle]
-#[allow(improper_ctypes_definitions)]
-pub unsafe extern "C" fn setup_environment(dtb_addr: *mut u8, old_kern_start: usize) {
-    // setup stack and gp too
-    let gp: usize;
-    let sp: usize;
-    // let ra: u64;
-    asm!("mv {0}, gp", out(reg) gp);
-    asm!("mv {0}, sp", out(reg) sp);
-    // asm!("mv {0}, ra", out(reg) ra);
-    asm!("mv gp, {0}", in(reg) gp - old_kern_start + HIGHER_HALF_BASE);
-    asm!("mv sp, {0}", in(reg) sp - old_kern_start + HIGHER_HALF_BASE);
-
-    // Unmap old page table
-    // __root_page_table.unmap_gigapage(old_kern_start);
-
-    // get bss section as slice
-    let slice = core::slice::from_raw_parts_mut(
-        &__bss_start as *const _ as *mut usize,
-        subdivide_size::<usize>(
-            &__bss_end as *const _ as usize - &__bss_start as *const _ as usize,
-        ),
-    );
-
-    // zero the slice
-    for thing in slice.iter_mut() {
-        *thing = 0;
-    }
-
-    // run kinit
-    core::mem::transmute::<_, extern "C" fn(*mut u8, usize)>(
-        (crate::kinit as usize) - (old_kern_start as usize) + HIGHER_HALF_BASE,
-    )(dtb_addr, old_kern_start)
+impl MemoryRegion {
+    /// Creates a region from its start and size.
+    pub const fn new(start: usize, size: usize) -> Self {
+        Self {
+            start,
+            end: start.saturating_add(size),
+        }
+    }
+
+    /// Gets the size of the region in bytes.
+    pub const fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Checks if the region is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    /// Checks if the two regions share any bytes.
+    pub const fn overlaps(&self, other: &Self) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// Layout of physical memory: where RAM is, and which parts of it
+/// must not be handed out.
+pub struct MemoryMap {
+    ram: [MemoryRegion; MAX_RAM_REGIONS],
+    ram_len: usize,
+    reserved: [MemoryRegion; MAX_RESERVED_REGIONS],
+    reserved_len: usize,
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        Self {
+            ram: [MemoryRegion { start: 0, end: 0 }; MAX_RAM_REGIONS],
+            ram_len: 0,
+            reserved: [MemoryRegion { start: 0, end: 0 }; MAX_RESERVED_REGIONS],
+            reserved_len: 0,
+        }
+    }
+
+    /// Discovers RAM from the `/memory` nodes, and reserves the kernel image,
+    /// the device tree blob, `/reserved-memory` and the initrd.
+    pub fn from_fdt(fdt: &Fdt, kernel: MemoryRegion, dtb: MemoryRegion) -> Self {
+        let mut map = Self::new();
+        let memory_nodes = fdt
+            .all_nodes()
+            .filter(|node| node.property("device_type").and_then(|p| p.as_str()) == Some("memory"));
+        for node in memory_nodes {
+            for reg in node.reg().into_iter().flatten() {
+                map.add_ram(MemoryRegion::new(reg.address, reg.size));
+            }
+        }
+
+        map.add_reserved(kernel);
+        map.add_reserved(dtb);
+        for reservation in fdt.memory_reservations() {
+            map.add_reserved(MemoryRegion::new(reservation.address, reservation.size));
+        }
+        if let Some(reserved_memory) = fdt.find_node("/reserved-memory") {
+            // Entries with only a size are allocated by the OS, we have no use for them
+            for child in reserved_memory.children() {
+                for reg in child.reg().into_iter().flatten() {
+                    map.add_reserved(MemoryRegion::new(reg.address, reg.size));
+                }
+            }
+        }
+        if let Some(chosen) = fdt.chosen() {
+            let start = chosen
+                .property("linux,initrd-start")
+                .and_then(|p| p.as_u64());
+            let end = chosen.property("linux,initrd-end").and_then(|p| p.as_u64());
+            if let (Some(start), Some(end)) = (start, end) {
+                map.add_reserved(MemoryRegion {
+                    start: start as usize,
+                    end: end as usize,
+                });
+            }
+        }
+        map
+    }
+
+    /// Adds a region of usable RAM.
+    pub fn add_ram(&mut self, region: MemoryRegion) {
+        if region.is_empty() {
+            return;
+        }
+        if self.ram_len == MAX_RAM_REGIONS {
+            printk!("Too many RAM regions, ignoring {:x?}", region);
+            return;
+        }
+        self.ram[self.ram_len] = region;
+        self.ram_len += 1;
+    }
+
+    /// Marks a region as not to be handed out. Once the table is full, the
+    /// nearest reserved region grows to cover it instead, since reserving
+    /// too much only wastes memory while reserving too little corrupts it.
+    pub fn add_reserved(&mut self, region: MemoryRegion) {
+        if region.is_empty() {
+            return;
+        }
+        if self.reserved_len < MAX_RESERVED_REGIONS {
+            self.reserved[self.reserved_len] = region;
+            self.reserved_len += 1;
+            return;
+        }
+        // Bytes between the two regions, zero if they overlap
+        let nearest = self
+            .reserved
+            .iter_mut()
+            .min_by_key(|r| r.start.saturating_sub(region.end) + region.start.saturating_sub(r.end))
+            .expect("MAX_RESERVED_REGIONS is non-zero");
+        let merged = MemoryRegion {
+            start: nearest.start.min(region.start),
+            end: nearest.end.max(region.end),
+        };
+        printk!(
+            "Too many reserved regions, merging {:x?} into {:x?}",
+            region,
+            merged
+        );
+        *nearest = merged;
+    }
+
+    /// Gets the RAM regions.
+    pub fn ram(&self) -> &[MemoryRegion] {
+        &self.ram[..self.ram_len]
+    }
+
+    /// Gets the reserved regions.
+    pub fn reserved(&self) -> &[MemoryRegion] {
+        &self.reserved[..self.reserved_len]
+    }
+
+    /// Drops any RAM outside of the given window, e.g. because
+    /// the kernel can't address it yet.
+    pub fn clamp_ram(&mut self, window: Range<usize>) {
+        for region in self.ram[..self.ram_len].iter_mut() {
+            let clamped = MemoryRegion {
+                start: region.start.max(window.start),
+                end: region.end.min(window.end),
+            };
+            if clamped != *region {
+                printk!("Clamping RAM {:x?} -> {:x?}", region, clamped);
+                *region = clamped;
+            }
+        }
+    }
+
+    /// Finds a page aligned region of at least `size` bytes of RAM
+    /// that does not overlap anything reserved.
+    pub fn find_free(&self, size: usize) -> Option<usize> {
+        for ram in self.ram() {
+            let mut candidate = MemoryRegion::new(round_up(ram.start), size);
+            while candidate.end <= ram.end {
+                match self.reserved().iter().find(|r| r.overlaps(&candidate)) {
+                    Some(reserved) => candidate = MemoryRegion::new(round_up(reserved.end), size),
+                    None => return Some(candidate.start),
+                }
+            }
+        }
+        None
+    }
+
+    /// Prints the memory map.
+    pub fn print(&self) {
+        for ram in self.ram() {
+            printk!("RAM      0x{:016x}-0x{:016x}", ram.start, ram.end);
+        }
+        for reserved in self.reserved() {
+            printk!("Reserved 0x{:016x}-0x{:016x}", reserved.start, reserved.end);
+        }
+    }
+}
+
+/// Rounds an address up to the next page boundary.
+pub const fn round_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Gets the physical memory occupied by the kernel image, including its
+/// stack and boot heap.
+pub fn kernel_region() -> MemoryRegion {
+    link_var!(__kern_start, __kern_end);
+    let start = unsafe { &__kern_start } as *const _ as usize;
+    let end = unsafe { &__kern_end } as *const _ as usize;
+    MemoryRegion {
+        start: virt_to_phys(start),
+        end: virt_to_phys(end),
+    }
 }
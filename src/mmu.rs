@@ -1,3 +1,7 @@
+pub use crate::arch::mmu::{
+    RootTable, HIGHER_HALF_BASE, KERNEL_STACK_BASE, USER_BASE, USER_STACK_TOP,
+};
+
 /// Common interface implemented by all page tables.
 pub trait PageTable: Sized {
     /// Prints a fancy representation of this page table.
@@ -13,7 +17,66 @@ pub trait PageTable: Sized {
     fn virt_to_phys(&self, virt_addr: usize) -> usize;
 }
 
-pub const HIGHER_HALF_BASE: usize = 0xC0000000;
+/// Errors from changing a page table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// There was no memory left for a page table.
+    OutOfMemory,
+    /// The page is already mapped.
+    AlreadyMapped,
+    /// Part of the range is not mapped.
+    NotMapped,
+    /// The range covers only part of a large page.
+    SplitsLargePage,
+}
+
+/// A set of virtual to physical mappings, implemented by each architecture's
+/// page tables. Addresses and lengths are rounded out to whole pages, and
+/// ranges are mapped with the largest pages that fit.
+pub trait AddressSpace {
+    /// Maps `len` bytes of physical memory at `phys_addr` to `virt_addr`.
+    ///
+    /// # Errors
+    /// Fails if part of the range is already mapped, or if there is no memory
+    /// for a page table. Whatever was mapped before the failure stays mapped.
+    fn map(
+        &mut self,
+        virt_addr: usize,
+        phys_addr: usize,
+        len: usize,
+        permissions: Permissions,
+        attributes: Attributes,
+    ) -> Result<(), MapError>;
+
+    /// Unmaps `len` bytes at `virt_addr`. Pages that aren't mapped are skipped.
+    ///
+    /// # Errors
+    /// Fails if the range covers only part of a large page, which is left mapped
+    /// along with everything after it.
+    fn unmap(&mut self, virt_addr: usize, len: usize) -> Result<(), MapError>;
+
+    /// Changes the permissions and attributes of `len` mapped bytes at `virt_addr`.
+    ///
+    /// # Errors
+    /// Fails if part of the range isn't mapped, or if it covers only part of a
+    /// large page. Everything before that has already been changed.
+    fn protect(
+        &mut self,
+        virt_addr: usize,
+        len: usize,
+        permissions: Permissions,
+        attributes: Attributes,
+    ) -> Result<(), MapError>;
+
+    /// Looks up the physical address a virtual address maps to.
+    fn translate(&self, virt_addr: usize) -> Option<usize>;
+
+    /// Switches the current CPU to this address space.
+    ///
+    /// # Safety
+    /// The code and stack in use must be mapped the same way in this address space.
+    unsafe fn activate(&self);
+}
 
 // Hack to make the allow work
 #[allow(non_upper_case_globals)]
@@ -25,11 +88,23 @@ mod permissions_inner {
             const Write = 1 << 1;
             const Execute = 1 << 2;
             const RW = Self::Read.bits | Self::Write.bits;
+            const RX = Self::Read.bits | Self::Execute.bits;
             const WX = Self::Write.bits | Self::Execute.bits;
-            // RX doesn't make sense
             const RWX = Self::Read.bits | Self::Write.bits | Self::Execute.bits;
         }
     }
+
+    bitflags::bitflags! {
+        /// Abstract representation of page attributes other than permissions.
+        pub struct Attributes: u8 {
+            /// Accessible from user mode.
+            const User = 1 << 0;
+            /// Present in every address space, so not flushed on a switch.
+            const Global = 1 << 1;
+            /// Device memory, which must not be cached or reordered.
+            const Device = 1 << 2;
+        }
+    }
 }
 
-pub use permissions_inner::Permissions;
+pub use permissions_inner::{Attributes, Permissions};
@@ -0,0 +1,87 @@
+//! Out of memory handling.
+//!
+//! When an allocation fails we first ask the allocators to give back memory
+//! they are holding on to, then kill the process using the most memory.
+//! If neither frees anything the allocation fails, which panics in
+//! [alloc_error] unless the caller used a fallible path.
+
+use core::alloc::Layout;
+
+use crate::{cpu, kmalloc, printk, process::Process, sched};
+
+/// Functions that free cached memory, returning the number of pages freed.
+static RECLAIMERS: &[fn() -> usize] = &[kmalloc::reclaim];
+
+/// Tries to free memory after an allocation of `size` bytes failed.
+/// Returns true if anything was freed, in which case the allocation should be retried.
+pub fn out_of_memory(size: usize) -> bool {
+    printk!("Out of memory allocating {} bytes", size);
+    let reclaimed: usize = RECLAIMERS.iter().map(|reclaim| reclaim()).sum();
+    if reclaimed > 0 {
+        printk!("Reclaimed {} pages", reclaimed);
+        return true;
+    }
+    match kill_victim() {
+        Some(pid) => {
+            printk!("Killed process {} to free memory", pid);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Runs an allocation, running the OOM handler and retrying for as long as it frees memory.
+pub fn retry<T>(size: usize, mut allocate: impl FnMut() -> Option<T>) -> Option<T> {
+    loop {
+        if let Some(allocation) = allocate() {
+            return Some(allocation);
+        }
+        if !out_of_memory(size) {
+            return None;
+        }
+    }
+}
+
+/// Kills the process using the most memory, returning its pid.
+fn kill_victim() -> Option<u64> {
+    // The scheduler takes the process table in interrupts
+    let victim = cpu::without_interrupts(|| {
+        // The allocation may have come from code holding the process table,
+        // or the scheduler
+        let current = sched::try_current_slot()?;
+        let processes = crate::PROCESSES.try_lock()?;
+        // The running task may be using its memory to allocate
+        let victim = processes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != current)
+            .flat_map(|(_, slot)| slot)
+            .filter(|p| p.memory_usage() > 0)
+            .max_by_key(|p| p.memory_usage())
+            .map(Process::pid);
+        victim
+    })?;
+    // Goes through the scheduler so the victim is dequeued and joiners wake
+    if sched::try_kill(victim) {
+        Some(victim)
+    } else {
+        None
+    }
+}
+
+/// Called when an infallible allocation fails, after the OOM handler gave up.
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let (free, total) = kmalloc::with_page_allocator(|pages| {
+        // Whatever holds the memory is still allocated
+        pages.print_leak_report();
+        (pages.free(), pages.total())
+    });
+    panic!(
+        "Out of memory allocating {} bytes aligned to {} ({} of {} pages free)",
+        layout.size(),
+        layout.align(),
+        free,
+        total
+    );
+}
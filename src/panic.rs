@@ -1,11 +1,18 @@
-use crate::{cpu, drivers, println2};
+use crate::{bsp, cpu, println2, STDOUT};
 use core::panic::PanicInfo;
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    // let stdout = unsafe { STDOUT.get_mut() };
-    let mut uart = drivers::known_good_uart();
+    // Don't lock, whoever panicked might be holding it
+    let stdout = unsafe { STDOUT.get_mut() };
+    if stdout.is_none() {
+        *stdout = Some(bsp::fallback_console());
+    }
+    // Interrupts may never come again, so write to the device directly
+    if let Some(console) = stdout {
+        console.detach_buffers();
+    }
 
-    println2!(Some(&mut uart), "[!] Kernel Panic: {}", _info);
+    println2!(stdout, "[!] Kernel Panic: {}", _info);
     cpu::wait_forever()
 }
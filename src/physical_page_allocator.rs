@@ -1,18 +1,17 @@
 pub use crate::bsp::{HEAP_SIZE, PAGE_SIZE};
-use crate::{link_var, print, printk, println};
-use core::alloc::GlobalAlloc;
+use crate::{
+    arch::mmu::{phys_to_virt, virt_to_phys},
+    link_var,
+    memory::{round_up, MemoryMap, MemoryRegion},
+    print, printk, println,
+};
+#[cfg(feature = "alloc_debug")]
+use core::panic::Location;
 
 link_var!(__heap_start);
 
-// const_evalutable_checked cannot evaluate this expression inline yet.
-const fn pages_subdivide(size: usize) -> usize {
-    // requires const_panic
-    assert!(
-        size % PAGE_SIZE == 0,
-        "heap size must be a multiple of page size"
-    );
-    size / PAGE_SIZE
-}
+/// Number of block sizes, from one page up to `1 << (MAX_ORDER - 1)` pages.
+pub const MAX_ORDER: usize = 16;
 
 /// Page flags
 #[derive(Debug, Clone, Copy)]
@@ -20,6 +19,8 @@ const fn pages_subdivide(size: usize) -> usize {
 enum PageFlags {
     Free = 1 << 0,
     Taken = 1 << 1,
+    /// Not RAM, or RAM that must never be handed out.
+    Reserved = 1 << 2,
 }
 
 impl PageFlags {
@@ -28,119 +29,324 @@ impl PageFlags {
     }
 }
 
+/// Mask of the [PageFlags] bits of a descriptor.
+const FLAGS_MASK: u8 = 0b111;
+/// Set on the first page of a free block, whose order is stored above it.
+const HEAD: u8 = 1 << 3;
+const ORDER_SHIFT: u8 = 4;
+
+/// Byte free pages are filled with in debug builds.
+#[cfg(feature = "alloc_debug")]
+const POISON: u8 = 0x6b;
+
+/// Free list links, stored in the first page of each free block.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
 /// Physical page allocator weeee
-pub struct PhysicalPageAllocator<const PAGES: usize>
-where
-    [u8; pages_subdivide(PAGES)]: Sized,
-{
+///
+/// A binary buddy allocator. Free memory is kept as naturally aligned blocks
+/// of `1 << order` pages on one free list per order, and freed blocks merge
+/// with their buddy whenever it is free too. Allocations are exact: the
+/// unused tail of a rounded up block goes straight back to the free lists.
+///
+/// The kernel's instance lives behind the allocator lock in [kmalloc].
+///
+/// [kmalloc]: crate::kmalloc
+pub struct PhysicalPageAllocator {
+    /// Physical address of the first page tracked.
     start: usize,
-    descriptors: [u8; pages_subdivide(PAGES)],
+    /// One descriptor per page, stored inside the pool itself.
+    descriptors: *mut u8,
+    /// Number of pages tracked.
+    pages: usize,
+    /// Heads of the free lists, by order.
+    free_lists: [*mut FreeBlock; MAX_ORDER],
+    /// Number of free pages.
+    free_pages: usize,
+    /// Number of allocated pages.
+    used_pages: usize,
+    /// Where each allocation was made, set on its first page.
+    #[cfg(feature = "alloc_debug")]
+    callers: *mut Option<&'static Location<'static>>,
 }
 
-// TODO: write an actual allocator that's efficient
-// this literally allocates a whole page for like 7 bytes
-#[global_allocator]
-pub static mut ALLOCATOR: PhysicalPageAllocator<HEAP_SIZE> = PhysicalPageAllocator::new();
-
-impl<const PAGES: usize> PhysicalPageAllocator<PAGES>
-where
-    [u8; pages_subdivide(PAGES)]: Sized,
-{
+impl PhysicalPageAllocator {
     pub const fn new() -> Self {
         Self {
             start: 0,
-            descriptors: [PageFlags::Free.val(); pages_subdivide(PAGES)],
+            descriptors: core::ptr::null_mut(),
+            pages: 0,
+            free_lists: [core::ptr::null_mut(); MAX_ORDER],
+            free_pages: 0,
+            used_pages: 0,
+            #[cfg(feature = "alloc_debug")]
+            callers: core::ptr::null_mut(),
         }
     }
 
-    /// Initializes based on _heap_start global constant.
+    /// Initializes with the boot heap after the kernel image.
+    /// Used when there is no memory map to go by.
     ///
     /// # Safety
     /// Only safe to call from a single allocator, otherwise multiple allocators
-    /// will have the same base address.
+    /// will hand out the same pages.
     pub unsafe fn default_init(&mut self) {
-        // mask out bottom bits to align to page
-        let val = {
-            let heap_start = &__heap_start as *const _ as usize;
-            if heap_start % PAGE_SIZE == 0 {
-                heap_start
-            } else {
-                let new_val = (heap_start + PAGE_SIZE) & !(PAGE_SIZE - 1);
-                printk!(
-                    "Original heap_start is not page aligned, rounding 0x{:x} -> 0x{:x}",
-                    heap_start,
-                    new_val
-                );
-                new_val
-            }
-        };
-        self.init(val);
+        let heap_start = virt_to_phys(&__heap_start as *const _ as usize);
+        let mut map = MemoryMap::new();
+        map.add_ram(MemoryRegion::new(heap_start, HEAP_SIZE));
+        self.init(&map);
     }
 
-    /// Initializes with the given start value.
+    /// Initializes with all of the usable RAM in the given memory map.
+    /// The page descriptors are stored in the first free pages.
     ///
     /// # Safety
-    /// Only safe to call if the start address is valid.
-    pub unsafe fn init(&mut self, start: usize) {
+    /// Only safe to call once, and the memory map must be accurate.
+    pub unsafe fn init(&mut self, map: &MemoryMap) {
+        let start = map
+            .ram()
+            .iter()
+            .map(|r| round_up(r.start))
+            .min()
+            .expect("No RAM to allocate from!");
+        let end = map
+            .ram()
+            .iter()
+            .map(|r| r.end & !(PAGE_SIZE - 1))
+            .max()
+            .unwrap();
+        let pages = (end - start) / PAGE_SIZE;
+        let metadata = map
+            .find_free(metadata_size(pages))
+            .expect("No room for page descriptors");
         self.start = start;
+        self.descriptors = phys_to_virt(metadata) as *mut u8;
+        self.pages = pages;
+        #[cfg(feature = "alloc_debug")]
+        {
+            self.callers = self.descriptors.add(round_up_to(pages, 8)) as *mut _;
+            core::ptr::write_bytes(self.callers, 0, pages);
+        }
+
+        // Everything is reserved unless it is RAM and not reserved in the map.
+        let descriptors = MemoryRegion::new(metadata, metadata_size(pages));
+        for (region, flags) in core::iter::once((MemoryRegion { start, end }, PageFlags::Reserved))
+            .chain(map.ram().iter().map(|r| (*r, PageFlags::Free)))
+            .chain(map.reserved().iter().map(|r| (*r, PageFlags::Reserved)))
+            .chain(core::iter::once((descriptors, PageFlags::Reserved)))
+        {
+            self.mark(region, flags);
+        }
+
+        // Hand every run of free pages to the free lists
+        let mut index = 0;
+        while index < pages {
+            if self.descriptors()[index] != PageFlags::Free.val() {
+                index += 1;
+                continue;
+            }
+            let run = self.descriptors()[index..]
+                .iter()
+                .take_while(|&&d| d == PageFlags::Free.val())
+                .count();
+            self.free_range(index, run);
+            index += run;
+        }
+        printk!(
+            "Page allocator managing 0x{:x}-0x{:x}, {} of {} pages usable",
+            start,
+            end,
+            self.free(),
+            self.total()
+        );
+    }
+
+    /// Sets the flags of every page touched by the given region.
+    fn mark(&mut self, region: MemoryRegion, flags: PageFlags) {
+        // Only free whole pages, but reserve any page that is partially covered
+        let (first, last) = match flags {
+            PageFlags::Free => (round_up(region.start), region.end & !(PAGE_SIZE - 1)),
+            _ => (region.start & !(PAGE_SIZE - 1), round_up(region.end)),
+        };
+        let end = self.start + self.pages * PAGE_SIZE;
+        let first = first.max(self.start).min(end);
+        let last = last.max(first).min(end);
+        let range = (first - self.start) / PAGE_SIZE..(last - self.start) / PAGE_SIZE;
+        for descriptor in self.descriptors_mut()[range].iter_mut() {
+            *descriptor = flags.val();
+        }
+    }
+
+    fn descriptors(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.descriptors, self.pages) }
     }
 
+    fn descriptors_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.descriptors, self.pages) }
+    }
+
+    /// Gets the physical address of the first page tracked.
     pub const fn get_base(&self) -> usize {
         self.start
     }
 
+    /// Checks if the allocator has been given memory to manage.
+    pub const fn is_initialized(&self) -> bool {
+        self.pages != 0
+    }
+
     fn assert_init(&self) {
-        if self.start == 0 {
+        if self.pages == 0 {
             panic!("allocator is uninitialized!");
         }
     }
 
+    /// Converts a page index to its absolute page frame number.
+    /// Buddies are found with frame numbers so blocks stay physically aligned.
+    const fn pfn(&self, index: usize) -> usize {
+        self.start / PAGE_SIZE + index
+    }
+
+    /// Gets the free list node stored in the given page.
+    fn block(&self, index: usize) -> *mut FreeBlock {
+        phys_to_virt(self.start + index * PAGE_SIZE) as *mut FreeBlock
+    }
+
+    /// Converts a free list node back to its page index.
+    fn block_index(&self, block: *mut FreeBlock) -> usize {
+        (virt_to_phys(block as usize) - self.start) / PAGE_SIZE
+    }
+
+    /// Checks if the page at the given index starts a free block of the given order.
+    fn is_free_head(&self, index: usize, order: usize) -> bool {
+        self.descriptors()[index] == PageFlags::Free.val() | HEAD | (order as u8) << ORDER_SHIFT
+    }
+
+    /// Adds the block at the given index to its free list.
+    fn push_block(&mut self, index: usize, order: usize) {
+        self.descriptors_mut()[index] = PageFlags::Free.val() | HEAD | (order as u8) << ORDER_SHIFT;
+        let block = self.block(index);
+        let next = self.free_lists[order];
+        unsafe {
+            block.write(FreeBlock {
+                next,
+                prev: core::ptr::null_mut(),
+            });
+            if let Some(next) = next.as_mut() {
+                next.prev = block;
+            }
+        }
+        self.free_lists[order] = block;
+    }
+
+    /// Removes the block at the given index from its free list.
+    fn unlink_block(&mut self, index: usize, order: usize) {
+        self.descriptors_mut()[index] = PageFlags::Free.val();
+        let block = self.block(index);
+        unsafe {
+            let FreeBlock { next, prev } = block.read();
+            match prev.as_mut() {
+                Some(prev) => prev.next = next,
+                None => self.free_lists[order] = next,
+            }
+            if let Some(next) = next.as_mut() {
+                next.prev = prev;
+            }
+            #[cfg(feature = "alloc_debug")]
+            core::ptr::write_bytes(block as *mut u8, POISON, core::mem::size_of::<FreeBlock>());
+        }
+    }
+
+    /// Takes the first block off the free list of the given order.
+    fn pop_block(&mut self, order: usize) -> Option<usize> {
+        let block = self.free_lists[order];
+        if block.is_null() {
+            return None;
+        }
+        let index = self.block_index(block);
+        self.unlink_block(index, order);
+        Some(index)
+    }
+
+    /// Frees a block, merging it with its buddy for as long as possible.
+    fn free_block(&mut self, mut index: usize, mut order: usize) {
+        for descriptor in self.descriptors_mut()[index..index + (1 << order)].iter_mut() {
+            *descriptor = PageFlags::Free.val();
+        }
+        #[cfg(feature = "alloc_debug")]
+        unsafe {
+            core::ptr::write_bytes(self.block(index) as *mut u8, POISON, PAGE_SIZE << order);
+        }
+        while order < MAX_ORDER - 1 {
+            let buddy_pfn = self.pfn(index) ^ (1 << order);
+            let buddy = match buddy_pfn.checked_sub(self.pfn(0)) {
+                Some(buddy) if buddy + (1 << order) <= self.pages => buddy,
+                _ => break,
+            };
+            if !self.is_free_head(buddy, order) {
+                break;
+            }
+            self.unlink_block(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push_block(index, order);
+    }
+
+    /// Frees a range of pages by splitting it into the largest aligned blocks.
+    fn free_range(&mut self, mut index: usize, mut pages: usize) {
+        self.free_pages += pages;
+        while pages > 0 {
+            let order = (self.pfn(index).trailing_zeros() as usize)
+                .min(log2(pages))
+                .min(MAX_ORDER - 1);
+            self.free_block(index, order);
+            index += 1 << order;
+            pages -= 1 << order;
+        }
+    }
+
     /// Try to allocate the contiguous region of pages, returning the pointer to the region if possible.
+    /// The region is aligned to its size rounded up to a power of two.
+    #[track_caller]
     pub fn try_allocate(&mut self, size: usize) -> Option<*mut u8> {
         self.assert_init();
         let pages = size_to_pages(size);
         assert!(pages > 0, "Can't make an empty allocation");
-        let mut begin_index = 0;
-        let mut matching = 0;
-        let begin_index: Option<usize> = 'block: {
-            for (i, entry) in self.descriptors.iter().enumerate() {
-                // printk!(
-                //     "Trying entry #{} begin: {} matching: {} needed: {}",
-                //     i,
-                //     begin_index,
-                //     matching,
-                //     pages
-                // );
-                let flags = *entry;
-                if flags & PageFlags::Free.val() != 0 {
-                    matching += 1;
-                } else {
-                    matching = 0;
-                    begin_index = i + 1;
-                }
-                if matching >= pages {
-                    // note: ra marks this as an error but it's actually fine
-                    // (see rust-analyzer#4747)
-                    // due to #![feature(label_break_value)]
-                    //printk!("Success!");
-                    break 'block Some(begin_index);
-                }
-            }
-            None
-        };
-        if let Some(begin_index) = begin_index {
-            //printk!("begin_index is found");
-            // Mark all descriptors as taken and return value.
-            for descriptor in self.descriptors[begin_index..=begin_index + pages].iter_mut() {
-                *descriptor = (*descriptor) & !PageFlags::Free.val() | PageFlags::Taken.val();
-            }
-            Some((self.start + (begin_index * PAGE_SIZE)) as _)
-        } else {
-            None
+        let order = log2(pages.next_power_of_two());
+        if order >= MAX_ORDER {
+            return None;
+        }
+        let (index, mut found) =
+            (order..MAX_ORDER).find_map(|o| self.pop_block(o).map(|index| (index, o)))?;
+        // Split off upper halves until the block is just big enough
+        while found > order {
+            found -= 1;
+            self.push_block(index + (1 << found), found);
         }
+        for descriptor in self.descriptors_mut()[index..index + pages].iter_mut() {
+            *descriptor = PageFlags::Taken.val();
+        }
+        self.free_pages -= 1 << order;
+        self.used_pages += pages;
+        // Give back the tail we don't need
+        let tail = (1 << order) - pages;
+        if tail > 0 {
+            self.free_range(index + pages, tail);
+        }
+        #[cfg(feature = "alloc_debug")]
+        {
+            self.check_poison(index, pages);
+            self.callers_mut()[index] = Some(Location::caller());
+        }
+        Some(phys_to_virt(self.start + (index * PAGE_SIZE)) as _)
     }
 
     /// Same as try_allocate, but also zeroes the range
+    #[track_caller]
     pub fn try_zallocate(&mut self, size: usize) -> Option<*mut u8> {
         let pages = match self.try_allocate(size) {
             Some(pointer) => pointer,
@@ -156,7 +362,7 @@ where
     /// Prints the page allocation table as a 32xN square
     pub fn print_page_allocation_table(&self) {
         self.assert_init();
-        for chunk in self.descriptors.chunks(32) {
+        for chunk in self.descriptors().chunks(32) {
             for descriptor in chunk {
                 if (*descriptor) & PageFlags::Free.val() != 0 {
                     print!(".");
@@ -164,39 +370,211 @@ where
                 if (*descriptor) & PageFlags::Taken.val() != 0 {
                     print!("X");
                 }
+                if (*descriptor) & PageFlags::Reserved.val() != 0 {
+                    print!("R");
+                }
             }
             println!();
         }
         println!();
-        println!(". = free, X = taken");
+        println!(". = free, X = taken, R = reserved");
+        for order in 0..MAX_ORDER {
+            let mut count = 0;
+            let mut block = self.free_lists[order];
+            while let Some(b) = unsafe { block.as_ref() } {
+                count += 1;
+                block = b.next;
+            }
+            if count > 0 {
+                println!("order {:>2}: {} free blocks", order, count);
+            }
+        }
     }
 
     /// Deallocates the given region of pages.
+    #[track_caller]
     pub fn deallocate(&mut self, addr: *mut u8, size: usize) {
         self.assert_init();
-        let addr = addr as usize - self.start;
+        #[cfg(feature = "alloc_debug")]
+        if !self.check_free(addr, size) {
+            // Already reported, and freeing would corrupt the free lists
+            return;
+        }
+        let addr = virt_to_phys(addr as usize) - self.start;
         let pages = size_to_pages(size);
         let begin_index = addr / PAGE_SIZE;
-        for descriptor in self.descriptors[begin_index..=begin_index + pages].iter_mut() {
-            *descriptor = (*descriptor) & !PageFlags::Taken.val() | PageFlags::Free.val();
+        #[cfg(feature = "alloc_debug")]
+        {
+            self.callers_mut()[begin_index] = None;
         }
+        assert!(
+            self.descriptors()[begin_index..begin_index + pages]
+                .iter()
+                .all(|&d| d & FLAGS_MASK == PageFlags::Taken.val()),
+            "Freeing pages that were not allocated"
+        );
+        self.used_pages -= pages;
+        self.free_range(begin_index, pages);
     }
 
     /// Gets the number of used pages.
     pub fn used(&self) -> usize {
         self.assert_init();
-        self.descriptors
-            .iter()
-            .filter(|&&d| d & PageFlags::Taken.val() != 0)
-            .count()
+        self.used_pages
+    }
+
+    /// Gets the number of free pages.
+    pub fn free(&self) -> usize {
+        self.assert_init();
+        self.free_pages
     }
 
     /// Gets the total number of pages.
     pub const fn total(&self) -> usize {
-        self.descriptors.len()
+        self.pages
+    }
+
+    /// Prints every allocation still live, grouped by where it was made.
+    pub fn print_leak_report(&self) {
+        self.assert_init();
+        #[cfg(feature = "alloc_debug")]
+        {
+            // (caller, allocations, pages)
+            let mut sites: [Option<(&Location, usize, usize)>; 32] = [None; 32];
+            let mut untracked = 0;
+            let mut index = 0;
+            while index < self.pages {
+                let caller = match self.callers()[index] {
+                    Some(caller) => caller,
+                    None => {
+                        index += 1;
+                        continue;
+                    }
+                };
+                // The allocation runs until the next allocation or non-taken page
+                let pages = 1 + self.descriptors()[index + 1..]
+                    .iter()
+                    .zip(&self.callers()[index + 1..])
+                    .take_while(|(d, c)| **d == PageFlags::Taken.val() && c.is_none())
+                    .count();
+                index += pages;
+                let site = sites
+                    .iter_mut()
+                    .find(|s| s.map_or(true, |(c, _, _)| c == caller));
+                match site {
+                    Some(Some((_, allocations, total))) => {
+                        *allocations += 1;
+                        *total += pages;
+                    }
+                    Some(site) => *site = Some((caller, 1, pages)),
+                    None => untracked += pages,
+                }
+            }
+            for (caller, allocations, pages) in sites.iter().flatten() {
+                println!(
+                    "{:>6} pages in {:>5} allocations from {}",
+                    pages, allocations, caller
+                );
+            }
+            if untracked > 0 {
+                println!("{:>6} pages from other callers", untracked);
+            }
+            println!("{} pages in use", self.used_pages);
+        }
+        #[cfg(not(feature = "alloc_debug"))]
+        println!("Build with the alloc_debug feature to track allocations");
     }
 }
 
+#[cfg(feature = "alloc_debug")]
+impl PhysicalPageAllocator {
+    fn callers(&self) -> &[Option<&'static Location<'static>>] {
+        unsafe { core::slice::from_raw_parts(self.callers, self.pages) }
+    }
+
+    fn callers_mut(&mut self) -> &mut [Option<&'static Location<'static>>] {
+        unsafe { core::slice::from_raw_parts_mut(self.callers, self.pages) }
+    }
+
+    /// Reports pages that were written to while they were free.
+    fn check_poison(&self, index: usize, pages: usize) {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(self.block(index) as *const u8, pages * PAGE_SIZE)
+        };
+        for (page, contents) in bytes.chunks(PAGE_SIZE).enumerate() {
+            if let Some(offset) = contents.iter().position(|&b| b != POISON) {
+                printk!(
+                    "alloc_debug: page 0x{:x} was written after being freed (offset 0x{:x} is 0x{:02x})",
+                    self.start + (index + page) * PAGE_SIZE,
+                    offset,
+                    contents[offset]
+                );
+            }
+        }
+    }
+
+    /// Checks that a region can be freed, reporting it if not.
+    #[track_caller]
+    fn check_free(&self, addr: *mut u8, size: usize) -> bool {
+        let caller = Location::caller();
+        let phys = virt_to_phys(addr as usize);
+        let pages = size_to_pages(size);
+        let problem = if phys % PAGE_SIZE != 0 {
+            "misaligned address"
+        } else if phys < self.start
+            || phys + pages * PAGE_SIZE > self.start + self.pages * PAGE_SIZE
+        {
+            "address outside the page pool"
+        } else {
+            let index = (phys - self.start) / PAGE_SIZE;
+            let descriptors = &self.descriptors()[index..index + pages];
+            if descriptors
+                .iter()
+                .all(|&d| d & FLAGS_MASK == PageFlags::Free.val())
+            {
+                "double free"
+            } else if descriptors
+                .iter()
+                .any(|&d| d & FLAGS_MASK == PageFlags::Reserved.val())
+            {
+                "reserved pages"
+            } else if descriptors
+                .iter()
+                .any(|&d| d & FLAGS_MASK != PageFlags::Taken.val())
+            {
+                "pages that were never allocated"
+            } else if self.callers()[index].is_none() {
+                "address inside an allocation"
+            } else {
+                return true;
+            }
+        };
+        printk!(
+            "alloc_debug: bad free of {:p} ({} bytes) at {}: {}",
+            addr,
+            size,
+            caller,
+            problem
+        );
+        false
+    }
+}
+
+/// Gets the number of bytes of allocator metadata needed for the given number of pages.
+/// Debug builds keep the allocation callers after the descriptors.
+const fn metadata_size(pages: usize) -> usize {
+    if cfg!(feature = "alloc_debug") {
+        round_up_to(pages, 8) + pages * core::mem::size_of::<Option<&core::panic::Location>>()
+    } else {
+        pages
+    }
+}
+
+/// Rounds up to a multiple of a power of two.
+const fn round_up_to(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
 const fn size_to_pages(size: usize) -> usize {
     let u = size / PAGE_SIZE;
     if size % PAGE_SIZE > 0 {
@@ -206,19 +584,7 @@ const fn size_to_pages(size: usize) -> usize {
     }
 }
 
-unsafe impl<const PAGES: usize> GlobalAlloc for PhysicalPageAllocator<PAGES>
-where
-    [u8; pages_subdivide(PAGES)]: Sized,
-{
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        //printk!("allocating size = {}", layout.size());
-        ALLOCATOR
-            .try_allocate(layout.size())
-            .expect("Failed to make allocation for global allocator") as _
-    }
-
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        //printk!("addr = {:?} size = {}", ptr, layout.size());
-        ALLOCATOR.deallocate(ptr as _, layout.size());
-    }
+/// Floor of log2, for non-zero values.
+const fn log2(value: usize) -> usize {
+    core::mem::size_of::<usize>() * 8 - 1 - value.leading_zeros() as usize
 }
@@ -1,3 +1,16 @@
+use crate::cmdline::Param;
+
+/// Console log level. Kernel messages are only printed when it is above [MESSAGE_LEVEL].
+pub static LOGLEVEL: Param<usize> = Param::new("loglevel", 7);
+
+/// Log level of messages printed with [printk].
+pub const MESSAGE_LEVEL: usize = 6;
+
+/// Checks if [printk] messages should be printed.
+pub fn printk_enabled() -> bool {
+    LOGLEVEL.get() > MESSAGE_LEVEL
+}
+
 /// See [std::print].
 #[macro_export]
 macro_rules! print {
@@ -27,22 +40,28 @@ macro_rules! println {
 #[macro_export]
 macro_rules! printk {
 	() => {
-		use crate::time::TimeCounter;
-		let timestamp = crate::time::time_counter().uptime();
-		let timestamp_us = timestamp.subsec_micros();
-		$crate::println!("[{:>5}.{:03}{:03}]", timestamp.as_secs(), timestamp_us / 1000, timestamp_us % 1000)
+		if $crate::print::printk_enabled() {
+			use crate::time::TimeCounter;
+			let timestamp = crate::time::time_counter().uptime();
+			let timestamp_us = timestamp.subsec_micros();
+			$crate::println!("[{:>5}.{:03}{:03}]", timestamp.as_secs(), timestamp_us / 1000, timestamp_us % 1000)
+		}
 	};
 	($fmt:expr) => ({
-		use crate::time::TimeCounter;
-		let timestamp = crate::time::time_counter().uptime();
-		let timestamp_us = timestamp.subsec_micros();
-		$crate::println!(concat!("[{:>5}.{:03}{:03}] ", $fmt), timestamp.as_secs(), timestamp_us / 1000, timestamp_us % 1000)
+		if $crate::print::printk_enabled() {
+			use crate::time::TimeCounter;
+			let timestamp = crate::time::time_counter().uptime();
+			let timestamp_us = timestamp.subsec_micros();
+			$crate::println!(concat!("[{:>5}.{:03}{:03}] ", $fmt), timestamp.as_secs(), timestamp_us / 1000, timestamp_us % 1000)
+		}
 	});
 	($fmt:expr, $($args:tt)+) => ({
-		use crate::time::TimeCounter;
-		let timestamp = crate::time::time_counter().uptime();
-		let timestamp_us = timestamp.subsec_micros();
-		$crate::println!(concat!("[{:>5}.{:03}{:03}] ", $fmt), timestamp.as_secs(), timestamp_us / 1000, timestamp_us % 1000, $($args)+)
+		if $crate::print::printk_enabled() {
+			use crate::time::TimeCounter;
+			let timestamp = crate::time::time_counter().uptime();
+			let timestamp_us = timestamp.subsec_micros();
+			$crate::println!(concat!("[{:>5}.{:03}{:03}] ", $fmt), timestamp.as_secs(), timestamp_us / 1000, timestamp_us % 1000, $($args)+)
+		}
 	});
 }
 
@@ -1,11 +1,315 @@
-use crate::arch::{Fregs, Regs};
+use crate::{
+    arch::{
+        default_fp_state, default_regs,
+        mmu::{phys_to_virt, virt_to_phys},
+        user, FpState, Regs,
+    },
+    bsp::PAGE_SIZE,
+    cmdline::StrParam,
+    cpu,
+    kmalloc::with_page_allocator,
+    kthread::KernelStack,
+    mmu::{AddressSpace, Attributes, Permissions, RootTable, USER_BASE, USER_STACK_TOP},
+    printk,
+    sched::{self, SpawnError},
+};
+
+/// Path of the first user program.
+pub static INIT: StrParam = StrParam::new("init");
+
+/// Number of slots in the process table.
+pub const MAX_PROCESSES: usize = 16;
+
+/// Size of the stack of user processes.
+pub const USER_STACK_SIZE: usize = 4 * PAGE_SIZE;
+
+/// Starts the first user program. There is no filesystem to load [INIT]
+/// from yet, so this is always the one built into the kernel.
+pub fn start_init() {
+    if let Some(path) = INIT.get() {
+        printk!(
+            "Can't load {} without a filesystem, starting the built-in init",
+            path
+        );
+    }
+    match sched::spawn_user("init", user::init_image()) {
+        Ok(pid) => printk!("Started init as process {}", pid),
+        Err(e) => printk!("Failed to start init: {:?}", e),
+    }
+}
+
+/// Scheduling state of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Waiting in the run queue.
+    Runnable,
+    /// Currently running.
+    Running,
+    /// Waiting to be woken up.
+    Blocked,
+    /// Finished, waiting to be reaped.
+    Exited,
+}
 
 /// Represents a scheduled process
 pub struct Process {
     regs: Regs,
-    fregs: Fregs,
+    /// FP registers, only up to date while another task owns them.
+    fp: FpState,
+    /// Where execution resumes.
+    pc: usize,
+    state: State,
     pid: u64,
+    /// Name, for diagnostics.
+    name: &'static str,
     // Currently only allow 8 disjoint mappings...
     // virt_base, size (bytes), phys_base
     pages: [(usize, usize, usize); 8],
+    /// Kernel stack owned by this process.
+    stack: Option<KernelStack>,
+    /// Page tables of a user process. Kernel tasks run in the kernel's.
+    address_space: Option<RootTable>,
+    /// Tick at which a sleeping process is woken up.
+    wake_at: Option<u64>,
+    /// Pid of the task waiting for this one to exit.
+    joiner: Option<u64>,
+}
+
+impl Process {
+    /// Creates a process for code that is already running, whose registers
+    /// are saved the first time it is switched away from.
+    pub const fn running(pid: u64, name: &'static str) -> Self {
+        Self {
+            regs: default_regs(),
+            fp: default_fp_state(),
+            pc: 0,
+            state: State::Running,
+            pid,
+            name,
+            pages: [(0, 0, 0); 8],
+            stack: None,
+            address_space: None,
+            wake_at: None,
+            joiner: None,
+        }
+    }
+
+    /// Creates a runnable kernel task starting at `entry` with the given
+    /// registers. `stack` is freed along with the process.
+    pub const fn kernel(
+        pid: u64,
+        name: &'static str,
+        entry: usize,
+        regs: Regs,
+        stack: KernelStack,
+    ) -> Self {
+        Self {
+            regs,
+            fp: default_fp_state(),
+            pc: entry,
+            state: State::Runnable,
+            pid,
+            name,
+            pages: [(0, 0, 0); 8],
+            stack: Some(stack),
+            address_space: None,
+            wake_at: None,
+            joiner: None,
+        }
+    }
+
+    /// Creates a runnable user process in a new address space, running
+    /// `image` loaded at [USER_BASE] with its stack below [USER_STACK_TOP].
+    /// Its traps run on `stack`, which is freed along with the process.
+    ///
+    /// # Errors
+    /// Fails if there is no memory for the process. Nothing is leaked.
+    pub fn user(
+        pid: u64,
+        name: &'static str,
+        image: &[u8],
+        stack: KernelStack,
+    ) -> Result<Self, SpawnError> {
+        let address_space = RootTable::new().map_err(|_| SpawnError::OutOfMemory)?;
+        let mut process = Self {
+            regs: cpu::user_regs(USER_STACK_TOP),
+            fp: default_fp_state(),
+            pc: USER_BASE,
+            state: State::Runnable,
+            pid,
+            name,
+            pages: [(0, 0, 0); 8],
+            stack: Some(stack),
+            address_space: Some(address_space),
+            wake_at: None,
+            joiner: None,
+        };
+        let loaded = process
+            .map_user(USER_BASE, image.len(), Permissions::RX)
+            .map(|memory| {
+                unsafe { core::ptr::copy_nonoverlapping(image.as_ptr(), memory, image.len()) };
+                cpu::sync_icache(memory as usize, image.len());
+            })
+            .and_then(|()| {
+                let stack_base = USER_STACK_TOP - USER_STACK_SIZE;
+                process.map_user(stack_base, USER_STACK_SIZE, Permissions::RW)
+            });
+        if loaded.is_err() {
+            process.release_memory();
+            process.release_address_space();
+            return Err(SpawnError::OutOfMemory);
+        }
+        Ok(process)
+    }
+
+    /// Allocates zeroed memory and maps it into the address space of the
+    /// process at `virt_addr`, returning where the kernel can reach it.
+    fn map_user(
+        &mut self,
+        virt_addr: usize,
+        len: usize,
+        permissions: Permissions,
+    ) -> Result<*mut u8, SpawnError> {
+        let size = (len.max(1) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let slot = self
+            .pages
+            .iter()
+            .position(|&(_, size, _)| size == 0)
+            .ok_or(SpawnError::OutOfMemory)?;
+        let address_space = self.address_space.as_mut().ok_or(SpawnError::OutOfMemory)?;
+        let memory = with_page_allocator(|pages| pages.try_zallocate(size))
+            .ok_or(SpawnError::OutOfMemory)?;
+        let phys_base = virt_to_phys(memory as usize);
+        if address_space
+            .map(virt_addr, phys_base, size, permissions, Attributes::User)
+            .is_err()
+        {
+            with_page_allocator(|pages| pages.deallocate(memory, size));
+            return Err(SpawnError::OutOfMemory);
+        }
+        self.pages[slot] = (virt_addr, size, phys_base);
+        Ok(memory)
+    }
+
+    /// Checks if this is a user process.
+    pub const fn is_user(&self) -> bool {
+        self.address_space.is_some()
+    }
+
+    /// Gets `len` bytes of user memory at `addr`, if they are all in one of
+    /// the mappings of the process.
+    pub fn user_bytes(&self, addr: usize, len: usize) -> Option<&[u8]> {
+        let end = addr.checked_add(len)?;
+        let &(virt_base, _, phys_base) = self.pages.iter().find(|&&(virt_base, size, _)| {
+            size > 0 && virt_base <= addr && end <= virt_base + size
+        })?;
+        let memory = phys_to_virt(phys_base + (addr - virt_base)) as *const u8;
+        Some(unsafe { core::slice::from_raw_parts(memory, len) })
+    }
+
+    /// Switches to the address space of the process, or the kernel's for a
+    /// kernel task.
+    ///
+    /// # Safety
+    /// See [AddressSpace::activate].
+    pub unsafe fn activate(&self) {
+        match &self.address_space {
+            Some(address_space) => address_space.activate(),
+            None => RootTable::kernel().activate(),
+        }
+    }
+
+    /// Gets the top of the kernel stack of the process, or 0 if it has none.
+    pub fn kernel_stack_top(&self) -> usize {
+        self.stack.as_ref().map_or(0, KernelStack::top)
+    }
+
+    /// Gets the process id.
+    pub const fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// Gets the name of the process.
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Gets the tick a sleeping process is woken up at.
+    pub const fn wake_at(&self) -> Option<u64> {
+        self.wake_at
+    }
+
+    /// Sets the tick to wake the process up at, if it is blocked.
+    pub fn set_wake_at(&mut self, tick: Option<u64>) {
+        self.wake_at = tick;
+    }
+
+    /// Gets the pid of the task waiting for this one to exit.
+    pub const fn joiner(&self) -> Option<u64> {
+        self.joiner
+    }
+
+    /// Sets the pid of the task waiting for this one to exit.
+    pub fn set_joiner(&mut self, pid: Option<u64>) {
+        self.joiner = pid;
+    }
+
+    /// Gets the scheduling state.
+    pub const fn state(&self) -> State {
+        self.state
+    }
+
+    /// Sets the scheduling state.
+    pub fn set_state(&mut self, state: State) {
+        self.state = state;
+    }
+
+    /// Saves the registers and program counter of the process after it was
+    /// interrupted.
+    pub fn save(&mut self, regs: &Regs, pc: usize) {
+        self.regs = *regs;
+        self.pc = pc;
+    }
+
+    /// Loads the saved registers of the process into `regs`, returning the
+    /// program counter to resume at.
+    pub fn restore(&self, regs: &mut Regs) -> usize {
+        *regs = self.regs;
+        self.pc
+    }
+
+    /// Gets the saved FP registers.
+    pub const fn fp_state(&self) -> &FpState {
+        &self.fp
+    }
+
+    /// Gets the saved FP registers, for saving new ones.
+    pub fn fp_state_mut(&mut self) -> &mut FpState {
+        &mut self.fp
+    }
+
+    /// Gets the number of bytes of memory owned by this process.
+    pub fn memory_usage(&self) -> usize {
+        self.pages.iter().map(|&(_, size, _)| size).sum()
+    }
+
+    /// Frees all memory owned by this process.
+    pub fn release_memory(&mut self) {
+        for (_, size, phys_base) in self.pages.iter_mut().filter(|(_, size, _)| *size > 0) {
+            with_page_allocator(|pages| pages.deallocate(phys_to_virt(*phys_base) as _, *size));
+            *size = 0;
+        }
+    }
+
+    /// Frees the kernel stack of this process, which must not be running.
+    pub fn release_stack(&mut self) {
+        self.stack = None;
+    }
+
+    /// Frees the page tables of this process, which must not be running.
+    pub fn release_address_space(&mut self) {
+        if let Some(address_space) = self.address_space.take() {
+            unsafe { address_space.free() };
+        }
+    }
 }
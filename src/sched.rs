@@ -0,0 +1,578 @@
+//! Preemptive round-robin scheduling of the tasks in [PROCESSES].
+//!
+//! Runnable tasks wait in a run queue and take turns, switching on every
+//! timer tick or when the running task yields, blocks or sleeps. Sleeping
+//! tasks are woken up by the tick that ends their sleep. Switches happen in
+//! the trap handler, which calls [switch] with the interrupted registers.
+//! Code outside the trap handler only touches the scheduler with interrupts
+//! disabled, so the trap handler can always lock it.
+//!
+//! FP registers are switched lazily. They stay with the last task that used
+//! them, and are disabled for every other task, so the first FP instruction
+//! of another task traps into [fp_trap], which swaps them. Tasks that never
+//! touch FP never pay for it, and registers that weren't written since they
+//! were loaded aren't saved.
+//!
+//! User processes run in their own address space, which is switched to along
+//! with their registers. Their traps run on their kernel stack, and faults
+//! or exits in them end just the process, through [kill_current].
+//!
+//! [PROCESSES]: crate::PROCESSES
+
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::{
+    arch::Regs,
+    cmdline::Param,
+    cpu,
+    kthread::KernelStack,
+    mmu::RootTable,
+    printk,
+    process::{Process, State, MAX_PROCESSES},
+    time::{self, TimeCounter},
+    PROCESSES,
+};
+
+/// Timer interrupts per second, each of which switches tasks.
+pub static HZ: Param<usize> = Param::new("hz", 100);
+
+/// Entry point of a task, given one argument. Tasks end with [exit].
+pub type Entry = extern "C" fn(usize) -> !;
+
+/// Errors from spawning a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The scheduler hasn't been started.
+    NotStarted,
+    /// Every process slot is taken.
+    TooManyTasks,
+    /// The stack couldn't be allocated.
+    OutOfMemory,
+}
+
+/// FIFO of the process slots waiting to run.
+struct RunQueue {
+    slots: [usize; MAX_PROCESSES],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            slots: [0; MAX_PROCESSES],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, slot: usize) {
+        // A slot is queued at most once, so this can't overflow
+        assert!(self.len < MAX_PROCESSES, "Run queue overflow");
+        self.slots[(self.head + self.len) % MAX_PROCESSES] = slot;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let slot = self.slots[self.head];
+        self.head = (self.head + 1) % MAX_PROCESSES;
+        self.len -= 1;
+        Some(slot)
+    }
+
+    /// Drops `slot` from the queue, if it's queued.
+    fn remove(&mut self, slot: usize) {
+        let (head, len) = (self.head, self.len);
+        self.len = 0;
+        // Entries only move towards the head, so none are overwritten
+        for i in 0..len {
+            let queued = self.slots[(head + i) % MAX_PROCESSES];
+            if queued != slot {
+                self.push(queued);
+            }
+        }
+    }
+}
+
+struct Scheduler {
+    queue: RunQueue,
+    /// Slot of the running task.
+    current: usize,
+    /// Slot of the task run when nothing else can, or None before [init].
+    idle: Option<usize>,
+    next_pid: u64,
+    /// Timer ticks since the scheduler started.
+    ticks: u64,
+    /// Frequency of the ticks, or 0 if there are none.
+    hz: usize,
+    /// Pid of the task whose FP state is in the FP registers.
+    fp_owner: Option<u64>,
+    /// Whether the FP registers changed since they were loaded or saved,
+    /// as of the last time their owner was switched away from.
+    fp_dirty: bool,
+    /// Pid of the user process whose address space is active, or None for
+    /// the kernel's.
+    user_space: Option<u64>,
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    queue: RunQueue::new(),
+    current: 0,
+    idle: None,
+    next_pid: 0,
+    ticks: 0,
+    hz: 0,
+    fp_owner: None,
+    fp_dirty: false,
+    user_space: None,
+});
+
+/// Body of the idle task.
+extern "C" fn idle_task(_: usize) -> ! {
+    cpu::wait_forever()
+}
+
+/// Makes the running code task 0, starts the idle task and starts switching
+/// tasks on timer ticks.
+///
+/// # Safety
+/// Must only be called once, after the allocator is initialized.
+pub unsafe fn init() {
+    // User address spaces copy the kernel's root table, so the table holding
+    // the kernel stacks has to be there before the first one is made
+    if RootTable::share_kernel_stacks().is_err() {
+        printk!("No memory for the kernel stack tables, scheduling is disabled");
+        return;
+    }
+    if let Err(e) = cpu::init_trap_stack() {
+        printk!("Failed to map the trap stack: {:?}", e);
+    }
+    let idle_slot = cpu::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let mut processes = PROCESSES.lock();
+        processes[0] = Some(Process::running(0, "kinit"));
+        sched.current = 0;
+        sched.next_pid = 1;
+        let idle = new_task(&mut sched, &mut processes, "idle", idle_task, 0).ok()?;
+        sched.idle = Some(idle);
+        Some(idle)
+    });
+    if idle_slot.is_none() {
+        printk!("Failed to start the idle task, scheduling is disabled");
+        return;
+    }
+    let hz = HZ.get();
+    if time::start_ticks(hz) {
+        cpu::without_interrupts(|| SCHEDULER.lock().hz = hz);
+        printk!("Scheduler started at {} Hz", hz);
+    } else {
+        printk!("Scheduler started without preemption");
+    }
+}
+
+/// Creates a runnable task without queueing it.
+fn new_task(
+    sched: &mut Scheduler,
+    processes: &mut [Option<Process>; MAX_PROCESSES],
+    name: &'static str,
+    entry: Entry,
+    arg: usize,
+) -> Result<usize, SpawnError> {
+    let slot = processes
+        .iter()
+        .position(Option::is_none)
+        .ok_or(SpawnError::TooManyTasks)?;
+    let stack = KernelStack::new(slot)?;
+    let regs = cpu::kernel_regs(stack.top(), arg);
+    let pid = sched.next_pid;
+    sched.next_pid += 1;
+    processes[slot] = Some(Process::kernel(pid, name, entry as usize, regs, stack));
+    Ok(slot)
+}
+
+/// Starts a kernel task running `entry(arg)` on a new stack, returning its
+/// pid. See [kthread] for running closures.
+///
+/// # Errors
+/// Fails if the scheduler isn't running, or there is no room for the task.
+///
+/// [kthread]: crate::kthread
+pub fn spawn(name: &'static str, entry: Entry, arg: usize) -> Result<u64, SpawnError> {
+    cpu::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        if sched.idle.is_none() {
+            return Err(SpawnError::NotStarted);
+        }
+        let mut processes = PROCESSES.lock();
+        reap(&mut processes);
+        let slot = new_task(&mut sched, &mut processes, name, entry, arg)?;
+        sched.queue.push(slot);
+        Ok(processes[slot].as_ref().map_or(0, Process::pid))
+    })
+}
+
+/// Starts a user process running `image`, returning its pid.
+///
+/// # Errors
+/// Fails if the scheduler isn't running, or there is no room for the process.
+pub fn spawn_user(name: &'static str, image: &[u8]) -> Result<u64, SpawnError> {
+    cpu::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        if sched.idle.is_none() {
+            return Err(SpawnError::NotStarted);
+        }
+        let mut processes = PROCESSES.lock();
+        reap(&mut processes);
+        let slot = processes
+            .iter()
+            .position(Option::is_none)
+            .ok_or(SpawnError::TooManyTasks)?;
+        let stack = KernelStack::new(slot)?;
+        let pid = sched.next_pid;
+        processes[slot] = Some(Process::user(pid, name, image, stack)?);
+        sched.next_pid += 1;
+        sched.queue.push(slot);
+        Ok(pid)
+    })
+}
+
+/// Frees the tasks that have exited. None of them can be running, and the
+/// address space of the running task is the only one that can be active.
+fn reap(processes: &mut [Option<Process>; MAX_PROCESSES]) {
+    for slot in processes.iter_mut() {
+        if slot.as_ref().map(Process::state) == Some(State::Exited) {
+            if let Some(mut process) = slot.take() {
+                process.release_stack();
+                process.release_memory();
+                process.release_address_space();
+            }
+        }
+    }
+}
+
+/// Gets the pid of the running task.
+pub fn current() -> u64 {
+    cpu::without_interrupts(|| {
+        let sched = SCHEDULER.lock();
+        PROCESSES.lock()[sched.current]
+            .as_ref()
+            .map_or(0, Process::pid)
+    })
+}
+
+/// Runs `f` on the running task, if there is one.
+/// Called by the trap handler, on behalf of the task.
+pub fn with_current<T>(f: impl FnOnce(&Process) -> T) -> Option<T> {
+    let sched = SCHEDULER.lock();
+    let processes = PROCESSES.lock();
+    processes[sched.current].as_ref().map(f)
+}
+
+/// Gets the slot of the running task, unless the scheduler is in use.
+pub fn try_current_slot() -> Option<usize> {
+    SCHEDULER.try_lock().map(|sched| sched.current)
+}
+
+/// Sets the state of the running task.
+fn set_current_state(state: State) {
+    let sched = SCHEDULER.lock();
+    if let Some(process) = PROCESSES.lock()[sched.current].as_mut() {
+        process.set_state(state);
+    }
+}
+
+/// Lets the other runnable tasks run before returning.
+pub fn yield_now() {
+    cpu::request_reschedule();
+}
+
+/// Stops running until another task or an interrupt handler calls [wake]
+/// with our pid. Wakes that come before blocking are lost, so callers should
+/// check what they are waiting for with interrupts disabled, then block.
+pub fn block() {
+    cpu::without_interrupts(|| {
+        set_current_state(State::Blocked);
+        // Taken once interrupts are enabled again, so a wake from an
+        // interrupt can't slip in between
+        cpu::request_reschedule();
+    });
+}
+
+/// Sleeps for at least `duration`, or until woken by [wake].
+/// Without timer ticks, this waits without letting other tasks run.
+pub fn sleep(duration: Duration) {
+    let slept = cpu::without_interrupts(|| {
+        let sched = SCHEDULER.lock();
+        if sched.hz == 0 {
+            return false;
+        }
+        // Round up, so we sleep at least as long as asked
+        let ticks = (duration.as_nanos() * sched.hz as u128 + 999_999_999) / 1_000_000_000;
+        let wake_at = sched.ticks + (ticks as u64).max(1);
+        if let Some(process) = PROCESSES.lock()[sched.current].as_mut() {
+            process.set_state(State::Blocked);
+            process.set_wake_at(Some(wake_at));
+        }
+        cpu::request_reschedule();
+        true
+    });
+    if !slept {
+        time::time_counter().wait_for(duration);
+    }
+}
+
+/// Makes `process`, which is in `slot`, runnable if it is blocked.
+fn wake_process(sched: &mut Scheduler, slot: usize, process: &mut Process) -> bool {
+    if process.state() != State::Blocked {
+        return false;
+    }
+    process.set_state(State::Runnable);
+    process.set_wake_at(None);
+    // A task that blocked but hasn't switched away yet is still current,
+    // and gets queued when it does
+    if slot != sched.current {
+        sched.queue.push(slot);
+    }
+    true
+}
+
+/// Finds the slot of a task.
+fn find(processes: &[Option<Process>; MAX_PROCESSES], pid: u64) -> Option<usize> {
+    processes
+        .iter()
+        .position(|slot| slot.as_ref().map_or(false, |p| p.pid() == pid))
+}
+
+/// Makes a blocked task runnable again. Returns false if there is no such
+/// task or it wasn't blocked.
+pub fn wake(pid: u64) -> bool {
+    cpu::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let mut processes = PROCESSES.lock();
+        match find(&processes, pid) {
+            Some(slot) => processes[slot]
+                .as_mut()
+                .map_or(false, |process| wake_process(&mut sched, slot, process)),
+            None => false,
+        }
+    })
+}
+
+/// Marks the task in `slot` as exited and takes it off the run queue,
+/// waking up whoever is waiting for it.
+fn end_task(sched: &mut Scheduler, processes: &mut [Option<Process>; MAX_PROCESSES], slot: usize) {
+    let joiner = processes[slot].as_mut().and_then(|process| {
+        process.set_state(State::Exited);
+        process.joiner()
+    });
+    sched.queue.remove(slot);
+    if let Some(slot) = joiner.and_then(|pid| find(processes, pid)) {
+        if let Some(process) = processes[slot].as_mut() {
+            wake_process(sched, slot, process);
+        }
+    }
+}
+
+/// Marks the running task as exited, waking up whoever is waiting for it.
+fn end_current() {
+    let mut sched = SCHEDULER.lock();
+    let mut processes = PROCESSES.lock();
+    let current = sched.current;
+    end_task(&mut sched, &mut processes, current);
+}
+
+/// Ends the running task, waking up whoever is waiting for it.
+pub fn exit() -> ! {
+    cpu::without_interrupts(|| {
+        end_current();
+        cpu::request_reschedule();
+    });
+    // Never scheduled again
+    cpu::wait_forever()
+}
+
+/// Ends the running task like [exit], then switches tasks like [switch].
+/// Called by the trap handler when a user process exits or faults.
+pub fn kill_current(regs: &mut Regs, pc: usize) -> usize {
+    end_current();
+    switch(regs, pc)
+}
+
+/// Ends a task that isn't running and frees it right away, waking up whoever
+/// is waiting for it. Gives up if the scheduler or the process table are in
+/// use, since the caller may be the one using them. Returns true if the task
+/// was killed.
+/// Called by the OOM handler.
+pub fn try_kill(pid: u64) -> bool {
+    cpu::without_interrupts(|| {
+        let mut sched = match SCHEDULER.try_lock() {
+            Some(sched) => sched,
+            None => return false,
+        };
+        let mut processes = match PROCESSES.try_lock() {
+            Some(processes) => processes,
+            None => return false,
+        };
+        let slot = match find(&processes, pid) {
+            Some(slot) if slot != sched.current && Some(slot) != sched.idle => slot,
+            _ => return false,
+        };
+        end_task(&mut sched, &mut processes, slot);
+        // Only the running task's address space can be active
+        reap(&mut processes);
+        true
+    })
+}
+
+/// Waits for a task to exit, and frees it.
+pub fn wait(pid: u64) {
+    loop {
+        let exited = cpu::without_interrupts(|| {
+            let sched = SCHEDULER.lock();
+            let mut processes = PROCESSES.lock();
+            let slot = match find(&processes, pid) {
+                Some(slot) => slot,
+                // Already reaped
+                None => return true,
+            };
+            let current = processes[sched.current].as_ref().map(Process::pid);
+            match processes[slot].as_mut() {
+                Some(process) if process.state() != State::Exited => process.set_joiner(current),
+                _ => {
+                    reap(&mut processes);
+                    return true;
+                }
+            }
+            drop(processes);
+            drop(sched);
+            // Interrupts are off, so it can't exit before we block
+            block();
+            false
+        });
+        if exited {
+            return;
+        }
+    }
+}
+
+/// Counts a timer tick, waking up the tasks whose sleep is over, then
+/// switches tasks like [switch].
+/// Called by the trap handler on timer interrupts.
+pub fn tick(regs: &mut Regs, pc: usize) -> usize {
+    {
+        let mut sched = SCHEDULER.lock();
+        let mut processes = PROCESSES.lock();
+        sched.ticks += 1;
+        let now = sched.ticks;
+        for (slot, process) in processes.iter_mut().enumerate() {
+            if let Some(process) = process {
+                if process.wake_at().map_or(false, |wake_at| wake_at <= now) {
+                    wake_process(&mut sched, slot, process);
+                }
+            }
+        }
+    }
+    switch(regs, pc)
+}
+
+/// Saves the interrupted task's `regs` and `pc`, and loads the next task
+/// into `regs`, returning the pc to resume at.
+/// Called by the trap handler on reschedule requests.
+pub fn switch(regs: &mut Regs, pc: usize) -> usize {
+    let mut sched = SCHEDULER.lock();
+    let idle = match sched.idle {
+        Some(idle) => idle,
+        None => return pc,
+    };
+    let mut processes = PROCESSES.lock();
+    let current = sched.current;
+    // Only the owner can have FP enabled
+    if cpu::fp_dirty() {
+        sched.fp_dirty = true;
+    }
+    // The task may have been killed while it was running
+    if let Some(process) = processes[current].as_mut() {
+        process.save(regs, pc);
+        match process.state() {
+            State::Running if current != idle => {
+                process.set_state(State::Runnable);
+                sched.queue.push(current);
+            }
+            State::Running => process.set_state(State::Runnable),
+            // Woken before it switched away
+            State::Runnable if current != idle => sched.queue.push(current),
+            _ => {}
+        }
+    }
+    let next = loop {
+        match sched.queue.pop() {
+            Some(slot) => {
+                let runnable = processes[slot]
+                    .as_ref()
+                    .map_or(false, |p| p.state() == State::Runnable);
+                if runnable {
+                    break slot;
+                }
+            }
+            None => break idle,
+        }
+    };
+    sched.current = next;
+    match processes[next].as_mut() {
+        Some(process) => {
+            process.set_state(State::Running);
+            cpu::set_fp_enabled(sched.fp_owner == Some(process.pid()));
+            let user_space = Some(process.pid()).filter(|_| process.is_user());
+            if sched.user_space != user_space {
+                // The trap handler and the kernel stacks are mapped in both
+                unsafe { process.activate() };
+                sched.user_space = user_space;
+            }
+            cpu::set_return_mode(process.is_user(), process.kernel_stack_top());
+            process.restore(regs)
+        }
+        None => pc,
+    }
+}
+
+/// Gives the FP registers to the running task, after it trapped by using
+/// them while they were disabled. Returns false if there is no task to give
+/// them to, in which case the trap wasn't ours.
+pub fn fp_trap() -> bool {
+    let mut sched = SCHEDULER.lock();
+    if sched.idle.is_none() {
+        return false;
+    }
+    let mut processes = PROCESSES.lock();
+    let pid = match processes[sched.current].as_ref() {
+        Some(process) => process.pid(),
+        None => return false,
+    };
+    cpu::set_fp_enabled(true);
+    if sched.fp_owner != Some(pid) {
+        let owner = sched.fp_owner;
+        // The owner may have exited since
+        let owner = processes
+            .iter_mut()
+            .flatten()
+            .find(|p| Some(p.pid()) == owner);
+        if let Some(owner) = owner {
+            if sched.fp_dirty {
+                unsafe { cpu::save_fp(owner.fp_state_mut()) };
+            }
+        }
+        if let Some(process) = processes[sched.current].as_ref() {
+            unsafe { cpu::restore_fp(process.fp_state()) };
+        }
+        sched.fp_owner = Some(pid);
+        sched.fp_dirty = false;
+        // Loading them marked them dirty
+        cpu::set_fp_enabled(true);
+    }
+    true
+}
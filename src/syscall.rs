@@ -0,0 +1,51 @@
+//! System calls, made by user processes with `ecall` on RISC-V and `svc` on
+//! AArch64. The number goes in a7 or x8, the arguments in a0-a2 or x0-x2,
+//! and the result comes back in a0 or x0.
+
+use crate::{print, printk, process::Process, sched};
+
+/// Ends the calling process. Takes an exit code, which is only printed.
+pub const EXIT: usize = 0;
+/// Lets the other runnable tasks run.
+pub const YIELD: usize = 1;
+/// Writes UTF-8 text to the console. Takes its address and length, and
+/// returns the length.
+pub const WRITE: usize = 2;
+
+/// Returned by calls that failed.
+pub const ERROR: usize = usize::MAX;
+
+/// Runs a system call for the running process. Returns the result, or None
+/// if the process asked to exit, in which case the caller must end it.
+/// Called by the trap handler.
+pub fn dispatch(number: usize, args: [usize; 3]) -> Option<usize> {
+    match number {
+        EXIT => {
+            printk!("Process {} exited with {}", sched::current(), args[0]);
+            None
+        }
+        YIELD => {
+            sched::yield_now();
+            Some(0)
+        }
+        WRITE => {
+            let written = sched::with_current(|process| write(process, args[0], args[1]));
+            Some(written.unwrap_or(ERROR))
+        }
+        _ => Some(ERROR),
+    }
+}
+
+/// Writes `len` bytes of text at `addr` in the memory of `process`.
+fn write(process: &Process, addr: usize, len: usize) -> usize {
+    let text = process
+        .user_bytes(addr, len)
+        .and_then(|bytes| core::str::from_utf8(bytes).ok());
+    match text {
+        Some(text) => {
+            print!("{}", text);
+            len
+        }
+        None => ERROR,
+    }
+}
@@ -3,6 +3,7 @@ use core::{cell::UnsafeCell, fmt::Debug, ops::Deref};
 use spin::Mutex;
 
 //pub mod mmio;
+pub mod ring_buffer;
 
 /// A Mutex wrapper that allows it
 /// (in some cases) to be borrowed without locking.
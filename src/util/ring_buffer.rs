@@ -0,0 +1,78 @@
+//! Lock-free ring buffer of bytes, for passing data to and from interrupt handlers.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A single producer, single consumer queue of bytes. One slot is kept
+/// empty to tell a full buffer from an empty one, so it holds `N - 1` bytes.
+pub struct RingBuffer<const N: usize> {
+    data: UnsafeCell<[u8; N]>,
+    /// Next slot to read. Only written by the consumer.
+    head: AtomicUsize,
+    /// Next slot to write. Only written by the producer.
+    tail: AtomicUsize,
+}
+
+// The producer and consumer each only write the slots they own
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            data: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Gets the number of bytes in the buffer.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (tail + N - head) % N
+    }
+
+    /// Checks if there is nothing to read.
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
+    /// Checks if there is no room to write.
+    pub fn is_full(&self) -> bool {
+        self.len() == N - 1
+    }
+
+    /// Adds a byte to the end. Returns false if the buffer is full.
+    /// Must only be called by the producer.
+    pub fn push(&self, value: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % N;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { (*self.data.get())[tail] = value };
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    /// Gets the first byte without removing it.
+    /// Must only be called by the consumer.
+    pub fn peek(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(unsafe { (*self.data.get())[head] })
+    }
+
+    /// Removes and returns the first byte.
+    /// Must only be called by the consumer.
+    pub fn pop(&self) -> Option<u8> {
+        let value = self.peek()?;
+        let head = self.head.load(Ordering::Relaxed);
+        self.head.store((head + 1) % N, Ordering::Release);
+        Some(value)
+    }
+}
@@ -37,6 +37,8 @@ def build(board, debug=False):
     command = ["cargo", "rustc", f"--target={target}"]
     if not debug:
         command.append("--release")
+    if "SCRAPS_FEATURES" in os.environ:
+        features = features + os.environ["SCRAPS_FEATURES"].split()
     for feature in features:
         command.extend(["--features", f"{feature}"])
     print(f"executing: RUSTFLAGS=\"{rustflags}\" {' '.join(command)}")